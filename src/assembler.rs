@@ -0,0 +1,311 @@
+use crate::instructions::Address;
+use crate::instructions::Instruction;
+use crate::symbols::SourceLine;
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+
+/// Assembles the mnemonics the disassembler prints back into a ROM.
+///
+/// One statement per line, optionally after a `label:`, with `;` starting a comment.
+/// Labels can be used wherever an address is expected and `DB` emits raw bytes:
+///
+/// ```text
+/// main:
+///     LD I, sprite
+///     DRW V0, V1, 3
+///     JP main
+/// sprite:
+///     DB 0xF0, 0x90, 0xF0
+/// ```
+pub struct Program {
+    pub rom: Vec<u8>,
+    /// Every label, and the source line of each statement
+    pub symbols: Symbols,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operand {
+    Register(usize),
+    I,
+    /// `[I]`, the memory I points at
+    Indirect,
+    Delay,
+    Sound,
+    Key,
+    Font,
+    Bcd,
+    Value(u16),
+}
+
+/// A line with its comment and label removed
+struct Statement<'a> {
+    line: u32,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+pub fn assemble(source: &str, file: &str, origin: u16) -> Result<Program, String> {
+    let mut labels = HashMap::new();
+    let mut symbols = Symbols::default();
+    let mut statements = Vec::new();
+
+    // First pass: find where every label ends up
+    let mut address = origin as usize;
+    for (number, text) in source.lines().enumerate() {
+        let line = number as u32 + 1;
+        let mut text = text.split(';').next().unwrap_or("").trim();
+
+        while let Some((label, rest)) = text.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            if labels.insert(label.to_string(), address as u16).is_some() {
+                return Err(format!(
+                    "{}:{}: label {} is defined twice",
+                    file, line, label
+                ));
+            }
+            symbols.insert_label(address as u16, label);
+            text = rest.trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, operands) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+        let statement = Statement {
+            line,
+            mnemonic: mnemonic.to_ascii_uppercase(),
+            operands: operands
+                .split(',')
+                .map(str::trim)
+                .filter(|operand| !operand.is_empty())
+                .collect(),
+        };
+
+        symbols.insert_line(
+            address as u16,
+            SourceLine {
+                file: file.to_string(),
+                line,
+            },
+        );
+        address += match statement.mnemonic.as_str() {
+            "DB" => statement.operands.len(),
+            _ => 2,
+        };
+        if address > 0x1000 {
+            return Err(format!("{}:{}: program runs past 0xFFF", file, line));
+        }
+        statements.push(statement);
+    }
+
+    // Second pass: encode everything now that all the labels are known
+    let mut rom = Vec::new();
+    for statement in statements {
+        let error = |message: String| format!("{}:{}: {}", file, statement.line, message);
+
+        let operands = statement
+            .operands
+            .iter()
+            .map(|operand| parse_operand(operand, &labels))
+            .collect::<Result<Vec<Operand>, String>>()
+            .map_err(error)?;
+
+        if statement.mnemonic == "DB" {
+            for operand in operands {
+                rom.push(byte(operand).map_err(error)?);
+            }
+        } else {
+            let instruction = encode(&statement.mnemonic, &operands).map_err(error)?;
+            rom.extend_from_slice(&instruction.opcode().to_be_bytes());
+        }
+    }
+
+    Ok(Program { rom, symbols })
+}
+
+fn parse_operand(text: &str, labels: &HashMap<String, u16>) -> Result<Operand, String> {
+    let operand = match text.to_ascii_uppercase().as_str() {
+        "I" => Operand::I,
+        "[I]" => Operand::Indirect,
+        "DT" => Operand::Delay,
+        "ST" => Operand::Sound,
+        "K" => Operand::Key,
+        "F" => Operand::Font,
+        "B" => Operand::Bcd,
+        upper => {
+            if let Some(register) = upper
+                .strip_prefix('V')
+                .filter(|digit| digit.len() == 1)
+                .and_then(|digit| usize::from_str_radix(digit, 16).ok())
+            {
+                return Ok(Operand::Register(register));
+            }
+            match parse_number(text).or_else(|| labels.get(text).copied()) {
+                Some(value) => Operand::Value(value),
+                None => return Err(format!("unknown label or bad number '{}'", text)),
+            }
+        }
+    };
+
+    Ok(operand)
+}
+
+fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix('$'))
+        .or_else(|| text.strip_prefix('#'))
+    {
+        u16::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u16::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn byte(operand: Operand) -> Result<u8, String> {
+    match operand {
+        Operand::Value(value) if value <= 0xFF => Ok(value as u8),
+        Operand::Value(value) => Err(format!("0x{:X} doesn't fit in a byte", value)),
+        _ => Err(String::from("expected a byte")),
+    }
+}
+
+fn address(value: u16) -> Result<Address, String> {
+    match value {
+        0..=0xFFF => Ok(Address::from_u16(value)),
+        _ => Err(format!("address 0x{:X} is past 0xFFF", value)),
+    }
+}
+
+fn encode(mnemonic: &str, operands: &[Operand]) -> Result<Instruction, String> {
+    use Operand::*;
+
+    let instruction = match (mnemonic, operands) {
+        ("CLS", []) => Instruction::Clear,
+        ("RET", []) => Instruction::Return,
+        ("JP", [Value(target)]) => Instruction::JumpTo(address(*target)?),
+        ("JP", [Register(0), Value(target)]) => Instruction::JumpOffset(address(*target)?),
+        ("CALL", [Value(target)]) => Instruction::Call(address(*target)?),
+        ("SE", [Register(vx), value @ Value(_)]) => Instruction::SkipIfEqualByte {
+            vx: *vx,
+            byte: byte(*value)?,
+        },
+        ("SE", [Register(vx), Register(vy)]) => Instruction::SkipIfEqualReg { vx: *vx, vy: *vy },
+        ("SNE", [Register(vx), value @ Value(_)]) => Instruction::SkipIfNotEqualByte {
+            vx: *vx,
+            byte: byte(*value)?,
+        },
+        ("SNE", [Register(vx), Register(vy)]) => {
+            Instruction::SkipIfNotEqualReg { vx: *vx, vy: *vy }
+        }
+        ("LD", [Register(vx), value @ Value(_)]) => Instruction::LoadByte {
+            vx: *vx,
+            byte: byte(*value)?,
+        },
+        ("LD", [Register(vx), Register(vy)]) => Instruction::LoadReg { vx: *vx, vy: *vy },
+        ("LD", [I, Value(target)]) => Instruction::LoadAddress(address(*target)?),
+        ("LD", [Register(vx), Delay]) => Instruction::LoadDelay { vx: *vx },
+        ("LD", [Register(vx), Key]) => Instruction::LoadKeyPressed { vx: *vx },
+        ("LD", [Delay, Register(vx)]) => Instruction::SetDelay { vx: *vx },
+        ("LD", [Sound, Register(vx)]) => Instruction::SetSound { vx: *vx },
+        ("LD", [Font, Register(vx)]) => Instruction::LoadSprite { vx: *vx },
+        ("LD", [Bcd, Register(vx)]) => Instruction::SetBCD { vx: *vx },
+        ("LD", [Indirect, Register(vx)]) => Instruction::LoadRegisters { vx: *vx },
+        ("LD", [Register(vx), Indirect]) => Instruction::ReadRegisters { vx: *vx },
+        ("ADD", [Register(vx), value @ Value(_)]) => Instruction::AddByte {
+            vx: *vx,
+            byte: byte(*value)?,
+        },
+        ("ADD", [Register(vx), Register(vy)]) => Instruction::AddReg { vx: *vx, vy: *vy },
+        ("ADD", [I, Register(vx)]) => Instruction::AddAddressOffset { vx: *vx },
+        ("OR", [Register(vx), Register(vy)]) => Instruction::Or { vx: *vx, vy: *vy },
+        ("AND", [Register(vx), Register(vy)]) => Instruction::And { vx: *vx, vy: *vy },
+        ("XOR", [Register(vx), Register(vy)]) => Instruction::Xor { vx: *vx, vy: *vy },
+        ("SUB", [Register(vx), Register(vy)]) => Instruction::Subtract { vx: *vx, vy: *vy },
+        ("SUBN", [Register(vx), Register(vy)]) => Instruction::SubtractReverse { vx: *vx, vy: *vy },
+        ("SHR", [Register(vx)]) => Instruction::ShiftRight { vx: *vx, vy: *vx },
+        ("SHR", [Register(vx), Register(vy)]) => Instruction::ShiftRight { vx: *vx, vy: *vy },
+        ("SHL", [Register(vx)]) => Instruction::ShiftLeft { vx: *vx, vy: *vx },
+        ("SHL", [Register(vx), Register(vy)]) => Instruction::ShiftLeft { vx: *vx, vy: *vy },
+        ("RND", [Register(vx), value @ Value(_)]) => Instruction::Random {
+            vx: *vx,
+            byte: byte(*value)?,
+        },
+        ("DRW", [Register(vx), Register(vy), Value(nibble)]) if *nibble <= 0xF => {
+            Instruction::Draw {
+                vx: *vx,
+                vy: *vy,
+                nibble: *nibble as u8,
+            }
+        }
+        ("SKP", [Register(vx)]) => Instruction::SkipIfKeyPressed { vx: *vx },
+        ("SKNP", [Register(vx)]) => Instruction::SkipIfNotKeyPressed { vx: *vx },
+        _ => return Err(format!("can't assemble {} with these operands", mnemonic)),
+    };
+
+    Ok(instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter;
+
+    #[test]
+    fn test_assemble() {
+        let source = "main: LD I, sprite ; comment\n\
+                      \x20   DRW V0, v1, 3\n\
+                      \x20   JP main\n\
+                      sprite:\n\
+                      \x20   DB 0xF0, $90, 240\n";
+        let program = assemble(source, "game.asm", 0x200).unwrap();
+
+        assert_eq!(
+            program.rom,
+            [0xA2, 0x06, 0xD0, 0x13, 0x12, 0x00, 0xF0, 0x90, 0xF0]
+        );
+        assert_eq!(program.symbols.label(0x206), Some("sprite"));
+        assert_eq!(
+            program.symbols.source_line(0x202).unwrap().to_string(),
+            "game.asm:2"
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        // Every instruction the disassembler prints assembles back to the same opcode
+        for opcode in 0..=0xFFFFu16 {
+            let instruction = interpreter::parse((opcode >> 8) as u8, opcode as u8);
+            if instruction == Instruction::Invalid {
+                continue;
+            }
+
+            let program = assemble(&instruction.to_string(), "test", 0x200).unwrap();
+            assert_eq!(program.rom, opcode.to_be_bytes(), "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source, "game.asm", 0x200).err().unwrap();
+
+        assert_eq!(
+            error("CLS\nJP nowhere"),
+            "game.asm:2: unknown label or bad number 'nowhere'"
+        );
+        assert_eq!(
+            error("LD V0, 0x100"),
+            "game.asm:1: 0x100 doesn't fit in a byte"
+        );
+        assert_eq!(
+            error("a: CLS\na: CLS"),
+            "game.asm:2: label a is defined twice"
+        );
+    }
+}
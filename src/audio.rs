@@ -0,0 +1,74 @@
+use sdl2::audio::AudioCallback;
+use sdl2::audio::AudioDevice;
+use sdl2::audio::AudioSpecDesired;
+use sdl2::AudioSubsystem;
+
+/// Pitch of the beep, the same as the COSMAC VIP's
+const FREQUENCY: f32 = 440.0;
+
+/// Volume used when none is configured
+pub const DEFAULT_VOLUME: f32 = 0.25;
+
+struct SquareWave {
+    /// How far through one cycle each sample moves
+    step: f32,
+    phase: f32,
+    volume: f32,
+}
+
+impl AudioCallback for SquareWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = if self.phase < 0.5 {
+                self.volume
+            } else {
+                -self.volume
+            };
+            self.phase = (self.phase + self.step) % 1.0;
+        }
+    }
+}
+
+/// Beeps while the sound timer is running
+pub struct Beeper {
+    device: AudioDevice<SquareWave>,
+    playing: bool,
+}
+
+impl Beeper {
+    pub fn new(audio: &AudioSubsystem, volume: f32) -> Result<Beeper, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(44_100),
+            channels: Some(1),
+            samples: None,
+        };
+        let device = audio.open_playback(None, &spec, |spec| SquareWave {
+            step: FREQUENCY / spec.freq as f32,
+            phase: 0.0,
+            volume,
+        })?;
+
+        Ok(Beeper {
+            device,
+            playing: false,
+        })
+    }
+
+    /// Starts or stops the beep to match the sound timer, called once per frame
+    pub fn update(&mut self, sound_timer: u8) {
+        let playing = sound_timer > 0;
+        if playing != self.playing {
+            match playing {
+                true => self.device.resume(),
+                false => self.device.pause(),
+            }
+            self.playing = playing;
+        }
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.device.lock().volume = volume;
+    }
+}
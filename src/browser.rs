@@ -0,0 +1,159 @@
+use crate::database::Database;
+use crate::font;
+use crate::loader;
+use crate::loader::RomFile;
+use sdl2::keyboard::Keycode;
+use std::path::Path;
+
+/// Size of the browser's picture, twice the display's resolution to fit in longer titles
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 128;
+
+/// A line of text with a pixel above and below
+const LINE_HEIGHT: usize = font::GLYPH_HEIGHT + 2;
+/// Entries that fit under the heading
+const ROWS: usize = HEIGHT / LINE_HEIGHT - 1;
+
+/// A menu drawn in the window for switching to another ROM from a directory or zip archive
+pub struct Browser {
+    /// The directory or archive's name, for the heading
+    name: String,
+    /// Each ROM with its title from the database, or else its file name, sorted by title
+    entries: Vec<(String, RomFile)>,
+    selected: usize,
+    /// The first entry in view
+    top: usize,
+}
+
+/// What a key pressed in the browser asks for
+pub enum Action {
+    Open(Box<RomFile>),
+    Close,
+}
+
+impl Browser {
+    /// Lists the ROMs in `path`, a directory or a zip archive, by their titles in `database`
+    pub fn open(path: &str, database: &Database) -> Result<Browser, String> {
+        let mut entries: Vec<(String, RomFile)> = loader::list(path)?
+            .into_iter()
+            .map(|rom| {
+                let title = match database.lookup(&rom.contents) {
+                    Some(profile) => profile.describe(),
+                    None => file_name(&rom.path),
+                };
+                (title, rom)
+            })
+            .collect();
+        if entries.is_empty() {
+            return Err(format!("there are no ROMs in {}", path));
+        }
+        entries.sort_by_key(|(title, _)| title.to_lowercase());
+
+        Ok(Browser {
+            name: file_name(path),
+            entries,
+            selected: 0,
+            top: 0,
+        })
+    }
+
+    /// Moves the selection with the arrow, page, home and end keys, opens the selected ROM
+    /// with return and closes the browser with escape
+    pub fn key(&mut self, keycode: Keycode) -> Option<Action> {
+        let last = self.entries.len() - 1;
+        self.selected = match keycode {
+            Keycode::Up => self.selected.saturating_sub(1),
+            Keycode::Down => (self.selected + 1).min(last),
+            Keycode::PageUp => self.selected.saturating_sub(ROWS),
+            Keycode::PageDown => (self.selected + ROWS).min(last),
+            Keycode::Home => 0,
+            Keycode::End => last,
+            Keycode::Return => {
+                return Some(Action::Open(Box::new(
+                    self.entries[self.selected].1.clone(),
+                )))
+            }
+            Keycode::Escape => return Some(Action::Close),
+            _ => return None,
+        };
+
+        // Scroll just far enough to keep the selection in view
+        self.top = self
+            .top
+            .min(self.selected)
+            .max((self.selected + 1).saturating_sub(ROWS));
+        None
+    }
+
+    /// Draws the heading and the entries in view as `WIDTH` by `HEIGHT` palette indices,
+    /// the selected one in a highlighted bar
+    pub fn render(&self) -> Vec<u8> {
+        let mut pixels = vec![0; WIDTH * HEIGHT];
+        let heading = format!("{} ROMS IN {}", self.entries.len(), self.name);
+        font::draw_text(&mut pixels, WIDTH, 1, 1, &heading, 1);
+
+        for (index, (title, _)) in self.entries.iter().enumerate().skip(self.top).take(ROWS) {
+            let y = (index - self.top + 1) * LINE_HEIGHT;
+            let colour = match index == self.selected {
+                true => {
+                    pixels[y * WIDTH..(y + LINE_HEIGHT) * WIDTH].fill(1);
+                    0
+                }
+                false => 1,
+            };
+            font::draw_text(&mut pixels, WIDTH, 1, y + 1, title, colour);
+        }
+        pixels
+    }
+}
+
+fn file_name(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_browser() {
+        let entries = (0..30)
+            .map(|number| {
+                let rom = RomFile {
+                    path: format!("games/{}.ch8", number),
+                    contents: vec![0x00, 0xE0],
+                    symbols: None,
+                    profile: None,
+                };
+                (format!("GAME {}", number), rom)
+            })
+            .collect();
+        let mut browser = Browser {
+            name: String::from("games"),
+            entries,
+            selected: 0,
+            top: 0,
+        };
+
+        browser.key(Keycode::PageDown);
+        browser.key(Keycode::Down);
+        assert_eq!(browser.selected, ROWS + 1);
+        assert_eq!(browser.top, 2);
+        browser.key(Keycode::Home);
+        assert_eq!((browser.selected, browser.top), (0, 0));
+
+        // The selected entry is drawn dark on a light bar
+        let pixels = browser.render();
+        assert!(pixels[LINE_HEIGHT * WIDTH..2 * LINE_HEIGHT * WIDTH].contains(&0));
+        assert_eq!(pixels[LINE_HEIGHT * WIDTH + WIDTH - 1], 1);
+        assert_eq!(pixels[2 * LINE_HEIGHT * WIDTH + WIDTH - 1], 0);
+
+        match browser.key(Keycode::Return) {
+            Some(Action::Open(rom)) => assert_eq!(rom.path, "games/0.ch8"),
+            _ => panic!("return should open the selected ROM"),
+        }
+    }
+}
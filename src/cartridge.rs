@@ -0,0 +1,198 @@
+use crate::database::Profile;
+use crate::palette::Palette;
+use crate::quirks;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// An Octo cartridge: a GIF of a label with a program and its options hidden in the low two
+/// bits of each pixel's colour index. Four pixels make a byte, the first in the top bits,
+/// running on through every frame. The bytes are a 32-bit big-endian length followed by that
+/// much JSON: `{"options": {...}, "program": "<Octo source>"}`.
+pub struct Cartridge {
+    /// Octo source
+    pub program: String,
+    pub options: Options,
+}
+
+/// The settings Octo saves with a program, as in its options panel
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Options {
+    /// Instructions per frame
+    tickrate: Option<usize>,
+    background_color: Option<String>,
+    fill_color: Option<String>,
+    fill_color2: Option<String>,
+    blend_color: Option<String>,
+    shift_quirks: Option<bool>,
+    load_store_quirks: Option<bool>,
+    jump_quirks: Option<bool>,
+    clip_quirks: Option<bool>,
+    v_blank_quirks: Option<bool>,
+    logic_quirks: Option<bool>,
+    /// Room for the program, which says which platform it's for
+    max_size: Option<usize>,
+    /// Keypad keys for actions such as `up` and `a`, as in the CHIP-8 database
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+}
+
+#[derive(Deserialize)]
+struct Payload {
+    #[serde(default)]
+    options: Options,
+    program: String,
+}
+
+/// Whether a file is a GIF, and so could be a cartridge
+pub fn is_gif(contents: &[u8]) -> bool {
+    contents.starts_with(b"GIF87a") || contents.starts_with(b"GIF89a")
+}
+
+pub fn decode(contents: &[u8]) -> Result<Cartridge, String> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options
+        .read_info(contents)
+        .map_err(|error| error.to_string())?;
+
+    let mut bytes = Vec::new();
+    let mut byte = 0;
+    let mut pixels = 0;
+    while let Some(frame) = decoder
+        .read_next_frame()
+        .map_err(|error| error.to_string())?
+    {
+        for &index in frame.buffer.iter() {
+            byte = byte << 2 | (index & 0b11);
+            pixels += 1;
+            if pixels % 4 == 0 {
+                bytes.push(byte);
+                byte = 0;
+            }
+        }
+    }
+
+    let length = match bytes[..] {
+        [a, b, c, d, ..] => u32::from_be_bytes([a, b, c, d]) as usize,
+        _ => return Err(String::from("there's no program in this image")),
+    };
+    let json = bytes
+        .get(4..4 + length)
+        .ok_or_else(|| String::from("the image is too small for the program it says it holds"))?;
+    let payload: Payload = serde_json::from_slice(json)
+        .map_err(|error| format!("this image isn't an Octo cartridge: {}", error))?;
+
+    Ok(Cartridge {
+        program: payload.program,
+        options: payload.options,
+    })
+}
+
+impl Options {
+    /// The options as a profile, like one from the database, for the program called `title`
+    pub fn profile(&self, title: &str) -> Result<Profile, String> {
+        // Octo's own presets leave 3583 bytes for SUPER-CHIP and more than 3584 for XO-CHIP
+        let id = match self.max_size {
+            Some(size) if size > 3584 => "xochip",
+            Some(3583) => "superchip",
+            _ => "modernChip8",
+        };
+        let preset = quirks::find_preset(id).expect("Octo's platforms should have presets");
+
+        let mut quirks = preset.quirks;
+        let overrides = [
+            (&mut quirks.shift, self.shift_quirks),
+            (&mut quirks.memory_leave_i_unchanged, self.load_store_quirks),
+            (&mut quirks.jump, self.jump_quirks),
+            (&mut quirks.wrap, self.clip_quirks.map(|clip| !clip)),
+            (&mut quirks.vblank, self.v_blank_quirks),
+            (&mut quirks.logic, self.logic_quirks),
+        ];
+        for (quirk, value) in overrides {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+
+        let colours = [
+            &self.background_color,
+            &self.fill_color,
+            &self.fill_color2,
+            &self.blend_color,
+        ];
+        let palette = match colours {
+            [Some(background), Some(fill), Some(fill2), Some(blend)] => Some(
+                Palette::parse(&format!("{},{},{},{}", background, fill, fill2, blend))
+                    .map_err(|error| format!("invalid colours in the cartridge: {}", error))?,
+            ),
+            [Some(background), Some(fill), ..] => Some(
+                Palette::parse(&format!("{},{}", background, fill))
+                    .map_err(|error| format!("invalid colours in the cartridge: {}", error))?,
+            ),
+            _ => None,
+        };
+
+        Ok(Profile {
+            title: title.to_string(),
+            authors: Vec::new(),
+            preset,
+            quirks,
+            speed: self.tickrate.unwrap_or(preset.speed),
+            keys: self.keys.clone(),
+            palette,
+        })
+    }
+}
+
+/// Hides `json` in a GIF the way Octo does, for testing
+#[cfg(test)]
+pub fn encode(json: &str) -> Vec<u8> {
+    let mut bytes = (json.len() as u32).to_be_bytes().to_vec();
+    bytes.extend_from_slice(json.as_bytes());
+    let mut pixels: Vec<u8> = bytes
+        .iter()
+        .flat_map(|byte| [byte >> 6, byte >> 4 & 3, byte >> 2 & 3, byte & 3])
+        // The label's own colours are in the upper bits
+        .map(|bits| bits | 0b100)
+        .collect();
+    pixels.resize(64 * 32, 0);
+
+    let palette = [0u8; 8 * 3];
+    let mut gif = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut gif, 64, 32, &palette).unwrap();
+        let frame = gif::Frame::from_indexed_pixels(64, 32, pixels, None);
+        encoder.write_frame(&frame).unwrap();
+    }
+    gif
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let gif = encode(
+            r##"{"options": {"tickrate": 30, "clipQuirks": true, "maxSize": 65024,
+                "backgroundColor": "#000000", "fillColor": "#FFCC00"},
+                "program": ": main clear"}"##,
+        );
+        assert!(is_gif(&gif));
+
+        let decoded = decode(&gif).unwrap();
+        assert_eq!(decoded.program, ": main clear");
+
+        let profile = decoded.options.profile("game").unwrap();
+        assert_eq!(profile.preset.id, "xochip");
+        assert_eq!(profile.speed, 30);
+        assert!(!profile.quirks.wrap);
+        assert_eq!(
+            profile.palette,
+            Some(Palette::parse("000000,FFCC00").unwrap())
+        );
+
+        assert!(decode(&encode("not json")).is_err());
+    }
+}
@@ -0,0 +1,458 @@
+use crate::instructions::Instruction;
+use crate::interpreter;
+use crate::symbols::Symbols;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// How control reaches a block from the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    Next,
+    Jump,
+    /// The skip was taken, passing over the following instruction
+    Skip,
+}
+
+/// A run of instructions that is only entered at the top and only left at the bottom
+#[derive(Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: u16,
+    /// Address just past the last instruction
+    pub end: u16,
+    pub successors: Vec<(u16, Edge)>,
+    /// Subroutines called from within the block
+    pub calls: Vec<u16>,
+    /// Ends in `JP V0, addr`, whose target isn't known statically
+    pub indirect: bool,
+}
+
+/// Control-flow graph of a ROM, found by recursive descent from its first instruction
+#[derive(Debug)]
+pub struct Cfg {
+    pub origin: u16,
+    pub blocks: BTreeMap<u16, BasicBlock>,
+    /// Entry points of the first instruction and every `CALL` target
+    pub subroutines: BTreeSet<u16>,
+    /// Whether each byte of the ROM was reached as part of an instruction
+    pub code: Vec<bool>,
+    /// Addresses loaded into I with `LD I, addr`, which are likely data
+    pub data_references: BTreeSet<u16>,
+    /// Instructions that can transfer control outside the ROM
+    pub leaves_rom: Vec<u16>,
+    /// Reachable words that don't decode to an instruction
+    pub invalid: Vec<u16>,
+}
+
+/// A run of bytes that is never reached as code
+#[derive(Debug, PartialEq)]
+pub struct Region {
+    pub start: u16,
+    /// Counted rather than given as an end, which could be past the last address
+    pub length: usize,
+    /// Some of it is pointed to by `LD I`, so it's most likely sprites or tables rather than dead code
+    pub referenced: bool,
+}
+
+impl Cfg {
+    pub fn build(rom: &[u8], origin: u16) -> Cfg {
+        let end = origin as usize + rom.len();
+        // Nothing can follow an instruction in the last word of the address space, so
+        // decoding stops before it and anything that reaches it leaves the ROM
+        let decode = |address: u16| {
+            let offset = address.checked_sub(origin)? as usize;
+            address.checked_add(2)?;
+            (address as usize + 1 < end).then(|| interpreter::parse(rom[offset], rom[offset + 1]))
+        };
+
+        let mut cfg = Cfg {
+            origin,
+            blocks: BTreeMap::new(),
+            subroutines: BTreeSet::from([origin]),
+            code: vec![false; rom.len()],
+            data_references: BTreeSet::new(),
+            leaves_rom: Vec::new(),
+            invalid: Vec::new(),
+        };
+
+        let mut visited = BTreeSet::new();
+        let mut leaders = BTreeSet::from([origin]);
+        let mut pending = vec![origin];
+
+        while let Some(address) = pending.pop() {
+            if !visited.insert(address) {
+                continue;
+            }
+
+            let instruction = match decode(address) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+            if instruction == Instruction::Invalid {
+                cfg.invalid.push(address);
+                continue;
+            }
+
+            let offset = (address - origin) as usize;
+            cfg.code[offset] = true;
+            cfg.code[offset + 1] = true;
+
+            if let Instruction::LoadAddress(addr) = &instruction {
+                cfg.data_references.insert(addr.to_u16());
+            }
+
+            let mut targets: Vec<u16> = successors(&instruction, address)
+                .iter()
+                .map(|&(target, _)| target)
+                .collect();
+            // Anything other than falling through starts a new block
+            if targets != [address + 2] {
+                leaders.extend(targets.iter().copied());
+            }
+
+            if let Instruction::Call(addr) = &instruction {
+                cfg.subroutines.insert(addr.to_u16());
+                leaders.insert(addr.to_u16());
+                targets.push(addr.to_u16());
+            }
+
+            for target in targets {
+                if decode(target).is_none() {
+                    cfg.leaves_rom.push(address);
+                } else {
+                    pending.push(target);
+                }
+            }
+        }
+
+        cfg.invalid.sort_unstable();
+        cfg.leaves_rom.sort_unstable();
+        cfg.leaves_rom.dedup();
+
+        for &leader in leaders.iter().filter(|leader| visited.contains(leader)) {
+            let block = build_block(leader, &leaders, &decode, &cfg.invalid);
+            if block.end > block.start {
+                cfg.blocks.insert(leader, block);
+            }
+        }
+
+        cfg
+    }
+
+    /// Blocks reachable from a subroutine's entry without following calls
+    pub fn subroutine_blocks(&self, entry: u16) -> Vec<&BasicBlock> {
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![entry];
+
+        while let Some(start) = pending.pop() {
+            if let Some(block) = self.blocks.get(&start) {
+                if seen.insert(start) {
+                    pending.extend(block.successors.iter().map(|&(target, _)| target));
+                }
+            }
+        }
+
+        seen.iter().map(|start| &self.blocks[start]).collect()
+    }
+
+    /// Runs of bytes never reached as code
+    pub fn unreached(&self) -> Vec<Region> {
+        let mut regions: Vec<Region> = Vec::new();
+
+        for (offset, _) in self.code.iter().enumerate().filter(|(_, &code)| !code) {
+            let address = self.origin + offset as u16;
+            let referenced = self.data_references.contains(&address);
+
+            match regions.last_mut() {
+                Some(region) if region.start as usize + region.length == address as usize => {
+                    region.length += 1;
+                    region.referenced |= referenced;
+                }
+                _ => regions.push(Region {
+                    start: address,
+                    length: 1,
+                    referenced,
+                }),
+            }
+        }
+
+        regions
+    }
+
+    /// A plain text overview of the graph and anything that couldn't be followed
+    pub fn summary(&self, symbols: &Symbols) -> String {
+        let code_bytes = self.code.iter().filter(|&&code| code).count();
+        let mut out = format!(
+            "{} subroutines, {} blocks, {} bytes of code and {} bytes never reached\n",
+            self.subroutines.len(),
+            self.blocks.len(),
+            code_bytes,
+            self.code.len() - code_bytes
+        );
+
+        for region in self.unreached() {
+            let kind = if region.referenced {
+                "data"
+            } else {
+                "unreachable"
+            };
+            let _ = writeln!(
+                out,
+                "{}: {}-{} ({} bytes)",
+                kind,
+                symbols.name(region.start),
+                symbols.name(region.start + (region.length - 1) as u16),
+                region.length
+            );
+        }
+        for block in self.blocks.values().filter(|block| block.indirect) {
+            let _ = writeln!(
+                out,
+                "indirect jump at {}, targets can't be followed",
+                symbols.name(block.end - 2)
+            );
+        }
+        for &address in self.invalid.iter() {
+            let _ = writeln!(
+                out,
+                "invalid instruction reached at {}",
+                symbols.name(address)
+            );
+        }
+        for &address in self.leaves_rom.iter() {
+            let _ = writeln!(out, "control leaves the ROM at {}", symbols.name(address));
+        }
+
+        out
+    }
+
+    /// Graphviz DOT with one cluster per subroutine.
+    /// Blocks shared between subroutines appear in each of them.
+    pub fn to_dot(&self, rom: &[u8], symbols: &Symbols) -> String {
+        let mut out = String::from(
+            "digraph rom {\n  node [shape=box, fontname=\"monospace\"];\n  compound=true;\n",
+        );
+
+        for &entry in self.subroutines.iter() {
+            let node = |start: u16| format!("s{:03X}_{:03X}", entry, start);
+
+            let _ = writeln!(out, "  subgraph cluster_{:03X} {{", entry);
+            let _ = writeln!(out, "    label=\"{}\";", symbols.name(entry));
+
+            let blocks = self.subroutine_blocks(entry);
+            for block in blocks.iter() {
+                let mut label = String::new();
+                for address in (block.start..block.end).step_by(2) {
+                    let offset = (address - self.origin) as usize;
+                    let instruction = interpreter::parse(rom[offset], rom[offset + 1]);
+                    let _ = write!(
+                        label,
+                        "{:03X}  {}\\l",
+                        address,
+                        symbols.format_instruction(&instruction)
+                    );
+                }
+
+                let colour = if block.indirect { ", color=red" } else { "" };
+                let _ = writeln!(
+                    out,
+                    "    {} [label=\"{}\"{}];",
+                    node(block.start),
+                    label.replace('"', "\\\""),
+                    colour
+                );
+
+                for &(target, edge) in block.successors.iter() {
+                    let style = match edge {
+                        Edge::Next => "",
+                        Edge::Jump => " [style=bold]",
+                        Edge::Skip => " [label=\"skip\"]",
+                    };
+                    let _ = writeln!(
+                        out,
+                        "    {} -> {}{};",
+                        node(block.start),
+                        node(target),
+                        style
+                    );
+                }
+            }
+            let _ = writeln!(out, "  }}");
+
+            for block in blocks.iter() {
+                for &callee in block.calls.iter() {
+                    let _ = writeln!(
+                        out,
+                        "  {} -> s{:03X}_{:03X} [style=dashed, lhead=cluster_{:03X}];",
+                        node(block.start),
+                        callee,
+                        callee,
+                        callee
+                    );
+                }
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// Where control can go after `instruction` at `address`, not counting calls or anywhere
+/// past the end of the address space
+fn successors(instruction: &Instruction, address: u16) -> Vec<(u16, Edge)> {
+    let next = address.checked_add(2);
+    let skip = address.checked_add(4);
+
+    let targets = match instruction {
+        Instruction::JumpTo(addr) => vec![(Some(addr.to_u16()), Edge::Jump)],
+        Instruction::Return | Instruction::JumpOffset(_) | Instruction::Invalid => Vec::new(),
+        // The callee returns to the next instruction
+        Instruction::Call(_) => vec![(next, Edge::Next)],
+        _ if instruction.is_skip() => vec![(next, Edge::Next), (skip, Edge::Skip)],
+        _ => vec![(next, Edge::Next)],
+    };
+    targets
+        .into_iter()
+        .filter_map(|(target, edge)| Some((target?, edge)))
+        .collect()
+}
+
+fn build_block(
+    start: u16,
+    leaders: &BTreeSet<u16>,
+    decode: &impl Fn(u16) -> Option<Instruction>,
+    invalid: &[u16],
+) -> BasicBlock {
+    let mut block = BasicBlock {
+        start,
+        end: start,
+        successors: Vec::new(),
+        calls: Vec::new(),
+        indirect: false,
+    };
+
+    let mut address = start;
+    while let Some(instruction) = decode(address) {
+        if invalid.contains(&address) {
+            break;
+        }
+
+        block.end = address + 2;
+        if let Instruction::Call(addr) = &instruction {
+            block.calls.push(addr.to_u16());
+        }
+        block.indirect = matches!(instruction, Instruction::JumpOffset(_));
+
+        let successors = successors(&instruction, address);
+        let falls_through = successors == [(address + 2, Edge::Next)];
+        if !falls_through || leaders.contains(&block.end) {
+            block.successors = successors;
+            break;
+        }
+        address = block.end;
+    }
+
+    // Only keep edges to code that was actually decoded
+    block
+        .successors
+        .retain(|&(target, _)| decode(target).is_some() && !invalid.contains(&target));
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: CALL 20A
+    // 202: SE V0, 0
+    // 204: JP 200
+    // 206: JP V0, 20E
+    // 208: F0 90 (sprite, loaded below)
+    // 20A: LD I, 208
+    // 20C: RET
+    // 20E: 12 34 (unreachable)
+    const ROM: [u8; 16] = [
+        0x22, 0x0A, 0x30, 0x00, 0x12, 0x00, 0xB2, 0x0E, 0xF0, 0x90, 0xA2, 0x08, 0x00, 0xEE, 0x12,
+        0x34,
+    ];
+
+    #[test]
+    fn test_blocks() {
+        let cfg = Cfg::build(&ROM, 0x200);
+
+        assert_eq!(cfg.subroutines, BTreeSet::from([0x200, 0x20A]));
+        assert_eq!(
+            cfg.blocks.keys().copied().collect::<Vec<_>>(),
+            vec![0x200, 0x204, 0x206, 0x20A]
+        );
+
+        let entry = &cfg.blocks[&0x200];
+        assert_eq!(entry.end, 0x204);
+        assert_eq!(entry.calls, vec![0x20A]);
+        assert_eq!(
+            entry.successors,
+            vec![(0x204, Edge::Next), (0x206, Edge::Skip)]
+        );
+
+        assert!(cfg.blocks[&0x206].indirect);
+        assert_eq!(cfg.subroutine_blocks(0x20A).len(), 1);
+    }
+
+    #[test]
+    fn test_unreached_regions() {
+        let cfg = Cfg::build(&ROM, 0x200);
+
+        assert_eq!(
+            cfg.unreached(),
+            vec![
+                Region {
+                    start: 0x208,
+                    length: 2,
+                    referenced: true
+                },
+                Region {
+                    start: 0x20E,
+                    length: 2,
+                    referenced: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_full_address_space() {
+        // An XO-CHIP ROM filling memory up to 0xFFFF: LD V0, 0 over and over, with a skip
+        // at FFFC whose target is past the end
+        let mut rom = [0x60, 0x00].repeat(0xFE00 / 2);
+        rom[0xFDFC..0xFDFE].copy_from_slice(&[0x30, 0x00]);
+
+        let cfg = Cfg::build(&rom, 0x200);
+        assert_eq!(cfg.blocks[&0x200].end, 0xFFFE);
+        assert_eq!(cfg.leaves_rom, vec![0xFFFC]);
+        assert_eq!(
+            cfg.unreached(),
+            vec![Region {
+                start: 0xFFFE,
+                length: 2,
+                referenced: false
+            }]
+        );
+        assert!(cfg
+            .summary(&Symbols::default())
+            .contains("unreachable: 0xFFFE-0xFFFF (2 bytes)"));
+
+        crate::lint::lint(&rom, 0x200);
+        crate::detect::detect(&rom, 0x200);
+        crate::symbols::listing(&rom, 0x200, &Symbols::default());
+    }
+
+    #[test]
+    fn test_dot() {
+        let dot = Cfg::build(&ROM, 0x200).to_dot(&ROM, &Symbols::default());
+
+        assert!(dot.contains("subgraph cluster_20A {"));
+        assert!(dot.contains("s200_200 -> s200_206 [label=\"skip\"];"));
+        assert!(dot.contains("s200_200 -> s20A_20A [style=dashed, lhead=cluster_20A];"));
+        assert!(dot.contains("s200_206 [label=\"206  JP V0, 0x20E\\l\", color=red];"));
+    }
+}
@@ -0,0 +1,263 @@
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use toml_edit::DocumentMut;
+use toml_edit::Item;
+
+/// Settings saved between runs, in `config.toml` in the user's config directory:
+///
+/// ```toml
+/// [defaults]
+/// scale = 12
+/// theme = "amber"
+/// volume = 0.2
+///
+/// # Keyed by SHA-1, as `chip8 info` shows it
+/// [roms.0123456789abcdef0123456789abcdef01234567]
+/// speed = 30
+/// keymap = "up:5,down:8,a:6"
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Used when neither the command line, the ROM's own section nor the database say otherwise
+    #[serde(default)]
+    pub defaults: Settings,
+    /// Settings for single ROMs, keyed by SHA-1, overriding the database
+    #[serde(default)]
+    pub roms: BTreeMap<String, Settings>,
+}
+
+/// One level of settings, each one also available as a command-line option
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    /// Hex colours as `--palette=` takes them, overriding the theme
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub palette: Option<String>,
+    /// Instructions per frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed: Option<usize>,
+    /// Quirks preset id, such as `modernChip8`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quirks: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keymap: Option<String>,
+    /// Beep volume from 0, silent, to 1
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f32>,
+}
+
+impl Settings {
+    /// The settings written as command-line options, so they're checked the same way
+    pub fn to_args(&self) -> Vec<String> {
+        let options = [
+            ("--scale", self.scale.map(|scale| scale.to_string())),
+            ("--speed", self.speed.map(|speed| speed.to_string())),
+            ("--quirks", self.quirks.clone()),
+            ("--keymap", self.keymap.clone()),
+            ("--volume", self.volume.map(|volume| volume.to_string())),
+            ("--theme", self.theme.clone()),
+            ("--palette", self.palette.clone()),
+        ];
+
+        options
+            .into_iter()
+            .filter_map(|(name, value)| Some(format!("{}={}", name, value?)))
+            .collect()
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        toml::from_str(text).map_err(|error| error.to_string())
+    }
+
+    /// Reads a config file, treating a missing one as empty
+    pub fn load(path: &Path) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Ok(text) => Config::parse(&text)
+                .map_err(|error| format!("invalid config in {}: {}", path.display(), error)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(error) => Err(format!("failed to read {}: {}", path.display(), error)),
+        }
+    }
+
+    /// Writes the section for the ROM with SHA-1 `hash` back to the file, leaving the rest of
+    /// it, comments included, as it was
+    pub fn save(&self, path: &Path, hash: &str) -> Result<(), String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(format!("failed to read {}: {}", path.display(), error)),
+        };
+        let settings = self.roms.get(hash).cloned().unwrap_or_default();
+        let text = update(&text, hash, &settings)
+            .map_err(|error| format!("failed to save {}: {}", path.display(), error))?;
+
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)
+                .map_err(|error| format!("failed to create {}: {}", directory.display(), error))?;
+        }
+        fs::write(path, text)
+            .map_err(|error| format!("failed to save {}: {}", path.display(), error))
+    }
+
+    /// Saves settings changed while a ROM was running into its section
+    pub fn remember(&mut self, hash: &str, changes: &Settings) {
+        let settings = self.roms.entry(hash.to_string()).or_default();
+        if changes.theme.is_some() {
+            // A palette would override the new theme
+            settings.theme = changes.theme.clone();
+            settings.palette = None;
+        }
+        settings.scale = changes.scale.or(settings.scale);
+        settings.speed = changes.speed.or(settings.speed);
+        settings.volume = changes.volume.or(settings.volume);
+    }
+}
+
+/// Replaces the settings in the `[roms.<hash>]` section of the config in `text`, editing the
+/// values in place so the comments around them stay put
+fn update(text: &str, hash: &str, settings: &Settings) -> Result<String, String> {
+    let mut document: DocumentMut = text.parse().map_err(|error| format!("{}", error))?;
+    let saved: DocumentMut = toml::to_string(settings)
+        .map_err(|error| error.to_string())?
+        .parse()
+        .map_err(|error| format!("{}", error))?;
+
+    let roms = document
+        .entry("roms")
+        .or_insert_with(|| {
+            let mut roms = toml_edit::Table::new();
+            // Only the sections for each ROM need a heading
+            roms.set_implicit(true);
+            Item::Table(roms)
+        })
+        .as_table_mut()
+        .ok_or("roms isn't a table")?;
+    let section = roms
+        .entry(hash)
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or_else(|| format!("roms.{} isn't a table", hash))?;
+
+    section.retain(|key, _| saved.contains_key(key));
+    for (key, item) in saved.iter() {
+        let value = item.as_value().ok_or("settings should be plain values")?;
+        match section.get_mut(key).and_then(Item::as_value_mut) {
+            Some(old) => {
+                let decor = old.decor().clone();
+                *old = value.clone();
+                *old.decor_mut() = decor;
+            }
+            None => {
+                section.insert(key, Item::Value(value.clone()));
+            }
+        }
+    }
+
+    Ok(document.to_string())
+}
+
+/// Where the config lives unless `--config=` says otherwise, e.g. `~/.config/chip8/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|directory| directory.join("chip8").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings() {
+        let config = Config::parse(
+            r#"
+            [defaults]
+            scale = 12
+            palette = "000000,ffffff"
+            speed = 20
+
+            [roms.abc]
+            theme = "amber"
+            speed = 30
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.roms["abc"].to_args(),
+            ["--speed=30", "--theme=amber"]
+        );
+        assert_eq!(
+            config.defaults.to_args(),
+            ["--scale=12", "--speed=20", "--palette=000000,ffffff"]
+        );
+
+        // Saving leaves out everything that isn't set
+        let saved = toml::to_string(&config).unwrap();
+        assert_eq!(Config::parse(&saved).unwrap(), config);
+        assert!(!saved.contains("volume"));
+
+        assert!(Config::parse("[defaults]\nsped = 20").is_err());
+    }
+
+    #[test]
+    fn test_update_keeps_comments() {
+        let text = "# Bigger for the projector\n\
+                    [defaults]\n\
+                    scale = 20\n\
+                    \n\
+                    [roms.abc]\n\
+                    speed = 30 # any faster and it's unplayable\n\
+                    palette = \"000000,ffffff\"\n";
+        let mut config = Config::parse(text).unwrap();
+        config.remember(
+            "abc",
+            &Settings {
+                theme: Some(String::from("lcd")),
+                speed: Some(25),
+                ..Settings::default()
+            },
+        );
+
+        assert_eq!(
+            update(text, "abc", &config.roms["abc"]).unwrap(),
+            "# Bigger for the projector\n\
+             [defaults]\n\
+             scale = 20\n\
+             \n\
+             [roms.abc]\n\
+             speed = 25 # any faster and it's unplayable\n\
+             theme = \"lcd\"\n"
+        );
+
+        let text = update("", "def", &config.roms["abc"]).unwrap();
+        assert_eq!(text, "[roms.def]\ntheme = \"lcd\"\nspeed = 25\n");
+    }
+
+    #[test]
+    fn test_remember() {
+        let mut config =
+            Config::parse("[roms.abc]\npalette = \"000000,ffffff\"\nspeed = 30").unwrap();
+        let changes = Settings {
+            theme: Some(String::from("lcd")),
+            volume: Some(0.0),
+            ..Settings::default()
+        };
+        config.remember("abc", &changes);
+
+        assert_eq!(
+            config.roms["abc"].to_args(),
+            ["--speed=30", "--volume=0", "--theme=lcd"]
+        );
+    }
+}
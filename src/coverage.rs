@@ -0,0 +1,292 @@
+use crate::instructions::Instruction;
+use crate::interpreter;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Lines at the top of the listing before the first address
+const HEADER_LINES: usize = 1;
+
+/// Records which addresses ran and which way each skip went.
+pub struct Coverage {
+    counts: Vec<u64>,
+    branches: BTreeMap<u16, Branch>,
+    /// Where the ROM was loaded, where the listing starts
+    origin: usize,
+    /// End of the loaded ROM, where the listing stops
+    rom_end: usize,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Branch {
+    taken: u64,
+    not_taken: u64,
+}
+
+/// A line of the annotated listing
+struct Line {
+    address: u16,
+    /// Times the line was executed, or None for bytes that aren't code
+    count: Option<u64>,
+    text: String,
+}
+
+impl Coverage {
+    /// Coverage of a ROM `rom_length` bytes long loaded at `origin`
+    pub fn new(ram_size: usize, origin: usize, rom_length: usize) -> Coverage {
+        Coverage {
+            counts: vec![0; ram_size],
+            branches: BTreeMap::new(),
+            origin,
+            rom_end: (origin + rom_length).min(ram_size),
+        }
+    }
+
+    /// Records the instruction that was just executed from `address`
+    pub fn record(&mut self, address: u16, memory: &Memory) {
+        self.counts[address as usize] += 1;
+
+        let opcode = interpreter::opcode_at(memory, address);
+        if interpreter::parse((opcode >> 8) as u8, opcode as u8).is_skip() {
+            let branch = self.branches.entry(address).or_default();
+            if address.checked_add(4) == Some(memory.program_counter) {
+                branch.taken += 1;
+            } else {
+                branch.not_taken += 1;
+            }
+        }
+    }
+
+    /// Walks the ROM two bytes at a time, falling back to single data bytes where that
+    /// keeps executed code at odd addresses aligned. Words that don't decode to an
+    /// instruction and never ran are treated as data rather than missed code.
+    fn lines(&self, memory: &Memory) -> Vec<Line> {
+        let mut lines = Vec::new();
+        let mut address = self.origin;
+
+        while address < self.rom_end {
+            let count = self.counts[address];
+            let next_executed = self.counts.get(address + 1).is_some_and(|&next| next > 0);
+
+            if count == 0 && (address + 1 >= self.rom_end || next_executed) {
+                lines.push(Line {
+                    address: address as u16,
+                    count: None,
+                    text: format!("{:03X}  {:02X}", address, memory.ram[address]),
+                });
+                address += 1;
+                continue;
+            }
+
+            let opcode = interpreter::opcode_at(memory, address as u16);
+            let instruction = interpreter::parse((opcode >> 8) as u8, opcode as u8);
+
+            let mut text = format!("{:03X}  {:04X}  {}", address, opcode, instruction);
+            if let Some(branch) = self.branches.get(&(address as u16)) {
+                let _ = write!(
+                    text,
+                    "  ; skipped {}, not skipped {}",
+                    branch.taken, branch.not_taken
+                );
+            }
+
+            lines.push(Line {
+                address: address as u16,
+                count: (count > 0 || instruction != Instruction::Invalid).then_some(count),
+                text,
+            });
+            address += 2;
+        }
+
+        lines
+    }
+
+    /// The ROM's disassembly with execution counts in the margin, `#####` marking code that never ran
+    pub fn listing(&self, memory: &Memory) -> String {
+        let lines = self.lines(memory);
+
+        let code: Vec<u64> = lines.iter().filter_map(|line| line.count).collect();
+        let executed = code.iter().filter(|&&count| count > 0).count();
+        let directions = self.branches.len() * 2;
+        let directions_hit: usize = self
+            .branches
+            .values()
+            .map(|branch| (branch.taken > 0) as usize + (branch.not_taken > 0) as usize)
+            .sum();
+
+        let mut out = format!(
+            "; {} of {} instructions executed ({:.1}%), {} of {} branch directions taken\n",
+            executed,
+            code.len(),
+            executed as f64 * 100.0 / code.len().max(1) as f64,
+            directions_hit,
+            directions
+        );
+
+        for line in lines {
+            let margin = match line.count {
+                Some(0) => String::from("#####"),
+                Some(count) => count.to_string(),
+                None => String::from("-"),
+            };
+            let _ = writeln!(out, "{:>9}  {}", margin, line.text);
+        }
+
+        out
+    }
+
+    /// lcov tracefile with each line number referring to the same line of `listing`
+    pub fn lcov(&self, memory: &Memory, listing: &str) -> String {
+        self.lcov_by(memory, |index, _| {
+            Some((listing.to_string(), (index + HEADER_LINES + 1) as u32))
+        })
+    }
+
+    /// lcov tracefile keyed to the source lines each address was assembled from.
+    /// Code without a source line is left out.
+    pub fn lcov_source(&self, memory: &Memory, symbols: &Symbols) -> String {
+        self.lcov_by(memory, |_, address| {
+            symbols
+                .source_line(address)
+                .map(|source| (source.file.clone(), source.line))
+        })
+    }
+
+    /// Builds an lcov tracefile, with `locate` giving the file and line number for
+    /// each listing line from its index and address
+    fn lcov_by(
+        &self,
+        memory: &Memory,
+        locate: impl Fn(usize, u16) -> Option<(String, u32)>,
+    ) -> String {
+        // Several instructions can come from one source line, which counts as run as often as any of them
+        let mut files: BTreeMap<String, BTreeMap<u32, (u64, Vec<u64>)>> = BTreeMap::new();
+
+        for (index, line) in self.lines(memory).iter().enumerate() {
+            let count = match line.count {
+                Some(count) => count,
+                None => continue,
+            };
+            let (file, number) = match locate(index, line.address) {
+                Some(location) => location,
+                None => continue,
+            };
+
+            let entry = files.entry(file).or_default().entry(number).or_default();
+            entry.0 = entry.0.max(count);
+            if let Some(branch) = self.branches.get(&line.address) {
+                entry.1.extend([branch.taken, branch.not_taken]);
+            }
+        }
+
+        let mut out = String::new();
+        for (file, lines) in files {
+            let _ = writeln!(out, "TN:\nSF:{}", file);
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+
+            for (number, (_, branches)) in lines.iter() {
+                for (direction, times) in branches.iter().enumerate() {
+                    let _ = writeln!(out, "BRDA:{},0,{},{}", number, direction, times);
+                    branches_found += 1;
+                    branches_hit += (*times > 0) as usize;
+                }
+            }
+            for (number, (count, _)) in lines.iter() {
+                let _ = writeln!(out, "DA:{},{}", number, count);
+            }
+
+            let hit = lines.values().filter(|(count, _)| *count > 0).count();
+            let _ = write!(
+                out,
+                "BRF:{}\nBRH:{}\nLF:{}\nLH:{}\nend_of_record\n",
+                branches_found,
+                branches_hit,
+                lines.len(),
+                hit
+            );
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn run(rom: &[u8], steps: usize) -> (Coverage, Memory) {
+        run_at(0x200, rom, steps)
+    }
+
+    fn run_at(origin: usize, rom: &[u8], steps: usize) -> (Coverage, Memory) {
+        let mut memory = Memory::new();
+        memory.load_rom_at(origin, rom).unwrap();
+        memory.program_counter = origin as u16;
+        let mut coverage = Coverage::new(memory.ram.len(), origin, rom.len());
+
+        let no_keys = HashSet::new();
+        for _ in 0..steps {
+            let address = memory.program_counter;
+            interpreter::step(&mut memory, &no_keys, &no_keys).unwrap();
+            coverage.record(address, &memory);
+        }
+
+        (coverage, memory)
+    }
+
+    #[test]
+    fn test_listing() {
+        // 200: SE V0, 0 (always skips), 202: LD V1, 1, 204: JP 204, 206: sprite byte
+        let (coverage, memory) = run(&[0x30, 0x00, 0x61, 0x01, 0x12, 0x04, 0xF0], 3);
+
+        assert_eq!(
+            coverage.listing(&memory),
+            "; 2 of 3 instructions executed (66.7%), 1 of 2 branch directions taken\n\
+             \x20       1  200  3000  SE V0, 0x00  ; skipped 1, not skipped 0\n\
+             \x20   #####  202  6101  LD V1, 0x01\n\
+             \x20       2  204  1204  JP 0x204\n\
+             \x20       -  206  F0\n"
+        );
+    }
+
+    #[test]
+    fn test_listing_at_start_address() {
+        // 300: LD V1, 1, 302: JP 302
+        let (coverage, memory) = run_at(0x300, &[0x61, 0x01, 0x13, 0x02], 2);
+
+        assert_eq!(
+            coverage.listing(&memory),
+            "; 2 of 2 instructions executed (100.0%), 0 of 0 branch directions taken\n\
+             \x20       1  300  6101  LD V1, 0x01\n\
+             \x20       1  302  1302  JP 0x302\n"
+        );
+    }
+
+    #[test]
+    fn test_lcov_lines_match_listing() {
+        let (coverage, memory) = run(&[0x30, 0x00, 0x61, 0x01, 0x12, 0x04], 3);
+        let lcov = coverage.lcov(&memory, "game.lst");
+
+        assert_eq!(
+            lcov,
+            "TN:\nSF:game.lst\nBRDA:2,0,0,1\nBRDA:2,0,1,0\nDA:2,1\nDA:3,0\nDA:4,2\n\
+             BRF:2\nBRH:1\nLF:3\nLH:2\nend_of_record\n"
+        );
+    }
+
+    #[test]
+    fn test_lcov_source_lines() {
+        let (coverage, memory) = run(&[0x30, 0x00, 0x61, 0x01, 0x12, 0x04], 3);
+        let symbols =
+            Symbols::parse("0x200 game.8o:5\n0x202 game.8o:6\n0x204 game.8o:6\n").unwrap();
+
+        assert_eq!(
+            coverage.lcov_source(&memory, &symbols),
+            "TN:\nSF:game.8o\nBRDA:5,0,0,1\nBRDA:5,0,1,0\nDA:5,1\nDA:6,2\n\
+             BRF:2\nBRH:1\nLF:2\nLH:2\nend_of_record\n"
+        );
+    }
+}
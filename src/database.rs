@@ -0,0 +1,222 @@
+use crate::palette::Palette;
+use crate::quirks;
+use crate::quirks::Preset;
+use crate::quirks::Quirks;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// ROM metadata in the format of the CHIP-8 community archive's database: `sha1-hashes.json`
+/// maps the SHA-1 of each ROM to an index into the programs in `programs.json`.
+/// None is built in; the default database is empty and recognises nothing.
+#[derive(Default)]
+pub struct Database {
+    hashes: HashMap<String, usize>,
+    programs: Vec<Program>,
+}
+
+#[derive(Deserialize)]
+struct Program {
+    title: String,
+    #[serde(default)]
+    authors: Vec<String>,
+    #[serde(default)]
+    roms: HashMap<String, Rom>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Rom {
+    /// Platforms the ROM runs on, most suitable first
+    #[serde(default)]
+    platforms: Vec<String>,
+    /// Platforms the ROM needs some quirks changed on
+    #[serde(default)]
+    quirky_platforms: BTreeMap<String, QuirkOverrides>,
+    tickrate: Option<usize>,
+    /// CHIP-8 keys for actions such as `up` and `a`
+    #[serde(default)]
+    keys: BTreeMap<String, u8>,
+    colors: Option<Colors>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct QuirkOverrides {
+    shift: Option<bool>,
+    memory_increment_by_x: Option<bool>,
+    memory_leave_i_unchanged: Option<bool>,
+    wrap: Option<bool>,
+    jump: Option<bool>,
+    vblank: Option<bool>,
+    logic: Option<bool>,
+}
+
+impl QuirkOverrides {
+    fn apply(&self, quirks: &mut Quirks) {
+        let overrides = [
+            (&mut quirks.shift, self.shift),
+            (
+                &mut quirks.memory_increment_by_x,
+                self.memory_increment_by_x,
+            ),
+            (
+                &mut quirks.memory_leave_i_unchanged,
+                self.memory_leave_i_unchanged,
+            ),
+            (&mut quirks.wrap, self.wrap),
+            (&mut quirks.jump, self.jump),
+            (&mut quirks.vblank, self.vblank),
+            (&mut quirks.logic, self.logic),
+        ];
+        for (quirk, value) in overrides {
+            if let Some(value) = value {
+                *quirk = value;
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Colors {
+    /// Background then foreground, followed by the XO-CHIP plane colours
+    #[serde(default)]
+    pixels: Vec<String>,
+}
+
+/// How a recognised ROM should be run
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub preset: &'static Preset,
+    pub quirks: Quirks,
+    /// Instructions per frame
+    pub speed: usize,
+    pub keys: BTreeMap<String, u8>,
+    pub palette: Option<Palette>,
+}
+
+impl Profile {
+    /// The title and authors, e.g. for the window title
+    pub fn describe(&self) -> String {
+        match self.authors.is_empty() {
+            true => self.title.clone(),
+            false => format!("{} by {}", self.title, self.authors.join(", ")),
+        }
+    }
+}
+
+impl Database {
+    pub fn parse(hashes: &str, programs: &str) -> Result<Database, String> {
+        Ok(Database {
+            hashes: serde_json::from_str(hashes)
+                .map_err(|error| format!("invalid hash list: {}", error))?,
+            programs: serde_json::from_str(programs)
+                .map_err(|error| format!("invalid program list: {}", error))?,
+        })
+    }
+
+    /// Reads `sha1-hashes.json` and `programs.json` from a directory
+    pub fn load(directory: &Path) -> Result<Database, String> {
+        let read = |name: &str| {
+            let path = directory.join(name);
+            fs::read_to_string(&path)
+                .map_err(|error| format!("failed to read {}: {}", path.display(), error))
+        };
+        Database::parse(&read("sha1-hashes.json")?, &read("programs.json")?)
+    }
+
+    pub fn lookup(&self, rom: &[u8]) -> Option<Profile> {
+        let hash = sha1(rom);
+        let program = self.programs.get(*self.hashes.get(&hash)?)?;
+        let rom = program.roms.get(&hash)?;
+
+        // The database lists the most suitable platform first
+        let preset = rom
+            .platforms
+            .iter()
+            .chain(rom.quirky_platforms.keys())
+            .find_map(|id| quirks::find_preset(id))?;
+
+        let mut quirks = preset.quirks;
+        if let Some(overrides) = rom.quirky_platforms.get(preset.id) {
+            overrides.apply(&mut quirks);
+        }
+
+        Some(Profile {
+            title: program.title.clone(),
+            authors: program.authors.clone(),
+            preset,
+            quirks,
+            speed: rom.tickrate.unwrap_or(preset.speed),
+            keys: rom.keys.clone(),
+            palette: rom
+                .colors
+                .as_ref()
+                .and_then(|colors| palette(&colors.pixels)),
+        })
+    }
+}
+
+/// The lowercase hex SHA-1 the database is keyed by
+pub fn sha1(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+/// Builds a palette from the first two or four pixel colours
+fn palette(pixels: &[String]) -> Option<Palette> {
+    let count = if pixels.len() >= 4 { 4 } else { 2 };
+    Palette::parse(&pixels.get(..count)?.join(",")).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Platform;
+
+    const ROM: [u8; 4] = [0x00, 0xE0, 0x12, 0x02];
+
+    fn database(rom: &str) -> Database {
+        let hashes = format!(r#"{{"{}": 0}}"#, sha1(&ROM));
+        let programs = format!(
+            r#"[{{"title": "Blank", "authors": ["A", "B"], "release": "2024",
+                  "roms": {{"{}": {}}}}}]"#,
+            sha1(&ROM),
+            rom
+        );
+        Database::parse(&hashes, &programs).unwrap()
+    }
+
+    #[test]
+    fn test_lookup() {
+        let database = database(
+            r##"{"file": "blank.ch8", "platforms": ["superchip", "modernChip8"], "tickrate": 9,
+                 "quirkyPlatforms": {"superchip": {"wrap": true}},
+                 "keys": {"up": 5}, "colors": {"pixels": ["#000000", "#ff8000"]}}"##,
+        );
+        let profile = database.lookup(&ROM).unwrap();
+
+        assert_eq!(profile.describe(), "Blank by A, B");
+        assert_eq!(profile.preset.id, "superchip");
+        assert!(profile.quirks.wrap && profile.quirks.shift);
+        assert_eq!(profile.speed, 9);
+        assert_eq!(profile.keys["up"], 5);
+        assert_eq!(profile.palette, Palette::parse("000000,ff8000").ok());
+
+        assert!(database.lookup(&[0x00, 0xE0]).is_none());
+    }
+
+    #[test]
+    fn test_platform_defaults() {
+        let profile = database(r#"{"platforms": ["xochip"]}"#)
+            .lookup(&ROM)
+            .unwrap();
+
+        assert_eq!(profile.preset.platform, Platform::XoChip);
+        assert_eq!(profile.speed, 100);
+        assert_eq!(profile.palette, None);
+    }
+}
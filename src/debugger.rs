@@ -0,0 +1,391 @@
+use crate::interpreter;
+use crate::keymap::Keymap;
+use crate::memory::Memory;
+use crate::palette::Palette;
+use crate::tui;
+use crate::tui::{Keypad, Screen};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+use sdl2::keyboard::Keycode;
+use std::collections::HashSet;
+use std::io;
+use std::time::Instant;
+
+/// Bytes shown per line of the memory pane
+const DUMP_WIDTH: usize = 8;
+
+/// Full-screen terminal debugger around the interpreter.
+pub struct Debugger {
+    pub breakpoints: HashSet<u16>,
+    pub paused: bool,
+    /// Address selected in the disassembly pane, where breakpoints are toggled
+    cursor: u16,
+    /// Set when continuing so the breakpoint we're stopped on doesn't immediately trigger again
+    resuming: bool,
+    status: String,
+}
+
+impl Debugger {
+    pub fn new(start: u16) -> Debugger {
+        Debugger {
+            breakpoints: HashSet::new(),
+            paused: true,
+            cursor: start,
+            resuming: false,
+            status: String::from("paused"),
+        }
+    }
+
+    /// Runs the debugger until Escape or Ctrl+C is pressed
+    pub fn run(&mut self, memory: &mut Memory, palette: &Palette) -> io::Result<()> {
+        let screen = Screen::enter()?;
+        // No keymap, since the arrow keys move the cursor
+        let mut keypad = Keypad::new(screen.enhanced, Keymap::default());
+        let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+        terminal.clear()?;
+
+        loop {
+            let deadline = Instant::now() + tui::FRAME;
+
+            while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+                if !event::poll(timeout)? {
+                    break;
+                }
+
+                let key = match event::read()? {
+                    Event::Key(key) => key,
+                    _ => continue,
+                };
+
+                if tui::is_quit(&key) {
+                    return Ok(());
+                }
+
+                if key.kind == KeyEventKind::Release || keypad.handle(&key) {
+                    continue;
+                }
+
+                self.handle_key(key.code, memory);
+            }
+
+            let (pressed_keys, new_keys) = keypad.next_frame();
+            if !self.paused {
+                self.run_frame(memory, &pressed_keys, &new_keys);
+            }
+
+            terminal.draw(|frame| self.draw(frame, memory, palette))?;
+        }
+    }
+
+    fn handle_key(&mut self, code: KeyCode, memory: &mut Memory) {
+        match code {
+            KeyCode::F(5) => {
+                self.paused = !self.paused;
+                self.resuming = !self.paused;
+                self.status = String::from(if self.paused { "paused" } else { "running" });
+                self.cursor = memory.program_counter;
+            }
+            KeyCode::F(10) if self.paused => {
+                self.status = match interpreter::step(memory, &HashSet::new(), &HashSet::new()) {
+                    Ok(()) => String::from("stepped"),
+                    Err(error) => error.to_string(),
+                };
+                self.cursor = memory.program_counter;
+            }
+            KeyCode::F(9) => self.toggle_breakpoint(self.cursor),
+            KeyCode::Up => self.cursor = self.cursor.saturating_sub(2),
+            KeyCode::Down => {
+                self.cursor = (self.cursor as usize + 2).min(memory.ram.len() - 2) as u16
+            }
+            _ => {}
+        }
+    }
+
+    pub fn toggle_breakpoint(&mut self, address: u16) {
+        if !self.breakpoints.remove(&address) {
+            self.breakpoints.insert(address);
+        }
+    }
+
+    /// Same as the main loop's frame, but stopping as soon as a breakpoint is reached
+    fn run_frame(
+        &mut self,
+        memory: &mut Memory,
+        pressed_keys: &HashSet<Keycode>,
+        new_keys: &HashSet<Keycode>,
+    ) {
+        for _ in 0..memory.speed {
+            if !self.resuming && self.breakpoints.contains(&memory.program_counter) {
+                self.paused = true;
+                self.cursor = memory.program_counter;
+                self.status = format!("breakpoint at 0x{:03X}", memory.program_counter);
+                return;
+            }
+
+            self.resuming = false;
+            let vblank = interpreter::waits_for_vblank(memory);
+            if let Err(error) = interpreter::step(memory, pressed_keys, new_keys) {
+                self.paused = true;
+                self.cursor = memory.program_counter;
+                self.status = error.to_string();
+                return;
+            }
+            if vblank {
+                break;
+            }
+        }
+
+        memory.tick_timers();
+        self.cursor = memory.program_counter;
+    }
+
+    fn draw(&self, frame: &mut Frame, memory: &Memory, palette: &Palette) {
+        let [main, help] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.size());
+        let [code, state, screen] = Layout::horizontal([
+            Constraint::Length(32),
+            Constraint::Length(34),
+            Constraint::Min(66),
+        ])
+        .areas(main);
+        let [registers, stack] =
+            Layout::vertical([Constraint::Length(12), Constraint::Min(0)]).areas(state);
+        let [display, ram] =
+            Layout::vertical([Constraint::Length(18), Constraint::Min(0)]).areas(screen);
+
+        self.draw_disassembly(frame, code, memory);
+        draw_registers(frame, registers, memory);
+        draw_stack(frame, stack, memory);
+        draw_display(frame, display, memory, palette);
+        draw_ram(frame, ram, memory);
+
+        let help_text = format!(
+            " {} | F5 continue/pause  F10 step  F9 breakpoint  Up/Down select  Esc quit",
+            self.status
+        );
+        frame.render_widget(
+            Paragraph::new(help_text).style(Style::default().add_modifier(Modifier::REVERSED)),
+            help,
+        );
+    }
+
+    fn draw_disassembly(&self, frame: &mut Frame, area: Rect, memory: &Memory) {
+        let rows = area.height.saturating_sub(2) as usize;
+        let lines: Vec<Line> = disassembly_window(self.cursor, rows, memory.ram.len())
+            .map(|address| {
+                let index = address as usize;
+                let instruction = interpreter::parse(memory.ram[index], memory.ram[index + 1]);
+
+                let marker = match (
+                    address == memory.program_counter,
+                    self.breakpoints.contains(&address),
+                ) {
+                    (true, true) => "*>",
+                    (true, false) => " >",
+                    (false, true) => "* ",
+                    (false, false) => "  ",
+                };
+                let text = format!(
+                    "{} {:03X}  {:02X}{:02X}  {}",
+                    marker,
+                    address,
+                    memory.ram[index],
+                    memory.ram[index + 1],
+                    instruction
+                );
+
+                let mut style = Style::default();
+                if self.breakpoints.contains(&address) {
+                    style = style.fg(Color::Red);
+                }
+                if address == memory.program_counter {
+                    style = style.add_modifier(Modifier::BOLD);
+                }
+                if address == self.cursor {
+                    style = style.add_modifier(Modifier::REVERSED);
+                }
+
+                Line::styled(text, style)
+            })
+            .collect();
+
+        frame.render_widget(Paragraph::new(lines).block(titled("Disassembly")), area);
+    }
+}
+
+fn draw_registers(frame: &mut Frame, area: Rect, memory: &Memory) {
+    let mut lines: Vec<Line> = (0..8)
+        .map(|reg| {
+            Line::from(format!(
+                "V{:X} {:02X} ({:3})   V{:X} {:02X} ({:3})",
+                reg,
+                memory.registers[reg],
+                memory.registers[reg],
+                reg + 8,
+                memory.registers[reg + 8],
+                memory.registers[reg + 8]
+            ))
+        })
+        .collect();
+
+    lines.push(Line::from(format!(
+        "I  {:03X}      PC {:03X}",
+        memory.i, memory.program_counter
+    )));
+    lines.push(Line::from(format!(
+        "DT {:02X}       ST {:02X}",
+        memory.delay, memory.sound
+    )));
+
+    frame.render_widget(Paragraph::new(lines).block(titled("Registers")), area);
+}
+
+fn draw_stack(frame: &mut Frame, area: Rect, memory: &Memory) {
+    let lines: Vec<Line> = memory
+        .stack
+        .iter()
+        .enumerate()
+        .map(|(index, address)| {
+            let text = format!("{:X}  {:03X}", index, address);
+            if index == memory.stack_pointer {
+                Line::styled(
+                    format!("{} <- SP", text),
+                    Style::default().fg(Color::Yellow),
+                )
+            } else {
+                Line::from(text)
+            }
+        })
+        .collect();
+
+    frame.render_widget(Paragraph::new(lines).block(titled("Stack")), area);
+}
+
+fn draw_display(frame: &mut Frame, area: Rect, memory: &Memory, palette: &Palette) {
+    let lines: Vec<Line> = memory
+        .display
+        .chunks(2)
+        .map(|rows| {
+            let spans: Vec<Span> = rows[0]
+                .iter()
+                .zip(rows[1].iter())
+                .map(|(&top, &bottom)| {
+                    Span::styled(
+                        "▀",
+                        Style::default()
+                            .fg(to_color(tui::to_terminal_color(palette, top)))
+                            .bg(to_color(tui::to_terminal_color(palette, bottom))),
+                    )
+                })
+                .collect();
+            Line::from(spans)
+        })
+        .collect();
+
+    frame.render_widget(Paragraph::new(lines).block(titled("Display")), area);
+}
+
+fn draw_ram(frame: &mut Frame, area: Rect, memory: &Memory) {
+    let rows = area.height.saturating_sub(2) as usize;
+    let start = dump_start(memory.i as usize, rows, memory.ram.len());
+    let end = (start + rows * DUMP_WIDTH).min(memory.ram.len());
+
+    let lines: Vec<Line> = (start..end)
+        .step_by(DUMP_WIDTH)
+        .map(|line_start| {
+            let mut spans = vec![Span::raw(format!("{:03X}  ", line_start))];
+            for address in line_start..(line_start + DUMP_WIDTH).min(end) {
+                let text = format!("{:02X} ", memory.ram[address]);
+                if address == memory.i as usize {
+                    spans.push(Span::styled(text, Style::default().fg(Color::Yellow)));
+                } else {
+                    spans.push(Span::raw(text));
+                }
+            }
+            Line::from(spans)
+        })
+        .collect();
+
+    frame.render_widget(
+        Paragraph::new(lines).block(titled(&format!("Memory @ I = {:03X}", memory.i))),
+        area,
+    );
+}
+
+fn titled(title: &str) -> Block<'_> {
+    Block::default().borders(Borders::ALL).title(title)
+}
+
+fn to_color(color: crossterm::style::Color) -> Color {
+    match color {
+        crossterm::style::Color::Rgb { r, g, b } => Color::Rgb(r, g, b),
+        _ => Color::Reset,
+    }
+}
+
+/// Instruction addresses to list, keeping `center` in the middle where possible
+fn disassembly_window(center: u16, rows: usize, ram_size: usize) -> impl Iterator<Item = u16> {
+    let before = (rows / 2) as u16 * 2;
+    // Stay aligned with `center` so odd addresses are disassembled correctly
+    let start = center - before.min(center - center % 2);
+    let last = (ram_size - 2) as u16;
+
+    (0..rows as u16)
+        .map_while(move |row| start.checked_add(row * 2))
+        .take_while(move |&address| address <= last)
+}
+
+/// First address of the memory dump, a line-aligned window that puts `i` roughly a quarter of the way down
+fn dump_start(i: usize, rows: usize, ram_size: usize) -> usize {
+    let aligned = i - i % DUMP_WIDTH;
+    let start = aligned.saturating_sub(rows / 4 * DUMP_WIDTH);
+    let last_start = ram_size.saturating_sub(rows * DUMP_WIDTH);
+
+    start.min(last_start - last_start % DUMP_WIDTH)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::{RAM_SIZE, XO_CHIP_RAM_SIZE};
+
+    #[test]
+    fn test_disassembly_window_centered() {
+        let addresses: Vec<u16> = disassembly_window(0x210, 5, RAM_SIZE).collect();
+        assert_eq!(addresses, vec![0x20C, 0x20E, 0x210, 0x212, 0x214]);
+    }
+
+    #[test]
+    fn test_disassembly_window_clamped() {
+        let addresses: Vec<u16> = disassembly_window(0x2, 6, RAM_SIZE).collect();
+        assert_eq!(addresses, vec![0x0, 0x2, 0x4, 0x6, 0x8, 0xA]);
+
+        let addresses: Vec<u16> = disassembly_window(0xFFB, 6, RAM_SIZE).collect();
+        assert_eq!(addresses, vec![0xFF5, 0xFF7, 0xFF9, 0xFFB, 0xFFD]);
+
+        let addresses: Vec<u16> = disassembly_window(0xFFE, 6, RAM_SIZE).collect();
+        assert_eq!(addresses, vec![0xFF8, 0xFFA, 0xFFC, 0xFFE]);
+    }
+
+    #[test]
+    fn test_disassembly_window_xo_chip_end() {
+        let addresses: Vec<u16> = disassembly_window(0xFFFE, 6, XO_CHIP_RAM_SIZE).collect();
+        assert_eq!(addresses, vec![0xFFF8, 0xFFFA, 0xFFFC, 0xFFFE]);
+
+        let addresses: Vec<u16> = disassembly_window(0xFFFF, 6, XO_CHIP_RAM_SIZE).collect();
+        assert_eq!(addresses, vec![0xFFF9, 0xFFFB, 0xFFFD]);
+    }
+
+    #[test]
+    fn test_dump_start() {
+        assert_eq!(dump_start(0x300, 8, RAM_SIZE), 0x2F0);
+        assert_eq!(dump_start(0x3, 8, RAM_SIZE), 0x0);
+        assert_eq!(dump_start(0xFFE, 8, RAM_SIZE), 0xFC0);
+        assert_eq!(dump_start(0xFFFF, 8, XO_CHIP_RAM_SIZE), 0xFFC0);
+    }
+}
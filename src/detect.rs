@@ -0,0 +1,288 @@
+use crate::cfg::BasicBlock;
+use crate::cfg::Cfg;
+use crate::instructions::Instruction;
+use crate::interpreter;
+use crate::quirks;
+use crate::quirks::Platform;
+use crate::quirks::Preset;
+use std::collections::BTreeSet;
+
+/// A guess at what a ROM missing from the database was written for
+#[derive(Debug, PartialEq)]
+pub struct Detection {
+    /// None when nothing in the ROM points away from the default behaviour
+    pub preset: Option<&'static Preset>,
+    /// The evidence for the guess, one line each
+    pub reasons: Vec<String>,
+}
+
+/// Scans the code reachable in a ROM loaded at `origin` for instructions and idioms that
+/// only make sense on one platform. XO-CHIP is preferred over SUPER-CHIP, and either over
+/// CHIP-8 ROMs that rely on COSMAC VIP behaviour.
+pub fn detect(rom: &[u8], origin: u16) -> Detection {
+    let cfg = Cfg::build(rom, origin);
+
+    let mut xo_chip = Vec::new();
+    let mut super_chip = Vec::new();
+    let mut cosmac_vip = Vec::new();
+
+    // Anything the graph reached, including the first unknown instruction on each path,
+    // since that's where SUPER-CHIP and XO-CHIP code stops decoding
+    let addresses = cfg
+        .blocks
+        .values()
+        .flat_map(|block| (block.start..block.end).step_by(2))
+        .chain(cfg.invalid.iter().copied());
+
+    let mut seen = BTreeSet::new();
+    for address in addresses {
+        let opcode = opcode_at(rom, origin, address);
+        let instruction = interpreter::parse((opcode >> 8) as u8, opcode as u8);
+
+        if let Some((platform, description)) = extension(&instruction, opcode) {
+            // One line per kind of instruction is enough
+            if seen.insert(description) {
+                let reason = format!("{:04X} at 0x{:03X} {}", opcode, address, description);
+                match platform {
+                    Platform::XoChip => xo_chip.push(reason),
+                    _ => super_chip.push(reason),
+                }
+            }
+        }
+
+        // Chipper, the usual SUPER-CHIP assembler, writes Vy as 0 when it isn't used,
+        // so only a distinct non-zero Vy shows the ROM meant it
+        if let Instruction::ShiftRight { vx, vy } | Instruction::ShiftLeft { vx, vy } = instruction
+        {
+            if vx != vy && vy != 0 && seen.insert("shift") {
+                cosmac_vip.push(format!(
+                    "{:04X} at 0x{:03X} shifts V{:X} into V{:X}, as on the COSMAC VIP",
+                    opcode, address, vy, vx
+                ));
+            }
+        }
+    }
+
+    for block in cfg.blocks.values() {
+        if let Some(address) = store_loop(&cfg, block, rom, origin) {
+            cosmac_vip.push(format!(
+                "{:04X} at 0x{:03X} runs in a loop without reloading I, so it expects I to advance",
+                opcode_at(rom, origin, address),
+                address
+            ));
+            break;
+        }
+    }
+
+    let (id, reasons) = if !xo_chip.is_empty() {
+        ("xochip", xo_chip)
+    } else if !super_chip.is_empty() {
+        ("superchip", super_chip)
+    } else if !cosmac_vip.is_empty() {
+        ("originalChip8", cosmac_vip)
+    } else {
+        return Detection {
+            preset: None,
+            reasons: Vec::new(),
+        };
+    };
+
+    Detection {
+        preset: quirks::find_preset(id),
+        reasons,
+    }
+}
+
+/// Signatures of files that get mistaken for ROMs, with what they are
+const SIGNATURES: [(&[u8], &str); 8] = [
+    (b"\x7FELF", "an ELF executable"),
+    (b"MZ", "a Windows executable"),
+    (b"PK\x03\x04", "a zip archive"),
+    (b"\x1F\x8B", "a gzip archive"),
+    (b"\x89PNG", "a PNG image"),
+    (b"GIF8", "a GIF image"),
+    (b"%PDF", "a PDF document"),
+    (b"\xCA\xFE\xBA\xBE", "a Mach-O or Java class file"),
+];
+
+/// Why a file doesn't look like a CHIP-8 ROM, if it doesn't
+pub fn foreign_format(rom: &[u8]) -> Option<String> {
+    if let Some((_, kind)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| rom.starts_with(signature))
+    {
+        return Some(format!("looks like {}", kind));
+    }
+
+    // Source code passed in place of the assembled ROM, say
+    let text = rom
+        .iter()
+        .filter(|&&byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace())
+        .count();
+    if rom.len() >= 16 && text * 10 >= rom.len() * 9 {
+        return Some(String::from("looks like a text file"));
+    }
+
+    let opcode = ((*rom.first()? as u16) << 8) | *rom.get(1)? as u16;
+    let instruction = interpreter::parse((opcode >> 8) as u8, opcode as u8);
+    if instruction == Instruction::Invalid && extension(&instruction, opcode).is_none() {
+        return Some(format!(
+            "starts with {:04X}, which isn't an instruction",
+            opcode
+        ));
+    }
+
+    None
+}
+
+/// Instructions beyond CHIP-8, with what they do
+fn extension(instruction: &Instruction, opcode: u16) -> Option<(Platform, &'static str)> {
+    match instruction {
+        Instruction::Draw { nibble: 0, .. } => {
+            return Some((Platform::SuperChip, "draws a 16x16 sprite"))
+        }
+        Instruction::Invalid => {}
+        _ => return None,
+    }
+
+    let nibbles = (
+        opcode >> 12,
+        (opcode >> 8) & 0xF,
+        (opcode >> 4) & 0xF,
+        opcode & 0xF,
+    );
+    let found = match nibbles {
+        (0x0, 0x0, 0xC, _) => (Platform::SuperChip, "scrolls the display down"),
+        (0x0, 0x0, 0xF, 0xB) => (Platform::SuperChip, "scrolls the display right"),
+        (0x0, 0x0, 0xF, 0xC) => (Platform::SuperChip, "scrolls the display left"),
+        (0x0, 0x0, 0xF, 0xD) => (Platform::SuperChip, "exits the interpreter"),
+        (0x0, 0x0, 0xF, 0xE) => (Platform::SuperChip, "switches to low resolution"),
+        (0x0, 0x0, 0xF, 0xF) => (Platform::SuperChip, "switches to high resolution"),
+        (0xF, _, 0x3, 0x0) => (Platform::SuperChip, "points I at a large font digit"),
+        (0xF, _, 0x7, 0x5) => (Platform::SuperChip, "saves registers to the flags"),
+        (0xF, _, 0x8, 0x5) => (Platform::SuperChip, "loads registers from the flags"),
+        (0x0, 0x0, 0xD, _) => (Platform::XoChip, "scrolls the display up"),
+        (0x5, _, _, 0x2) => (Platform::XoChip, "saves a range of registers"),
+        (0x5, _, _, 0x3) => (Platform::XoChip, "loads a range of registers"),
+        (0xF, 0x0, 0x0, 0x0) => (Platform::XoChip, "loads a 16-bit address into I"),
+        (0xF, _, 0x0, 0x1) => (Platform::XoChip, "selects the drawing planes"),
+        (0xF, 0x0, 0x0, 0x2) => (Platform::XoChip, "loads an audio pattern"),
+        (0xF, _, 0x3, 0xA) => (Platform::XoChip, "sets the audio pitch"),
+        _ => return None,
+    };
+
+    Some(found)
+}
+
+/// The first `Fx55` or `Fx65` in `block` that control can come back round to without
+/// passing an `LD I`, which only works if each store moves I along
+fn store_loop(cfg: &Cfg, block: &BasicBlock, rom: &[u8], origin: u16) -> Option<u16> {
+    let decode = |address: u16| {
+        let opcode = opcode_at(rom, origin, address);
+        interpreter::parse((opcode >> 8) as u8, opcode as u8)
+    };
+    let loads_i = |start: u16, end: u16| {
+        (start..end)
+            .step_by(2)
+            .any(|address| matches!(decode(address), Instruction::LoadAddress(_)))
+    };
+
+    let mut store = None;
+    for address in (block.start..block.end).step_by(2) {
+        match decode(address) {
+            Instruction::LoadRegisters { .. } | Instruction::ReadRegisters { .. } => {
+                store = store.or(Some(address))
+            }
+            Instruction::LoadAddress(_) => store = None,
+            _ => {}
+        }
+    }
+    let store = store?;
+    // Coming back round to the top reloads I before the store
+    if loads_i(block.start, store) {
+        return None;
+    }
+
+    let mut visited = BTreeSet::new();
+    let mut pending: Vec<u16> = block.successors.iter().map(|&(target, _)| target).collect();
+    while let Some(start) = pending.pop() {
+        if start == block.start {
+            return Some(store);
+        }
+        let next = match cfg.blocks.get(&start) {
+            Some(next) if visited.insert(start) && !loads_i(next.start, next.end) => next,
+            _ => continue,
+        };
+        pending.extend(next.successors.iter().map(|&(target, _)| target));
+    }
+
+    None
+}
+
+fn opcode_at(rom: &[u8], origin: u16, address: u16) -> u16 {
+    let offset = (address - origin) as usize;
+    ((rom[offset] as u16) << 8) | rom[offset + 1] as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detected(rom: &[u8]) -> Option<&'static str> {
+        detect(rom, 0x200).preset.map(|preset| preset.id)
+    }
+
+    #[test]
+    fn test_extensions() {
+        // CLS; HIGH; JP 204
+        let detection = detect(&[0x00, 0xE0, 0x00, 0xFF, 0x12, 0x04], 0x200);
+        assert_eq!(detection.preset.unwrap().platform, Platform::SuperChip);
+        assert_eq!(
+            detection.reasons,
+            ["00FF at 0x202 switches to high resolution"]
+        );
+
+        // DRW V0, V1, 0; plane 3
+        assert_eq!(detected(&[0xD0, 0x10, 0xF3, 0x01]), Some("xochip"));
+    }
+
+    #[test]
+    fn test_foreign_format() {
+        assert_eq!(foreign_format(&[0x00, 0xE0, 0x12, 0x00]), None);
+        // Starting with a SUPER-CHIP instruction is fine
+        assert_eq!(foreign_format(&[0x00, 0xFF]), None);
+
+        assert_eq!(
+            foreign_format(b"\x7FELF\x02\x01\x01"),
+            Some(String::from("looks like an ELF executable"))
+        );
+        assert_eq!(
+            foreign_format(b"main:\n    CLS\n    JP main\n"),
+            Some(String::from("looks like a text file"))
+        );
+        assert_eq!(
+            foreign_format(&[0xFF, 0xFF]),
+            Some(String::from("starts with FFFF, which isn't an instruction"))
+        );
+    }
+
+    #[test]
+    fn test_cosmac_vip_idioms() {
+        // SHR V0, V1; JP 202
+        assert_eq!(detected(&[0x80, 0x16, 0x12, 0x02]), Some("originalChip8"));
+        // SHR V0 as Chipper writes it
+        assert_eq!(detected(&[0x80, 0x06, 0x12, 0x02]), None);
+
+        // LD I, 0x300; loop: LD [I], V0; ADD V1, 1; SE V1, 4; JP loop; JP 20C
+        let rom = [
+            0xA3, 0x00, 0xF0, 0x55, 0x71, 0x01, 0x31, 0x04, 0x12, 0x02, 0x12, 0x0A,
+        ];
+        assert_eq!(detected(&rom), Some("originalChip8"));
+
+        // The same loop reloading I each time round
+        let rom = [
+            0xA3, 0x00, 0xF0, 0x55, 0x71, 0x01, 0x31, 0x04, 0x12, 0x00, 0x12, 0x0A,
+        ];
+        assert_eq!(detected(&rom), None);
+    }
+}
@@ -0,0 +1,112 @@
+/// Width of a character in pixels
+pub const GLYPH_WIDTH: usize = 3;
+/// Height of a character in pixels
+pub const GLYPH_HEIGHT: usize = 5;
+/// How far along each character moves the next, leaving a pixel between them
+pub const ADVANCE: usize = GLYPH_WIDTH + 1;
+
+/// A 3x5 pixel font for text drawn in the window, such as the ROM browser. Each row is
+/// three bits with the leftmost pixel in bit 2. Letters are all capitals.
+const GLYPHS: [(char, [u8; GLYPH_HEIGHT]); 55] = [
+    (' ', [0, 0, 0, 0, 0]),
+    ('0', [7, 5, 5, 5, 7]),
+    ('1', [2, 6, 2, 2, 7]),
+    ('2', [7, 1, 7, 4, 7]),
+    ('3', [7, 1, 7, 1, 7]),
+    ('4', [5, 5, 7, 1, 1]),
+    ('5', [7, 4, 7, 1, 7]),
+    ('6', [7, 4, 7, 5, 7]),
+    ('7', [7, 1, 1, 1, 1]),
+    ('8', [7, 5, 7, 5, 7]),
+    ('9', [7, 5, 7, 1, 7]),
+    ('A', [2, 5, 7, 5, 5]),
+    ('B', [6, 5, 6, 5, 6]),
+    ('C', [3, 4, 4, 4, 3]),
+    ('D', [6, 5, 5, 5, 6]),
+    ('E', [7, 4, 6, 4, 7]),
+    ('F', [7, 4, 6, 4, 4]),
+    ('G', [3, 4, 5, 5, 3]),
+    ('H', [5, 5, 7, 5, 5]),
+    ('I', [7, 2, 2, 2, 7]),
+    ('J', [1, 1, 1, 5, 2]),
+    ('K', [5, 5, 6, 5, 5]),
+    ('L', [4, 4, 4, 4, 7]),
+    ('M', [5, 7, 7, 5, 5]),
+    ('N', [6, 5, 5, 5, 5]),
+    ('O', [2, 5, 5, 5, 2]),
+    ('P', [6, 5, 6, 4, 4]),
+    ('Q', [2, 5, 5, 6, 3]),
+    ('R', [6, 5, 6, 5, 5]),
+    ('S', [3, 4, 2, 1, 6]),
+    ('T', [7, 2, 2, 2, 2]),
+    ('U', [5, 5, 5, 5, 7]),
+    ('V', [5, 5, 5, 5, 2]),
+    ('W', [5, 5, 7, 7, 5]),
+    ('X', [5, 5, 2, 5, 5]),
+    ('Y', [5, 5, 2, 2, 2]),
+    ('Z', [7, 1, 2, 4, 7]),
+    ('.', [0, 0, 0, 0, 2]),
+    (',', [0, 0, 0, 2, 4]),
+    ('-', [0, 0, 7, 0, 0]),
+    ('\'', [2, 2, 0, 0, 0]),
+    ('!', [2, 2, 2, 0, 2]),
+    ('?', [7, 1, 2, 0, 2]),
+    (':', [0, 2, 0, 2, 0]),
+    ('(', [1, 2, 2, 2, 1]),
+    (')', [4, 2, 2, 2, 4]),
+    ('/', [1, 1, 2, 4, 4]),
+    ('&', [2, 5, 2, 5, 3]),
+    ('+', [0, 2, 7, 2, 0]),
+    ('_', [0, 0, 0, 0, 7]),
+    ('#', [5, 7, 5, 7, 5]),
+    ('>', [4, 2, 1, 2, 4]),
+    ('<', [1, 2, 4, 2, 1]),
+    ('[', [3, 2, 2, 2, 3]),
+    (']', [6, 2, 2, 2, 6]),
+];
+
+/// The rows of a character, with lowercase drawn as capitals and anything else as `?`
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    let find = |character: char| GLYPHS.iter().find(|(known, _)| *known == character);
+    find(character.to_ascii_uppercase())
+        .or_else(|| find('?'))
+        .map(|(_, rows)| *rows)
+        .unwrap()
+}
+
+/// Draws `text` with its top left corner at `x`, `y` into `pixels`, rows of `width` palette
+/// indices, setting each pixel of the characters to `colour`. Anything off the edges is cut off.
+pub fn draw_text(pixels: &mut [u8], width: usize, x: usize, y: usize, text: &str, colour: u8) {
+    let height = pixels.len() / width;
+    for (index, character) in text.chars().enumerate() {
+        let left = x + index * ADVANCE;
+        if left >= width {
+            break;
+        }
+        for (row, bits) in glyph(character).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (0b100 >> col) != 0 && left + col < width && y + row < height {
+                    pixels[(y + row) * width + left + col] = colour;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_draw_text() {
+        let mut pixels = [0; 8 * 5];
+        draw_text(&mut pixels, 8, 0, 0, "t1", 1);
+
+        let rows: Vec<&[u8]> = pixels.chunks(8).collect();
+        assert_eq!(rows[0], [1, 1, 1, 0, 0, 1, 0, 0]);
+        assert_eq!(rows[4], [0, 1, 0, 0, 1, 1, 1, 0]);
+
+        // Unknown characters show up as question marks
+        assert_eq!(glyph('~'), glyph('?'));
+    }
+}
@@ -0,0 +1,511 @@
+//! GDB Remote Serial Protocol stub.
+//!
+//! Registers are exposed to the client in this order, all little endian:
+//!
+//! | number | register        | size    |
+//! |--------|-----------------|---------|
+//! | 0-15   | V0-VF           | 1 byte  |
+//! | 16     | I               | 2 bytes |
+//! | 17     | program counter | 2 bytes |
+//! | 18     | stack pointer   | 1 byte  |
+//! | 19     | delay timer     | 1 byte  |
+//! | 20     | sound timer     | 1 byte  |
+//!
+//! Addresses in memory packets map directly onto `ram`.
+
+use crate::interpreter;
+use crate::interpreter::ExecError;
+use crate::memory::Memory;
+use std::collections::HashSet;
+use std::io;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+const REGISTER_COUNT: usize = 21;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// What the session loop should do after a packet was handled
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+pub struct GdbStub {
+    breakpoints: HashSet<u16>,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub {
+            breakpoints: HashSet::new(),
+        }
+    }
+
+    /// Waits for a debugger on localhost and serves it until it detaches or kills the target
+    pub fn serve(&mut self, port: u16, memory: &mut Memory) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("waiting for gdb on 127.0.0.1:{}", port);
+
+        let (mut stream, address) = listener.accept()?;
+        println!("gdb connected from {}", address);
+        stream.set_nodelay(true)?;
+
+        while let Some(packet) = read_packet(&mut stream)? {
+            match self.handle(&packet, memory) {
+                Action::Reply(reply) => write_packet(&mut stream, &reply)?,
+                Action::Step => {
+                    let signal = match interpreter::step(memory, &HashSet::new(), &HashSet::new()) {
+                        Ok(()) => SIGTRAP,
+                        Err(error) => error_signal(&error),
+                    };
+                    write_packet(&mut stream, &stop_reply(signal))?;
+                }
+                Action::Continue => {
+                    let signal = self.continue_until_stopped(&mut stream, memory)?;
+                    write_packet(&mut stream, &stop_reply(signal))?;
+                }
+                Action::Detach => {
+                    write_packet(&mut stream, "OK")?;
+                    break;
+                }
+                Action::Kill => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str, memory: &mut Memory) -> Action {
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+
+        let reply = match command {
+            "?" => stop_reply(SIGTRAP),
+            "g" => read_registers(memory),
+            "G" => reply_result(write_registers(memory, args)),
+            "p" => match parse_hex(args) {
+                Some(number) if number < REGISTER_COUNT => encode_register(memory, number),
+                _ => error(),
+            },
+            "P" => reply_result(write_register(memory, args)),
+            "m" => read_memory(memory, args).unwrap_or_else(error),
+            "M" => reply_result(write_memory(memory, args)),
+            "Z" | "z" => self.breakpoint(command == "Z", args),
+            "c" => return Action::Continue,
+            "s" => return Action::Step,
+            "D" => return Action::Detach,
+            "k" => return Action::Kill,
+            _ if packet.starts_with("qSupported") => String::from("PacketSize=4000"),
+            _ if packet == "qAttached" => String::from("1"),
+            _ if packet == "qC" => String::from("QC1"),
+            _ if packet.starts_with("qfThreadInfo") => String::from("m1"),
+            _ if packet.starts_with("qsThreadInfo") => String::from("l"),
+            _ if packet.starts_with('H') => String::from("OK"),
+            // Anything else is unsupported, which the protocol signals with an empty reply
+            _ => String::new(),
+        };
+
+        Action::Reply(reply)
+    }
+
+    /// Handles `Z0,addr,kind` and `z0,addr,kind`. Only software breakpoints are supported.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let address = match (fields.next(), fields.next().and_then(parse_hex)) {
+            (Some("0"), Some(address)) => address as u16,
+            (Some("0"), None) => return error(),
+            _ => return String::new(),
+        };
+
+        if insert {
+            self.breakpoints.insert(address);
+        } else {
+            self.breakpoints.remove(&address);
+        }
+
+        String::from("OK")
+    }
+
+    /// Runs in real time until a breakpoint is hit or the client sends an interrupt, returning the stop signal
+    fn continue_until_stopped(
+        &self,
+        stream: &mut TcpStream,
+        memory: &mut Memory,
+    ) -> io::Result<u8> {
+        let no_keys = HashSet::new();
+        // Don't stop on the breakpoint we're continuing from
+        let mut resuming = true;
+
+        stream.set_nonblocking(true)?;
+        let signal = 'running: loop {
+            let deadline = Instant::now() + FRAME;
+
+            for _ in 0..memory.speed {
+                if !resuming && self.breakpoints.contains(&memory.program_counter) {
+                    break 'running SIGTRAP;
+                }
+                resuming = false;
+                let vblank = interpreter::waits_for_vblank(memory);
+                if let Err(error) = interpreter::step(memory, &no_keys, &no_keys) {
+                    break 'running error_signal(&error);
+                }
+                if vblank {
+                    break;
+                }
+            }
+            memory.tick_timers();
+
+            let mut byte = [0u8];
+            match stream.read(&mut byte) {
+                Ok(0) => break SIGINT,
+                Ok(_) if byte[0] == 0x03 => break SIGINT,
+                Ok(_) => {}
+                Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+                Err(error) => return Err(error),
+            }
+
+            if let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+                std::thread::sleep(remaining);
+            }
+        };
+        stream.set_nonblocking(false)?;
+
+        Ok(signal)
+    }
+}
+
+/// The signal reported to the client when execution fails, leaving the PC on the faulting instruction
+fn error_signal(error: &ExecError) -> u8 {
+    match error {
+        ExecError::InvalidInstruction { .. } => SIGILL,
+        _ => SIGSEGV,
+    }
+}
+
+/// Reads the next `$packet#checksum`, acknowledging it. Returns None when the connection closes.
+fn read_packet(stream: &mut impl ReadWrite) -> io::Result<Option<String>> {
+    let mut byte = [0u8];
+
+    loop {
+        // Skip acks and anything else between packets until the start of one
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+
+        let mut checksum = [0u8; 2];
+        stream.read_exact(&mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum)
+            .ok()
+            .and_then(|text| u8::from_str_radix(text, 16).ok());
+
+        if expected == Some(checksum_of(&data)) {
+            stream.write_all(b"+")?;
+            return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+        }
+
+        // Ask for a retransmission
+        stream.write_all(b"-")?;
+    }
+}
+
+fn write_packet(stream: &mut impl Write, data: &str) -> io::Result<()> {
+    write!(stream, "${}#{:02x}", data, checksum_of(data.as_bytes()))?;
+    stream.flush()
+}
+
+/// Shorthand for streams that can be both read from and written to, e.g. a `TcpStream`
+trait ReadWrite: Read + Write {}
+
+impl<T: Read + Write> ReadWrite for T {}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error() -> String {
+    String::from("E01")
+}
+
+fn reply_result(result: Option<()>) -> String {
+    match result {
+        Some(()) => String::from("OK"),
+        None => error(),
+    }
+}
+
+/// Size in bytes of a register in the layout documented at the top of this module
+fn register_size(number: usize) -> usize {
+    match number {
+        16 | 17 => 2,
+        _ => 1,
+    }
+}
+
+fn register_value(memory: &Memory, number: usize) -> u16 {
+    match number {
+        0..=15 => memory.registers[number] as u16,
+        16 => memory.i,
+        17 => memory.program_counter,
+        18 => memory.stack_pointer as u16,
+        19 => memory.delay as u16,
+        _ => memory.sound as u16,
+    }
+}
+
+fn set_register_value(memory: &mut Memory, number: usize, value: u16) -> Option<()> {
+    match number {
+        0..=15 => memory.registers[number] = value as u8,
+        16 => memory.i = value,
+        17 => memory.program_counter = value,
+        18 if (value as usize) <= memory.stack.len() => memory.stack_pointer = value as usize,
+        19 => memory.delay = value as u8,
+        20 => memory.sound = value as u8,
+        _ => return None,
+    }
+
+    Some(())
+}
+
+fn encode_register(memory: &Memory, number: usize) -> String {
+    let bytes = register_value(memory, number).to_le_bytes();
+    encode_hex(&bytes[..register_size(number)])
+}
+
+fn read_registers(memory: &Memory) -> String {
+    (0..REGISTER_COUNT)
+        .map(|number| encode_register(memory, number))
+        .collect()
+}
+
+/// Handles `G` with every register in order
+fn write_registers(memory: &mut Memory, args: &str) -> Option<()> {
+    let bytes = decode_hex(args)?;
+    let mut offset = 0;
+
+    for number in 0..REGISTER_COUNT {
+        let size = register_size(number);
+        let value = decode_le(bytes.get(offset..offset + size)?);
+        set_register_value(memory, number, value)?;
+        offset += size;
+    }
+
+    Some(())
+}
+
+/// Handles `Pn=value`
+fn write_register(memory: &mut Memory, args: &str) -> Option<()> {
+    let (number, value) = args.split_once('=')?;
+    let number = parse_hex(number)?;
+    let bytes = decode_hex(value)?;
+
+    if number >= REGISTER_COUNT || bytes.len() != register_size(number) {
+        return None;
+    }
+
+    set_register_value(memory, number, decode_le(&bytes))
+}
+
+/// Handles `maddr,length`
+fn read_memory(memory: &Memory, args: &str) -> Option<String> {
+    let (address, length) = args.split_once(',')?;
+    let start = parse_hex(address)?;
+    let end = start.checked_add(parse_hex(length)?)?;
+
+    memory.ram.get(start..end).map(encode_hex)
+}
+
+/// Handles `Maddr,length:data`
+fn write_memory(memory: &mut Memory, args: &str) -> Option<()> {
+    let (range, data) = args.split_once(':')?;
+    let (address, length) = range.split_once(',')?;
+    let start = parse_hex(address)?;
+    let bytes = decode_hex(data)?;
+
+    if bytes.len() != parse_hex(length)? {
+        return None;
+    }
+
+    memory
+        .ram
+        .get_mut(start..start.checked_add(bytes.len())?)?
+        .copy_from_slice(&bytes);
+
+    Some(())
+}
+
+fn parse_hex(text: &str) -> Option<usize> {
+    usize::from_str_radix(text, 16).ok()
+}
+
+fn decode_le(bytes: &[u8]) -> u16 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0u16, |value, &byte| (value << 8) | byte as u16)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(text.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn reply(stub: &mut GdbStub, memory: &mut Memory, packet: &str) -> String {
+        match stub.handle(packet, memory) {
+            Action::Reply(reply) => reply,
+            action => panic!("expected a reply, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_read_registers() {
+        let mut memory = Memory::new();
+        memory.registers[0] = 0xAB;
+        memory.i = 0x0123;
+
+        let registers = reply(&mut GdbStub::new(), &mut memory, "g");
+
+        assert_eq!(registers.len(), 23 * 2);
+        assert_eq!(&registers[0..2], "ab");
+        // I then the program counter, little endian
+        assert_eq!(&registers[32..40], "23010002");
+    }
+
+    #[test]
+    fn test_write_register() {
+        let mut memory = Memory::new();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(reply(&mut stub, &mut memory, "P11=0403"), "OK");
+        assert_eq!(memory.program_counter, 0x304);
+        assert_eq!(reply(&mut stub, &mut memory, "P5=7f"), "OK");
+        assert_eq!(memory.registers[5], 0x7F);
+        assert_eq!(reply(&mut stub, &mut memory, "P5=7f00"), "E01");
+    }
+
+    #[test]
+    fn test_memory_packets() {
+        let mut memory = Memory::new();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(reply(&mut stub, &mut memory, "M200,3:a1b2c3"), "OK");
+        assert_eq!(reply(&mut stub, &mut memory, "m200,3"), "a1b2c3");
+        assert_eq!(reply(&mut stub, &mut memory, "mffe,4"), "E01");
+    }
+
+    #[test]
+    fn test_breakpoints_and_execution() {
+        let mut memory = Memory::new();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(reply(&mut stub, &mut memory, "Z0,202,2"), "OK");
+        assert!(stub.breakpoints.contains(&0x202));
+        assert_eq!(reply(&mut stub, &mut memory, "z0,202,2"), "OK");
+        assert!(stub.breakpoints.is_empty());
+        assert_eq!(reply(&mut stub, &mut memory, "Z1,202,2"), "");
+
+        assert_eq!(stub.handle("c", &mut memory), Action::Continue);
+        assert_eq!(stub.handle("s", &mut memory), Action::Step);
+    }
+
+    #[test]
+    fn test_packet_framing() {
+        let mut stream = Cursor::new(b"+$m200,2#5d".to_vec());
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Some(String::from("m200,2"))
+        );
+
+        let mut out = Vec::new();
+        write_packet(&mut out, "OK").unwrap();
+        assert_eq!(out, b"$OK#9a");
+    }
+
+    #[test]
+    fn test_session_over_socket() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let server = std::thread::spawn(move || {
+            let mut memory = Memory::new();
+            // LD V0, 0x2A; JP 0x200
+            memory.load_rom(&[0x60, 0x2A, 0x12, 0x00]).unwrap();
+            GdbStub::new().serve(port, &mut memory).unwrap();
+            memory
+        });
+
+        let mut client = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        let mut exchange = |packet: &str| {
+            write_packet(&mut client, packet).unwrap();
+            let mut ack = [0u8];
+            client.read_exact(&mut ack).unwrap();
+            assert_eq!(ack[0], b'+');
+            read_packet(&mut client).unwrap().unwrap()
+        };
+
+        assert_eq!(exchange("s"), "S05");
+        assert_eq!(exchange("p0"), "2a");
+        assert_eq!(exchange("Z0,200,2"), "OK");
+        assert_eq!(exchange("c"), "S05");
+        assert_eq!(exchange("p11"), "0002");
+        assert_eq!(exchange("D"), "OK");
+
+        let memory = server.join().unwrap();
+        assert_eq!(memory.registers[0], 0x2A);
+    }
+}
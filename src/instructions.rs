@@ -8,6 +8,15 @@ pub struct Address {
 }
 
 impl Address {
+    /// The low 12 bits of `value` as an address operand
+    pub fn from_u16(value: u16) -> Address {
+        Address {
+            high: ((value >> 8) & 0xF) as u8,
+            middle: ((value >> 4) & 0xF) as u8,
+            low: (value & 0xF) as u8,
+        }
+    }
+
     pub fn to_u16(&self) -> u16 {
         ((self.high as u16) << 8) + ((self.middle as u16) << 4) + self.low as u16
     }
@@ -88,6 +97,7 @@ pub enum Instruction {
     /// 0x8xy6 - SHR Vx {, Vy}
     ShiftRight {
         vx: usize,
+        vy: usize,
     },
     /// 0x8xy7 - SUBN Vx, Vy
     SubtractReverse {
@@ -97,6 +107,7 @@ pub enum Instruction {
     /// 0x8xyE - SHL Vx {, Vy}
     ShiftLeft {
         vx: usize,
+        vy: usize,
     },
     /// 0x9xy0 - SNE Vx, Vy
     SkipIfNotEqualReg {
@@ -164,6 +175,128 @@ pub enum Instruction {
     },
 }
 
+impl Instruction {
+    /// Whether this conditionally skips the next instruction
+    pub fn is_skip(&self) -> bool {
+        matches!(
+            self,
+            Instruction::SkipIfEqualByte { .. }
+                | Instruction::SkipIfNotEqualByte { .. }
+                | Instruction::SkipIfEqualReg { .. }
+                | Instruction::SkipIfNotEqualReg { .. }
+                | Instruction::SkipIfKeyPressed { .. }
+                | Instruction::SkipIfNotKeyPressed { .. }
+        )
+    }
+
+    /// The two byte encoding, the inverse of `interpreter::parse`.
+    /// `Invalid` encodes as 0x0000, which parses back to it.
+    pub fn opcode(&self) -> u16 {
+        let encode = |high: u16, x: usize, y: usize, low: u16| {
+            (high << 12) | ((x as u16) << 8) | ((y as u16) << 4) | low
+        };
+        let with_byte =
+            |high: u16, x: usize, byte: u8| (high << 12) | ((x as u16) << 8) | byte as u16;
+
+        match self {
+            Instruction::Invalid => 0x0000,
+            Instruction::Clear => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::JumpTo(addr) => 0x1000 | addr.to_u16(),
+            Instruction::Call(addr) => 0x2000 | addr.to_u16(),
+            Instruction::SkipIfEqualByte { vx, byte } => with_byte(0x3, *vx, *byte),
+            Instruction::SkipIfNotEqualByte { vx, byte } => with_byte(0x4, *vx, *byte),
+            Instruction::SkipIfEqualReg { vx, vy } => encode(0x5, *vx, *vy, 0x0),
+            Instruction::LoadByte { vx, byte } => with_byte(0x6, *vx, *byte),
+            Instruction::AddByte { vx, byte } => with_byte(0x7, *vx, *byte),
+            Instruction::LoadReg { vx, vy } => encode(0x8, *vx, *vy, 0x0),
+            Instruction::Or { vx, vy } => encode(0x8, *vx, *vy, 0x1),
+            Instruction::And { vx, vy } => encode(0x8, *vx, *vy, 0x2),
+            Instruction::Xor { vx, vy } => encode(0x8, *vx, *vy, 0x3),
+            Instruction::AddReg { vx, vy } => encode(0x8, *vx, *vy, 0x4),
+            Instruction::Subtract { vx, vy } => encode(0x8, *vx, *vy, 0x5),
+            Instruction::ShiftRight { vx, vy } => encode(0x8, *vx, *vy, 0x6),
+            Instruction::SubtractReverse { vx, vy } => encode(0x8, *vx, *vy, 0x7),
+            Instruction::ShiftLeft { vx, vy } => encode(0x8, *vx, *vy, 0xE),
+            Instruction::SkipIfNotEqualReg { vx, vy } => encode(0x9, *vx, *vy, 0x0),
+            Instruction::LoadAddress(addr) => 0xA000 | addr.to_u16(),
+            Instruction::JumpOffset(addr) => 0xB000 | addr.to_u16(),
+            Instruction::Random { vx, byte } => with_byte(0xC, *vx, *byte),
+            Instruction::Draw { vx, vy, nibble } => encode(0xD, *vx, *vy, *nibble as u16),
+            Instruction::SkipIfKeyPressed { vx } => with_byte(0xE, *vx, 0x9E),
+            Instruction::SkipIfNotKeyPressed { vx } => with_byte(0xE, *vx, 0xA1),
+            Instruction::LoadDelay { vx } => with_byte(0xF, *vx, 0x07),
+            Instruction::LoadKeyPressed { vx } => with_byte(0xF, *vx, 0x0A),
+            Instruction::SetDelay { vx } => with_byte(0xF, *vx, 0x15),
+            Instruction::SetSound { vx } => with_byte(0xF, *vx, 0x18),
+            Instruction::AddAddressOffset { vx } => with_byte(0xF, *vx, 0x1E),
+            Instruction::LoadSprite { vx } => with_byte(0xF, *vx, 0x29),
+            Instruction::SetBCD { vx } => with_byte(0xF, *vx, 0x33),
+            Instruction::LoadRegisters { vx } => with_byte(0xF, *vx, 0x55),
+            Instruction::ReadRegisters { vx } => with_byte(0xF, *vx, 0x65),
+        }
+    }
+
+    /// The address operand of jumps, calls and `LD I`
+    pub fn target(&self) -> Option<u16> {
+        match self {
+            Instruction::JumpTo(addr)
+            | Instruction::Call(addr)
+            | Instruction::LoadAddress(addr)
+            | Instruction::JumpOffset(addr) => Some(addr.to_u16()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Invalid => write!(f, "???"),
+            Instruction::Clear => write!(f, "CLS"),
+            Instruction::Return => write!(f, "RET"),
+            Instruction::JumpTo(addr) => write!(f, "JP {:?}", addr),
+            Instruction::Call(addr) => write!(f, "CALL {:?}", addr),
+            Instruction::SkipIfEqualByte { vx, byte } => write!(f, "SE V{:X}, 0x{:02X}", vx, byte),
+            Instruction::SkipIfNotEqualByte { vx, byte } => {
+                write!(f, "SNE V{:X}, 0x{:02X}", vx, byte)
+            }
+            Instruction::SkipIfEqualReg { vx, vy } => write!(f, "SE V{:X}, V{:X}", vx, vy),
+            Instruction::LoadByte { vx, byte } => write!(f, "LD V{:X}, 0x{:02X}", vx, byte),
+            Instruction::AddByte { vx, byte } => write!(f, "ADD V{:X}, 0x{:02X}", vx, byte),
+            Instruction::LoadReg { vx, vy } => write!(f, "LD V{:X}, V{:X}", vx, vy),
+            Instruction::Or { vx, vy } => write!(f, "OR V{:X}, V{:X}", vx, vy),
+            Instruction::And { vx, vy } => write!(f, "AND V{:X}, V{:X}", vx, vy),
+            Instruction::Xor { vx, vy } => write!(f, "XOR V{:X}, V{:X}", vx, vy),
+            Instruction::AddReg { vx, vy } => write!(f, "ADD V{:X}, V{:X}", vx, vy),
+            Instruction::Subtract { vx, vy } => write!(f, "SUB V{:X}, V{:X}", vx, vy),
+            Instruction::ShiftRight { vx, vy } if vx == vy => write!(f, "SHR V{:X}", vx),
+            Instruction::ShiftRight { vx, vy } => write!(f, "SHR V{:X}, V{:X}", vx, vy),
+            Instruction::SubtractReverse { vx, vy } => write!(f, "SUBN V{:X}, V{:X}", vx, vy),
+            Instruction::ShiftLeft { vx, vy } if vx == vy => write!(f, "SHL V{:X}", vx),
+            Instruction::ShiftLeft { vx, vy } => write!(f, "SHL V{:X}, V{:X}", vx, vy),
+            Instruction::SkipIfNotEqualReg { vx, vy } => write!(f, "SNE V{:X}, V{:X}", vx, vy),
+            Instruction::LoadAddress(addr) => write!(f, "LD I, {:?}", addr),
+            Instruction::JumpOffset(addr) => write!(f, "JP V0, {:?}", addr),
+            Instruction::Random { vx, byte } => write!(f, "RND V{:X}, 0x{:02X}", vx, byte),
+            Instruction::Draw { vx, vy, nibble } => {
+                write!(f, "DRW V{:X}, V{:X}, {}", vx, vy, nibble)
+            }
+            Instruction::SkipIfKeyPressed { vx } => write!(f, "SKP V{:X}", vx),
+            Instruction::SkipIfNotKeyPressed { vx } => write!(f, "SKNP V{:X}", vx),
+            Instruction::LoadDelay { vx } => write!(f, "LD V{:X}, DT", vx),
+            Instruction::LoadKeyPressed { vx } => write!(f, "LD V{:X}, K", vx),
+            Instruction::SetDelay { vx } => write!(f, "LD DT, V{:X}", vx),
+            Instruction::SetSound { vx } => write!(f, "LD ST, V{:X}", vx),
+            Instruction::AddAddressOffset { vx } => write!(f, "ADD I, V{:X}", vx),
+            Instruction::LoadSprite { vx } => write!(f, "LD F, V{:X}", vx),
+            Instruction::SetBCD { vx } => write!(f, "LD B, V{:X}", vx),
+            Instruction::LoadRegisters { vx } => write!(f, "LD [I], V{:X}", vx),
+            Instruction::ReadRegisters { vx } => write!(f, "LD V{:X}, [I]", vx),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,4 +310,31 @@ mod tests {
         };
         assert_eq!(format!("{:?}", address), "0x218")
     }
+
+    #[test]
+    fn test_instruction_display() {
+        let address = Address {
+            high: 0x02,
+            middle: 0x0A,
+            low: 0x04,
+        };
+        assert_eq!(Instruction::Call(address).to_string(), "CALL 0x2A4");
+        assert_eq!(
+            Instruction::LoadByte { vx: 0xA, byte: 0x5 }.to_string(),
+            "LD VA, 0x05"
+        );
+        assert_eq!(
+            Instruction::Draw {
+                vx: 1,
+                vy: 2,
+                nibble: 15
+            }
+            .to_string(),
+            "DRW V1, V2, 15"
+        );
+        assert_eq!(
+            Instruction::LoadRegisters { vx: 3 }.to_string(),
+            "LD [I], V3"
+        );
+    }
 }
@@ -2,11 +2,13 @@ use crate::display_constants;
 use crate::instructions::Address;
 use crate::instructions::Instruction;
 use crate::memory::Memory;
+use crate::recompiler::Mismatch;
 use rand::Rng;
 use sdl2::keyboard::Keycode;
 use std::collections::HashSet;
+use std::fmt;
 
-const KEY_MAP: [Keycode; 16] = [
+pub const KEY_MAP: [Keycode; 16] = [
     Keycode::X,
     Keycode::Num1,
     Keycode::Num2,
@@ -25,6 +27,68 @@ const KEY_MAP: [Keycode; 16] = [
     Keycode::V,
 ];
 
+/// Why the machine could not carry on executing
+#[derive(Debug)]
+pub enum ExecError {
+    /// The bytes at `address` don't decode to a known instruction
+    InvalidInstruction { address: u16, opcode: u16 },
+    /// CALL at `address` with every stack slot in use
+    StackOverflow { address: u16 },
+    /// RET at `address` with nothing on the stack
+    StackUnderflow { address: u16 },
+    /// The instruction at `address` reads or writes ram past the end, starting from `target`
+    MemoryOutOfBounds { address: u16, target: usize },
+    /// The program counter moved past the end of ram
+    ProgramCounterOutOfBounds { address: u16 },
+    /// Cross-check mode found a compiled block that disagrees with the interpreter
+    Mismatch(Box<Mismatch>),
+}
+
+impl ExecError {
+    /// The address of the instruction that failed
+    pub fn address(&self) -> u16 {
+        match self {
+            ExecError::InvalidInstruction { address, .. }
+            | ExecError::StackOverflow { address }
+            | ExecError::StackUnderflow { address }
+            | ExecError::MemoryOutOfBounds { address, .. }
+            | ExecError::ProgramCounterOutOfBounds { address } => *address,
+            ExecError::Mismatch(mismatch) => mismatch.block_start,
+        }
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecError::InvalidInstruction { address, opcode } => {
+                write!(f, "invalid instruction {:04X} at 0x{:03X}", opcode, address)
+            }
+            ExecError::StackOverflow { address } => {
+                write!(f, "stack overflow calling from 0x{:03X}", address)
+            }
+            ExecError::StackUnderflow { address } => {
+                write!(f, "return with an empty stack at 0x{:03X}", address)
+            }
+            ExecError::MemoryOutOfBounds { address, target } => write!(
+                f,
+                "instruction at 0x{:03X} accessed memory out of bounds at 0x{:X}",
+                address, target
+            ),
+            ExecError::ProgramCounterOutOfBounds { address } => {
+                write!(
+                    f,
+                    "program counter ran off the end of memory at 0x{:X}",
+                    address
+                )
+            }
+            ExecError::Mismatch(mismatch) => write!(f, "{}", mismatch),
+        }
+    }
+}
+
+impl std::error::Error for ExecError {}
+
 pub fn parse(high_byte: u8, low_byte: u8) -> Instruction {
     match (
         get_upper_bits(high_byte),
@@ -96,14 +160,20 @@ pub fn parse(high_byte: u8, low_byte: u8) -> Instruction {
             vy: reg_y as usize,
         },
         //8xy6
-        (0x8, reg_x, _, 0x6) => Instruction::ShiftRight { vx: reg_x as usize },
+        (0x8, reg_x, reg_y, 0x6) => Instruction::ShiftRight {
+            vx: reg_x as usize,
+            vy: reg_y as usize,
+        },
         //8xy7
         (0x8, reg_x, reg_y, 0x7) => Instruction::SubtractReverse {
             vx: reg_x as usize,
             vy: reg_y as usize,
         },
         //8xyE
-        (0x8, reg_x, _, 0xE) => Instruction::ShiftLeft { vx: reg_x as usize },
+        (0x8, reg_x, reg_y, 0xE) => Instruction::ShiftLeft {
+            vx: reg_x as usize,
+            vy: reg_y as usize,
+        },
         //9xy0
         (0x9, reg_x, reg_y, 0x0) => Instruction::SkipIfNotEqualReg {
             vx: reg_x as usize,
@@ -150,144 +220,195 @@ pub fn parse(high_byte: u8, low_byte: u8) -> Instruction {
     }
 }
 
+/// The raw two byte opcode at `address`
+pub fn opcode_at(memory: &Memory, address: u16) -> u16 {
+    ((memory.ram[address as usize] as u16) << 8) | memory.ram[address as usize + 1] as u16
+}
+
+/// Parses and executes the instruction at the program counter
+pub fn step(
+    memory: &mut Memory,
+    pressed_keys: &HashSet<Keycode>,
+    new_keys: &HashSet<Keycode>,
+) -> Result<(), ExecError> {
+    let address = memory.program_counter;
+    if address as usize + 1 >= memory.ram.len() {
+        return Err(ExecError::ProgramCounterOutOfBounds { address });
+    }
+
+    let instruction = parse(
+        memory.ram[address as usize],
+        memory.ram[address as usize + 1],
+    );
+
+    execute(memory, instruction, pressed_keys, new_keys)
+}
+
 pub fn execute(
     memory: &mut Memory,
     instruction: Instruction,
     pressed_keys: &HashSet<Keycode>,
     new_keys: &HashSet<Keycode>,
-) {
+) -> Result<(), ExecError> {
+    let address = memory.program_counter;
+
+    // Everything that touches ram from I is checked up front, so the instructions below can index freely
+    let accessed = match instruction {
+        Instruction::Draw { nibble, .. } => nibble as usize,
+        Instruction::SetBCD { .. } => 3,
+        Instruction::LoadRegisters { vx } | Instruction::ReadRegisters { vx } => vx + 1,
+        _ => 0,
+    };
+    if memory.i as usize + accessed > memory.ram.len() {
+        return Err(ExecError::MemoryOutOfBounds {
+            address,
+            target: memory.i as usize,
+        });
+    }
+
     // Because we read in the instruction and arguments together,
     // we need to increment the program counter by 2 normally so we don't read in the middle of anything.
     match instruction {
-        Instruction::Invalid => todo!(),
+        Instruction::Invalid => {
+            return Err(ExecError::InvalidInstruction {
+                address,
+                opcode: opcode_at(memory, address),
+            })
+        }
         Instruction::Clear => {
             memory.display = [[0; 64]; 32];
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::Return => {
-            memory.program_counter = memory.stack[memory.stack_pointer];
+            if memory.stack_pointer == 0 {
+                return Err(ExecError::StackUnderflow { address });
+            }
             memory.stack_pointer -= 1;
+            memory.program_counter = memory.stack[memory.stack_pointer];
         }
         Instruction::JumpTo(addr) => memory.program_counter = addr.to_u16(),
         Instruction::Call(addr) => {
+            // The stack pointer is the next free slot, so all 16 can be used
+            if memory.stack_pointer >= memory.stack.len() {
+                return Err(ExecError::StackOverflow { address });
+            }
+            memory.stack[memory.stack_pointer] = memory
+                .program_counter
+                .checked_add(2)
+                .ok_or(ExecError::ProgramCounterOutOfBounds { address })?;
             memory.stack_pointer += 1;
-            memory.stack[memory.stack_pointer] = memory.program_counter + 2;
             memory.program_counter = addr.to_u16();
         }
         Instruction::SkipIfEqualByte { vx, byte } => {
             if memory.registers[vx] == byte {
-                memory.program_counter += 4
+                advance(memory, 4)?
             } else {
-                memory.program_counter += 2
+                advance(memory, 2)?
             }
         }
         Instruction::SkipIfNotEqualByte { vx, byte } => {
             if memory.registers[vx] != byte {
-                memory.program_counter += 4
+                advance(memory, 4)?
             } else {
-                memory.program_counter += 2
+                advance(memory, 2)?
             }
         }
         Instruction::SkipIfEqualReg { vx, vy } => {
             if memory.registers[vx] == memory.registers[vy] {
-                memory.program_counter += 4
+                advance(memory, 4)?
             } else {
-                memory.program_counter += 2
+                advance(memory, 2)?
             }
         }
         Instruction::LoadByte { vx, byte } => {
             memory.registers[vx] = byte;
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::AddByte { vx, byte } => {
-            let result = memory.registers[vx] as u16 + byte as u16;
-            if result > 255 {
-                memory.registers[0xF] = 1;
-            }
-
-            memory.registers[vx] = result as u8;
-            memory.program_counter += 2;
+            // Unlike 8xy4, there's no carry flag
+            memory.registers[vx] = memory.registers[vx].wrapping_add(byte);
+            advance(memory, 2)?;
         }
         Instruction::LoadReg { vx, vy } => {
-            memory.registers[vx] += memory.registers[vy];
-            memory.program_counter += 2;
+            memory.registers[vx] = memory.registers[vy];
+            advance(memory, 2)?;
         }
         Instruction::Or { vx, vy } => {
             memory.registers[vx] |= memory.registers[vy];
-            memory.program_counter += 2;
+            if memory.quirks.logic {
+                memory.registers[0xF] = 0;
+            }
+            advance(memory, 2)?;
         }
         Instruction::And { vx, vy } => {
             memory.registers[vx] &= memory.registers[vy];
-            memory.program_counter += 2;
+            if memory.quirks.logic {
+                memory.registers[0xF] = 0;
+            }
+            advance(memory, 2)?;
         }
         Instruction::Xor { vx, vy } => {
             memory.registers[vx] ^= memory.registers[vy];
-            memory.program_counter += 2;
+            if memory.quirks.logic {
+                memory.registers[0xF] = 0;
+            }
+            advance(memory, 2)?;
         }
         Instruction::AddReg { vx, vy } => {
-            let result = memory.registers[vx] as u16 + memory.registers[vy] as u16;
-            if result > 255 {
-                memory.registers[0xF] = 1;
-            }
-            memory.registers[vx] = result as u8;
-            memory.program_counter += 2;
+            // The flag is written after the result, so VF as a destination ends up holding it
+            let (result, carry) = memory.registers[vx].overflowing_add(memory.registers[vy]);
+            memory.registers[vx] = result;
+            memory.registers[0xF] = carry as u8;
+            advance(memory, 2)?;
         }
         Instruction::Subtract { vx, vy } => {
-            if memory.registers[vx] > memory.registers[vy] {
-                memory.registers[0xF] = 1;
-                memory.registers[vx] -= memory.registers[vy];
-            } else {
-                memory.registers[0xF] = 0;
-                let result = memory.registers[vx] as i16 - memory.registers[vy] as i16;
-                memory.registers[vx] = result as u8;
-            }
-            memory.program_counter += 2;
-        }
-        Instruction::ShiftRight { vx } => {
-            if memory.registers[vx] & 0b0000_0001 == 1 {
-                memory.registers[0xF] = 1
-            } else {
-                memory.registers[0xF] = 0
-            }
-            memory.registers[vx] /= 2;
-            memory.program_counter += 2;
+            let no_borrow = memory.registers[vx] >= memory.registers[vy];
+            memory.registers[vx] = memory.registers[vx].wrapping_sub(memory.registers[vy]);
+            memory.registers[0xF] = no_borrow as u8;
+            advance(memory, 2)?;
+        }
+        Instruction::ShiftRight { vx, vy } => {
+            // Without the shift quirk Vy is shifted into Vx, as on the COSMAC VIP
+            let value = memory.registers[if memory.quirks.shift { vx } else { vy }];
+            memory.registers[vx] = value >> 1;
+            memory.registers[0xF] = value & 0b0000_0001;
+            advance(memory, 2)?;
         }
         Instruction::SubtractReverse { vx, vy } => {
-            if memory.registers[vy] > memory.registers[vx] {
-                memory.registers[0xF] = 1
-            } else {
-                memory.registers[0xF] = 0
-            }
-            memory.registers[vx] = memory.registers[vy] - memory.registers[vx];
-            memory.program_counter += 2;
+            let no_borrow = memory.registers[vy] >= memory.registers[vx];
+            memory.registers[vx] = memory.registers[vy].wrapping_sub(memory.registers[vx]);
+            memory.registers[0xF] = no_borrow as u8;
+            advance(memory, 2)?;
         }
-        Instruction::ShiftLeft { vx } => {
-            if memory.registers[vx] & 0b1000_0000 == 0b1000_0000 {
-                memory.registers[0xF] = 1
-            } else {
-                memory.registers[0xF] = 0
-            }
-            memory.registers[vx] <<= 1;
-            memory.program_counter += 2;
+        Instruction::ShiftLeft { vx, vy } => {
+            let value = memory.registers[if memory.quirks.shift { vx } else { vy }];
+            memory.registers[vx] = value << 1;
+            memory.registers[0xF] = value >> 7;
+            advance(memory, 2)?;
         }
         Instruction::SkipIfNotEqualReg { vx, vy } => {
             if memory.registers[vx] != memory.registers[vy] {
-                memory.program_counter += 4
+                advance(memory, 4)?
             } else {
-                memory.program_counter += 2
+                advance(memory, 2)?
             }
         }
         Instruction::LoadAddress(addr) => {
             memory.i = addr.to_u16();
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::JumpOffset(addr) => {
-            memory.program_counter = addr.to_u16() + memory.registers[0] as u16;
+            // The jump quirk reads the register named by the top nibble of the address, as on SUPER-CHIP
+            let offset = if memory.quirks.jump {
+                addr.high as usize
+            } else {
+                0
+            };
+            memory.program_counter = addr.to_u16() + memory.registers[offset] as u16;
         }
         Instruction::Random { vx, byte } => {
-            let mut rng = rand::thread_rng();
-            memory.registers[vx] = rng.gen_range(0..255) & byte;
-            memory.program_counter += 2;
+            memory.registers[vx] = memory.rng.gen_range(0..255) & byte;
+            advance(memory, 2)?;
         }
         Instruction::Draw {
             vx,
@@ -296,14 +417,22 @@ pub fn execute(
         } => {
             let x = memory.registers[vx] as usize;
             let y = memory.registers[vy] as usize;
+            let width = display_constants::WIDTH as usize;
+            let height = display_constants::HEIGHT as usize;
             let mut collided = false;
 
             for row in 0..(length as usize) {
                 let bits = get_as_bits(memory.ram[memory.i as usize + row]);
 
                 for (bit, _) in bits.iter().enumerate() {
-                    let row_index = (y + row) % display_constants::HEIGHT as usize;
-                    let col_index = (x + bit) % display_constants::WIDTH as usize;
+                    // Without the wrap quirk the sprite's position wraps but anything past the edge is clipped
+                    if !memory.quirks.wrap
+                        && (x % width + bit >= width || y % height + row >= height)
+                    {
+                        continue;
+                    }
+                    let row_index = (y + row) % height;
+                    let col_index = (x + bit) % width;
 
                     let old_value = memory.display[row_index][col_index];
 
@@ -321,29 +450,30 @@ pub fn execute(
                 memory.registers[0xF] = 0;
             }
 
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::SkipIfKeyPressed { vx } => {
-            let key_code = memory.registers[vx] as usize;
+            // Only the low nibble picks a key, as on the COSMAC VIP
+            let key_code = (memory.registers[vx] & 0xF) as usize;
 
             if pressed_keys.contains(&KEY_MAP[key_code]) {
-                memory.program_counter += 4;
+                advance(memory, 4)?;
             } else {
-                memory.program_counter += 2;
+                advance(memory, 2)?;
             }
         }
         Instruction::SkipIfNotKeyPressed { vx } => {
-            let key_code = memory.registers[vx] as usize;
+            let key_code = (memory.registers[vx] & 0xF) as usize;
 
             if !pressed_keys.contains(&KEY_MAP[key_code]) {
-                memory.program_counter += 4;
+                advance(memory, 4)?;
             } else {
-                memory.program_counter += 2;
+                advance(memory, 2)?;
             }
         }
         Instruction::LoadDelay { vx } => {
             memory.registers[vx] = memory.delay;
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::LoadKeyPressed { vx } => {
             // Use new_keys to avoid instances of reading one 'keypress' several times
@@ -351,22 +481,22 @@ pub fn execute(
             for keycode in new_keys.iter() {
                 if KEY_MAP.contains(keycode) {
                     memory.registers[vx] = KEY_MAP.iter().position(|k| k == keycode).unwrap() as u8;
-                    memory.program_counter += 2;
+                    advance(memory, 2)?;
                     break;
                 }
             }
         }
         Instruction::SetDelay { vx } => {
             memory.delay = memory.registers[vx];
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::SetSound { vx } => {
             memory.sound = memory.registers[vx];
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::AddAddressOffset { vx } => {
-            memory.i += memory.registers[vx] as u16;
-            memory.program_counter += 2;
+            memory.i = memory.i.wrapping_add(memory.registers[vx] as u16);
+            advance(memory, 2)?;
         }
         Instruction::LoadSprite { vx } => {
             match memory.registers[vx] {
@@ -388,7 +518,7 @@ pub fn execute(
                 0xF => memory.i = 75,
                 _ => memory.i = 0,
             }
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::SetBCD { vx } => {
             let hundreds = memory.registers[vx] / 100;
@@ -399,21 +529,57 @@ pub fn execute(
             memory.ram[memory.i as usize + 1] = tens;
             memory.ram[memory.i as usize + 2] = ones;
 
-            memory.program_counter += 2;
+            advance(memory, 2)?;
         }
         Instruction::LoadRegisters { vx } => {
             for index in 0..(vx + 1) {
                 memory.ram[memory.i as usize + index] = memory.registers[index];
             }
-            memory.program_counter += 2;
+            advance_i(memory, vx);
+            advance(memory, 2)?;
         }
         Instruction::ReadRegisters { vx } => {
             for index in 0..(vx + 1) {
                 memory.registers[index] = memory.ram[memory.i as usize + index];
             }
-            memory.program_counter += 2;
+            advance_i(memory, vx);
+            advance(memory, 2)?;
         }
     }
+
+    Ok(())
+}
+
+/// Moves the program counter on by `bytes`, failing rather than wrapping round at the end
+/// of the address space
+fn advance(memory: &mut Memory, bytes: u16) -> Result<(), ExecError> {
+    memory.program_counter =
+        memory
+            .program_counter
+            .checked_add(bytes)
+            .ok_or(ExecError::ProgramCounterOutOfBounds {
+                address: memory.program_counter,
+            })?;
+    Ok(())
+}
+
+/// Moves I past the registers `Fx55` and `Fx65` just stored or read, as the memory quirks say
+fn advance_i(memory: &mut Memory, vx: usize) {
+    if memory.quirks.memory_leave_i_unchanged {
+        return;
+    }
+    let length = if memory.quirks.memory_increment_by_x {
+        vx
+    } else {
+        vx + 1
+    };
+    memory.i = memory.i.wrapping_add(length as u16);
+}
+
+/// Whether the instruction at the program counter is a draw that has to wait for the next frame
+pub fn waits_for_vblank(memory: &Memory) -> bool {
+    let pc = memory.program_counter as usize;
+    memory.quirks.vblank && pc < memory.ram.len() && memory.ram[pc] >> 4 == 0xD
 }
 
 /// Returns the top 4 bits of a u8
@@ -443,6 +609,7 @@ fn get_as_bits(byte: u8) -> [u8; 8] {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory;
 
     #[test]
     fn test_get_upper_bits() {
@@ -461,4 +628,98 @@ mod tests {
         let byte = 0b1010_1010;
         assert_eq!(get_as_bits(byte), [1, 0, 1, 0, 1, 0, 1, 0])
     }
+
+    #[test]
+    fn test_quirks() {
+        let no_keys = HashSet::new();
+        let mut memory = Memory::new();
+        memory.quirks = crate::quirks::find_preset("originalChip8").unwrap().quirks;
+
+        // LD V1, 0x81; SHR V0, V1; LD I, 0x300; LD [I], V1; OR V0, V1
+        memory
+            .load_rom(&[0x61, 0x81, 0x80, 0x16, 0xA3, 0x00, 0xF1, 0x55, 0x80, 0x11])
+            .unwrap();
+        for _ in 0..4 {
+            step(&mut memory, &no_keys, &no_keys).unwrap();
+        }
+        assert_eq!(memory.registers[0], 0x40);
+        assert_eq!(memory.i, 0x302);
+        assert_eq!(memory.registers[0xF], 1);
+
+        step(&mut memory, &no_keys, &no_keys).unwrap();
+        assert_eq!(memory.registers[0xF], 0);
+
+        // JP V0, 0x300 reads V3 with the jump quirk
+        memory.quirks.jump = true;
+        memory.registers[3] = 4;
+        memory.load_rom(&[0xB3, 0x00]).unwrap();
+        memory.program_counter = 0x200;
+        step(&mut memory, &no_keys, &no_keys).unwrap();
+        assert_eq!(memory.program_counter, 0x304);
+    }
+
+    #[test]
+    fn test_key_skip_masks_register() {
+        // LD V0, 0x10; SKP V0; SKNP V0
+        let mut memory = Memory::new();
+        memory
+            .load_rom(&[0x60, 0x10, 0xE0, 0x9E, 0xE0, 0xA1])
+            .unwrap();
+        let pressed = HashSet::from([KEY_MAP[0]]);
+
+        step(&mut memory, &pressed, &pressed).unwrap();
+        step(&mut memory, &pressed, &pressed).unwrap();
+        assert_eq!(memory.program_counter, 0x206);
+
+        memory.program_counter = 0x204;
+        step(&mut memory, &pressed, &pressed).unwrap();
+        assert_eq!(memory.program_counter, 0x206);
+    }
+
+    #[test]
+    fn test_step_errors() {
+        let no_keys = HashSet::new();
+        let mut memory = Memory::new();
+
+        // RET with nothing on the stack
+        memory.load_rom(&[0x00, 0xEE]).unwrap();
+        assert!(matches!(
+            step(&mut memory, &no_keys, &no_keys),
+            Err(ExecError::StackUnderflow { address: 0x200 })
+        ));
+
+        // CALL 0x200 over and over, which fills all 16 levels of the stack
+        memory.load_rom(&[0x22, 0x00]).unwrap();
+        memory.program_counter = 0x200;
+        for _ in 0..16 {
+            step(&mut memory, &no_keys, &no_keys).unwrap();
+        }
+        assert!(matches!(
+            step(&mut memory, &no_keys, &no_keys),
+            Err(ExecError::StackOverflow { address: 0x200 })
+        ));
+        memory.stack_pointer = 0;
+
+        // LD [I], VF with I at the end of ram
+        memory.load_rom(&[0xAF, 0xFF, 0xFF, 0x55]).unwrap();
+        step(&mut memory, &no_keys, &no_keys).unwrap();
+        assert!(matches!(
+            step(&mut memory, &no_keys, &no_keys),
+            Err(ExecError::MemoryOutOfBounds { address: 0x202, .. })
+        ));
+
+        // LD V0, 0 in the last word of XO-CHIP's 64 KiB, with nowhere to go next
+        memory.ram.resize(memory::XO_CHIP_RAM_SIZE, 0);
+        memory.load_rom(&[0x60, 0x00].repeat(0xFE00 / 2)).unwrap();
+        memory.program_counter = 0xFFFE;
+        assert!(matches!(
+            step(&mut memory, &no_keys, &no_keys),
+            Err(ExecError::ProgramCounterOutOfBounds { address: 0xFFFE })
+        ));
+
+        memory.program_counter = 0x200;
+        memory.load_rom(&[0xFF, 0xFF]).unwrap();
+        let error = step(&mut memory, &no_keys, &no_keys).unwrap_err();
+        assert_eq!(error.to_string(), "invalid instruction FFFF at 0x200");
+    }
 }
@@ -0,0 +1,106 @@
+use crate::interpreter::KEY_MAP;
+use sdl2::keyboard::Keycode;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+/// Host keys for the actions named in the CHIP-8 database
+const ACTIONS: [(&str, Keycode); 6] = [
+    ("up", Keycode::Up),
+    ("down", Keycode::Down),
+    ("left", Keycode::Left),
+    ("right", Keycode::Right),
+    ("a", Keycode::Space),
+    ("b", Keycode::Return),
+];
+
+/// Extra host keys that press keypad keys, on top of the usual layout in `KEY_MAP`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Keymap {
+    /// Host key and the `KEY_MAP` key it stands in for
+    bindings: Vec<(Keycode, Keycode)>,
+}
+
+impl Keymap {
+    /// Binds the arrow keys, space and return to the keypad keys a ROM uses for each action
+    pub fn from_actions(keys: &BTreeMap<String, u8>) -> Keymap {
+        let bindings = ACTIONS
+            .iter()
+            .filter_map(|&(action, host)| {
+                let key = *keys.get(action)?;
+                Some((host, *KEY_MAP.get(key as usize)?))
+            })
+            .collect();
+
+        Keymap { bindings }
+    }
+
+    /// Parses bindings written as `action:key` pairs, e.g. `up:5,down:8,a:6`
+    pub fn parse(text: &str) -> Result<Keymap, String> {
+        let mut keys = BTreeMap::new();
+
+        for binding in text.split(',') {
+            let (action, key) = binding
+                .split_once(':')
+                .ok_or_else(|| format!("expected action:key, got '{}'", binding))?;
+            if !ACTIONS.iter().any(|&(name, _)| name == action) {
+                let names: Vec<&str> = ACTIONS.iter().map(|&(name, _)| name).collect();
+                return Err(format!(
+                    "unknown action '{}', expected one of: {}",
+                    action,
+                    names.join(", ")
+                ));
+            }
+            let key = u8::from_str_radix(key, 16)
+                .ok()
+                .filter(|&key| key <= 0xF)
+                .ok_or_else(|| format!("'{}' is not a keypad key, expected 0-F", key))?;
+            keys.insert(action.to_string(), key);
+        }
+
+        Ok(Keymap::from_actions(&keys))
+    }
+
+    /// Whether a host key stands in for a keypad key
+    pub fn binds(&self, host: Keycode) -> bool {
+        self.bindings.iter().any(|&(bound, _)| bound == host)
+    }
+
+    /// `keys` with the keypad key for each bound host key added
+    pub fn apply(&self, keys: &HashSet<Keycode>) -> HashSet<Keycode> {
+        let mut mapped = keys.clone();
+        for (host, keypad) in self.bindings.iter() {
+            if keys.contains(host) {
+                mapped.insert(*keypad);
+            }
+        }
+        mapped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let keys = BTreeMap::from([(String::from("up"), 5), (String::from("a"), 0x10)]);
+        let keymap = Keymap::from_actions(&keys);
+
+        let pressed = keymap.apply(&HashSet::from([Keycode::Up, Keycode::Space]));
+        assert_eq!(
+            pressed,
+            HashSet::from([Keycode::Up, Keycode::Space, Keycode::W])
+        );
+
+        assert_eq!(
+            Keymap::parse("up:5,a:10").err().unwrap(),
+            "'10' is not a keypad key, expected 0-F"
+        );
+        assert_eq!(
+            Keymap::parse("up:5")
+                .unwrap()
+                .apply(&HashSet::from([Keycode::Up])),
+            HashSet::from([Keycode::Up, Keycode::W])
+        );
+    }
+}
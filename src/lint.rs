@@ -0,0 +1,401 @@
+use crate::cfg::{BasicBlock, Cfg};
+use crate::instructions::Instruction;
+use crate::interpreter;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::RangeInclusive;
+
+/// Calls that can be nested before the stack runs out
+const STACK_DEPTH: usize = 16;
+
+/// End of the built-in font, everything from here up to the ROM starts out zeroed
+const FONT_END: u16 = 0x50;
+
+/// Highest address in ram
+const RAM_END: u16 = 0xFFF;
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Warning {
+    pub address: u16,
+    pub message: String,
+}
+
+/// What is known about the machine at a point in a block, from the instructions before it
+#[derive(Clone)]
+struct State {
+    i: Option<u16>,
+    registers: [Option<u8>; 16],
+    /// The last `Fx55` or `Fx65` since I was loaded, whose effect on I depends on the quirk in use
+    store: Option<u16>,
+}
+
+impl State {
+    fn unknown() -> State {
+        State {
+            i: None,
+            registers: [None; 16],
+            store: None,
+        }
+    }
+}
+
+/// Looks for likely problems in a ROM loaded at `origin`, using the control-flow graph to
+/// only look at code that can run. Values are only tracked within a basic block, so
+/// anything depending on state from elsewhere goes unchecked.
+pub fn lint(rom: &[u8], origin: u16) -> Vec<Warning> {
+    let cfg = Cfg::build(rom, origin);
+    let rom_end = (origin as usize + rom.len()).min(u16::MAX as usize) as u16;
+    let mut warnings = BTreeSet::new();
+    let mut warn = |address: u16, message: String| {
+        warnings.insert(Warning { address, message });
+    };
+
+    for &address in cfg.invalid.iter() {
+        warn(
+            address,
+            String::from("code runs into bytes that aren't an instruction"),
+        );
+    }
+    for &address in cfg.leaves_rom.iter() {
+        warn(
+            address,
+            String::from("control can run off the end of the ROM"),
+        );
+    }
+    for &address in cfg.data_references.iter() {
+        let executed = address
+            .checked_sub(origin)
+            .and_then(|offset| cfg.code.get(offset as usize))
+            .is_some_and(|&code| code);
+        if executed {
+            warn(
+                address,
+                String::from("address is both executed as code and loaded as data"),
+            );
+        }
+    }
+
+    // Writes are gathered first, so reads anywhere can be checked against them
+    let mut written = BTreeSet::new();
+    for block in cfg.blocks.values() {
+        scan(block, rom, origin, |_, instruction, state| {
+            if let Some(range) = state.i.and_then(|i| accessed(instruction, i, true)) {
+                written.extend(range);
+            }
+        });
+    }
+
+    for block in cfg.blocks.values() {
+        scan(block, rom, origin, |address, instruction, state| {
+            check(address, instruction, state, rom_end, &written, &mut warn);
+        });
+    }
+
+    check_stack_depth(&cfg, rom, &mut warn);
+
+    warnings.into_iter().collect()
+}
+
+fn check(
+    address: u16,
+    instruction: &Instruction,
+    state: &State,
+    rom_end: u16,
+    written: &BTreeSet<u16>,
+    warn: &mut impl FnMut(u16, String),
+) {
+    match *instruction {
+        Instruction::ShiftRight { vx, vy } | Instruction::ShiftLeft { vx, vy } if vx != vy => warn(
+            address,
+            format!(
+                "shift uses V{:X} with the shift quirk off, as on the COSMAC VIP, but shifts V{:X} in place with it on",
+                vy, vx
+            ),
+        ),
+        Instruction::LoadSprite { vx } => {
+            if let Some(value) = state.registers[vx].filter(|&value| value > 0xF) {
+                warn(
+                    address,
+                    format!(
+                        "font character 0x{:02X} is above 0xF, reading past the font",
+                        value
+                    ),
+                );
+            }
+        }
+        _ => {}
+    }
+
+    let uses_i = matches!(
+        instruction,
+        Instruction::Draw { .. }
+            | Instruction::SetBCD { .. }
+            | Instruction::LoadRegisters { .. }
+            | Instruction::ReadRegisters { .. }
+            | Instruction::AddAddressOffset { .. }
+    );
+    if let Some(store) = state.store.filter(|_| uses_i) {
+        warn(
+            store,
+            String::from("I is used again after this without being reloaded, which behaves differently depending on the load/store quirk"),
+        );
+    }
+
+    let i = match state.i {
+        Some(i) => i,
+        None => return,
+    };
+
+    if let Some(range) = accessed(instruction, i, true) {
+        if range.clone().any(|target| target < 0x200) {
+            warn(
+                address,
+                format!(
+                    "writes to 0x{:03X}, inside the interpreter area below 0x200",
+                    i
+                ),
+            );
+        }
+    }
+
+    if let Some(range) = accessed(instruction, i, false) {
+        let uninitialised = range.clone().find(|&target| {
+            let zeroed = (FONT_END..0x200).contains(&target) || target >= rom_end;
+            zeroed && !written.contains(&target)
+        });
+        if let Some(target) = uninitialised {
+            warn(
+                address,
+                format!("reads 0x{:03X}, which nothing in the ROM writes", target),
+            );
+        }
+    }
+}
+
+/// The addresses `instruction` writes (or reads) through I, if any
+fn accessed(instruction: &Instruction, i: u16, writes: bool) -> Option<RangeInclusive<u16>> {
+    let length = match (instruction, writes) {
+        (Instruction::SetBCD { .. }, true) => 3,
+        (Instruction::LoadRegisters { vx }, true) => *vx as u16 + 1,
+        (Instruction::Draw { nibble, .. }, false) => *nibble as u16,
+        (Instruction::ReadRegisters { vx }, false) => *vx as u16 + 1,
+        _ => return None,
+    };
+
+    (length > 0).then(|| i..=i.saturating_add(length - 1).min(RAM_END))
+}
+
+/// Runs through a block calling `visit` with what's known before each instruction
+fn scan(
+    block: &BasicBlock,
+    rom: &[u8],
+    origin: u16,
+    mut visit: impl FnMut(u16, &Instruction, &State),
+) {
+    let mut state = State::unknown();
+
+    for address in (block.start..block.end).step_by(2) {
+        let instruction = decode(rom, origin, address);
+        visit(address, &instruction, &state);
+        update(&mut state, &instruction, address);
+    }
+
+    // A block that loops straight back to itself runs again with I left as the store leaves it
+    let loops = block
+        .successors
+        .iter()
+        .any(|&(target, _)| target == block.start);
+    if state.store.is_some() && loops {
+        for address in (block.start..block.end).step_by(2) {
+            let instruction = decode(rom, origin, address);
+            visit(address, &instruction, &state);
+            update(&mut state, &instruction, address);
+            if state.store.is_none() {
+                break;
+            }
+        }
+    }
+}
+
+fn update(state: &mut State, instruction: &Instruction, address: u16) {
+    match *instruction {
+        Instruction::LoadAddress(ref addr) => {
+            state.i = Some(addr.to_u16());
+            state.store = None;
+        }
+        Instruction::AddAddressOffset { vx } => {
+            state.i = state
+                .i
+                .zip(state.registers[vx])
+                .map(|(i, value)| i + value as u16);
+            state.store = None;
+        }
+        Instruction::LoadRegisters { .. } => state.store = Some(address),
+        Instruction::ReadRegisters { vx } => {
+            state.registers[..=vx].fill(None);
+            state.store = Some(address);
+        }
+        Instruction::LoadByte { vx, byte } => state.registers[vx] = Some(byte),
+        Instruction::LoadReg { vx, vy } => state.registers[vx] = state.registers[vy],
+        Instruction::AddByte { vx, byte } => {
+            state.registers[vx] = state.registers[vx].map(|value| value.wrapping_add(byte))
+        }
+        Instruction::Or { vx, .. }
+        | Instruction::And { vx, .. }
+        | Instruction::Xor { vx, .. }
+        | Instruction::AddReg { vx, .. }
+        | Instruction::Subtract { vx, .. }
+        | Instruction::SubtractReverse { vx, .. }
+        | Instruction::ShiftRight { vx, .. }
+        | Instruction::ShiftLeft { vx, .. } => {
+            state.registers[vx] = None;
+            state.registers[0xF] = None;
+        }
+        Instruction::Random { vx, .. }
+        | Instruction::LoadDelay { vx }
+        | Instruction::LoadKeyPressed { vx } => state.registers[vx] = None,
+        Instruction::Draw { .. } => state.registers[0xF] = None,
+        // The subroutine could change anything
+        Instruction::Call(_) => *state = State::unknown(),
+        _ => {}
+    }
+}
+
+/// Warns about call paths nested deeper than the stack, or that recurse
+fn check_stack_depth(cfg: &Cfg, rom: &[u8], warn: &mut impl FnMut(u16, String)) {
+    // Call sites and their targets for each subroutine
+    let calls: BTreeMap<u16, Vec<(u16, u16)>> = cfg
+        .subroutines
+        .iter()
+        .map(|&entry| {
+            let mut sites = Vec::new();
+            for block in cfg.subroutine_blocks(entry) {
+                for address in (block.start..block.end).step_by(2) {
+                    if let Instruction::Call(addr) = decode(rom, cfg.origin, address) {
+                        sites.push((address, addr.to_u16()));
+                    }
+                }
+            }
+            (entry, sites)
+        })
+        .collect();
+
+    let mut depths = BTreeMap::new();
+    let mut path = vec![cfg.origin];
+    walk_calls(&calls, &mut depths, &mut path, warn);
+}
+
+/// Walks the call graph depth first, recording the deepest each subroutine is reached
+/// and warning at calls that overflow the stack or recurse
+fn walk_calls(
+    calls: &BTreeMap<u16, Vec<(u16, u16)>>,
+    depths: &mut BTreeMap<u16, usize>,
+    path: &mut Vec<u16>,
+    warn: &mut impl FnMut(u16, String),
+) {
+    let current = *path.last().unwrap();
+    let depth = path.len() - 1;
+
+    // Already explored from here with at least as deep a stack
+    if depths
+        .get(&current)
+        .is_some_and(|&deepest| deepest >= depth)
+    {
+        return;
+    }
+    depths.insert(current, depth);
+
+    for &(site, callee) in calls.get(&current).into_iter().flatten() {
+        if path.contains(&callee) {
+            warn(
+                site,
+                format!(
+                    "recursive call to 0x{:03X}, the stack overflows if it doesn't stop",
+                    callee
+                ),
+            );
+        } else if depth + 1 > STACK_DEPTH {
+            warn(
+                site,
+                format!(
+                    "call path is more than {} calls deep, overflowing the stack",
+                    STACK_DEPTH
+                ),
+            );
+        } else {
+            path.push(callee);
+            walk_calls(calls, depths, path, warn);
+            path.pop();
+        }
+    }
+}
+
+fn decode(rom: &[u8], origin: u16, address: u16) -> Instruction {
+    let offset = (address - origin) as usize;
+    interpreter::parse(rom[offset], rom[offset + 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(rom: &[u8]) -> Vec<(u16, String)> {
+        lint(rom, 0x200)
+            .into_iter()
+            .map(|warning| (warning.address, warning.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_rom() {
+        // LD V0, 5; LD F, V0; DRW V0, V0, 5; JP 206
+        assert!(messages(&[0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06]).is_empty());
+    }
+
+    #[test]
+    fn test_font_and_shift() {
+        // LD V0, 0x10; LD F, V0; SHR V0, V1; JP 206
+        let warnings = messages(&[0x60, 0x10, 0xF0, 0x29, 0x80, 0x16, 0x12, 0x06]);
+
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].0, 0x202);
+        assert!(warnings[0].1.contains("0x10 is above 0xF"));
+        assert_eq!(warnings[1].0, 0x204);
+        assert!(warnings[1].1.contains("shift"));
+    }
+
+    #[test]
+    fn test_memory_checks() {
+        // LD I, 0x100; LD [I], V0; LD I, 0x300; DRW V0, V0, 1; then runs off the end
+        let warnings = messages(&[0xA1, 0x00, 0xF0, 0x55, 0xA3, 0x00, 0xD0, 0x01]);
+
+        assert!(warnings.contains(&(
+            0x202,
+            String::from("writes to 0x100, inside the interpreter area below 0x200")
+        )));
+        assert!(warnings.contains(&(
+            0x206,
+            String::from("reads 0x300, which nothing in the ROM writes")
+        )));
+        assert!(warnings
+            .iter()
+            .any(|(address, message)| *address == 0x206 && message.contains("off the end")));
+    }
+
+    #[test]
+    fn test_store_loop() {
+        // LD I, 0x300; loop: LD [I], V0; JP loop
+        let warnings = messages(&[0xA3, 0x00, 0xF0, 0x55, 0x12, 0x02]);
+        assert!(warnings
+            .iter()
+            .any(|(address, message)| *address == 0x202 && message.contains("quirk")));
+    }
+
+    #[test]
+    fn test_recursion() {
+        // CALL 202; CALL 202
+        let warnings = messages(&[0x22, 0x02, 0x22, 0x02]);
+        assert!(warnings
+            .iter()
+            .any(|(address, message)| *address == 0x202 && message.contains("recursive")));
+    }
+}
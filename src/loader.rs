@@ -0,0 +1,349 @@
+use crate::cartridge;
+use crate::database::Profile;
+use crate::octo;
+use crate::quirks;
+use crate::quirks::Preset;
+use crate::symbols::Symbols;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+use std::io::IsTerminal;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use zip::ZipArchive;
+
+/// Extensions ROMs go by, with the platform each one is written for when it says
+pub const EXTENSIONS: [(&str, Option<&str>); 6] = [
+    ("ch8", None),
+    ("c8", None),
+    ("sc8", Some("superchip")),
+    ("xo8", Some("xochip")),
+    // Octo source, which targets XO-CHIP unless told otherwise
+    ("8o", Some("xochip")),
+    // Octo cartridges, which carry their own settings
+    ("gif", None),
+];
+
+/// A ROM read from disk or from inside an archive
+#[derive(Clone)]
+pub struct RomFile {
+    /// Where it came from, with entries in an archive as `<archive>/<entry>`. Files such as
+    /// symbols and screenshots are named after this.
+    pub path: String,
+    pub contents: Vec<u8>,
+    /// Symbols from compiling source, which take the place of a symbol file
+    pub symbols: Option<Symbols>,
+    /// Settings that came with the ROM, as in an Octo cartridge, which take the place of the
+    /// database's
+    pub profile: Option<Profile>,
+}
+
+/// Whether a file name has one of the ROM `EXTENSIONS`
+pub fn is_rom(name: &str) -> bool {
+    extension(name).is_some()
+}
+
+/// Whether a file name has the `.zip` extension
+pub fn is_zip(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+fn extension(name: &str) -> Option<&'static (&'static str, Option<&'static str>)> {
+    let extension = Path::new(name).extension()?.to_str()?;
+    EXTENSIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+}
+
+/// The preset a file's extension implies, such as SUPER-CHIP for `.sc8`
+pub fn extension_preset(name: &str) -> Option<&'static Preset> {
+    quirks::find_preset(extension(name)?.1?)
+}
+
+/// Reads a ROM from a file, a zip archive or a directory. In an archive or a directory,
+/// `choice` names the ROM to open, otherwise the only one is opened or the user picks one.
+pub fn load(path: &str, choice: Option<&str>) -> Result<RomFile, String> {
+    let metadata =
+        fs::metadata(path).map_err(|error| format!("failed to read {}: {}", path, error))?;
+
+    if metadata.is_dir() {
+        let names = directory_names(path)?;
+        let chosen = Path::new(path).join(pick(path, &names, choice)?);
+        return load(&chosen.to_string_lossy(), None);
+    }
+
+    if is_zip(path) {
+        return load_zip(path, choice);
+    }
+
+    let contents = fs::read(path).map_err(|error| format!("failed to read {}: {}", path, error))?;
+    rom_file(path.to_string(), contents)
+}
+
+/// Compiles Octo source and cartridges, or takes anything else as it is
+fn rom_file(path: String, contents: Vec<u8>) -> Result<RomFile, String> {
+    if cartridge::is_gif(&contents) {
+        let cartridge = cartridge::decode(&contents)
+            .map_err(|error| format!("can't load {}: {}", path, error))?;
+        let title = Path::new(&path)
+            .file_stem()
+            .map_or_else(|| path.clone(), |stem| stem.to_string_lossy().into_owned());
+        let profile = cartridge.options.profile(&title)?;
+        let program = octo::compile(&cartridge.program, &path)?;
+        return Ok(RomFile {
+            path,
+            contents: program.rom,
+            symbols: Some(program.symbols),
+            profile: Some(profile),
+        });
+    }
+
+    let octo = extension(&path).is_some_and(|(extension, _)| *extension == "8o");
+    if !octo {
+        return Ok(RomFile {
+            path,
+            contents,
+            symbols: None,
+            profile: None,
+        });
+    }
+
+    let source = String::from_utf8(contents)
+        .map_err(|_| format!("{} isn't text, so it can't be Octo source", path))?;
+    let program = octo::compile(&source, &path)?;
+    Ok(RomFile {
+        path,
+        contents: program.rom,
+        symbols: Some(program.symbols),
+        profile: None,
+    })
+}
+
+/// Reads every ROM in a directory, including those in zip archives there, or every ROM in a
+/// zip archive, for choosing between them. Files that can't be read are left out with a warning.
+pub fn list(path: &str) -> Result<Vec<RomFile>, String> {
+    if is_zip(path) {
+        return read_zip(path);
+    }
+
+    let mut roms = Vec::new();
+    for name in directory_names(path)? {
+        let file = Path::new(path).join(&name).to_string_lossy().into_owned();
+        let found = match is_zip(&name) {
+            true => read_zip(&file),
+            false => load(&file, None).map(|rom| vec![rom]),
+        };
+        match found {
+            Ok(found) => roms.extend(found),
+            Err(error) => eprintln!("warning: {}", error),
+        }
+    }
+    Ok(roms)
+}
+
+/// The ROMs and zip archives in a directory, sorted by name
+fn directory_names(path: &str) -> Result<Vec<String>, String> {
+    let mut names = fs::read_dir(path)
+        .map_err(|error| format!("failed to read {}: {}", path, error))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| is_rom(name) || is_zip(name))
+        .collect::<Vec<String>>();
+    names.sort_by_key(|name| name.to_lowercase());
+    Ok(names)
+}
+
+fn load_zip(path: &str, choice: Option<&str>) -> Result<RomFile, String> {
+    let mut archive = open_zip(path)?;
+    let names = zip_names(&archive);
+    let name = pick(path, &names, choice)?.to_string();
+    read_entry(&mut archive, path, &name)
+}
+
+/// Every ROM in an archive, leaving out any that can't be read, such as GIFs that aren't
+/// cartridges, with a warning
+fn read_zip(path: &str) -> Result<Vec<RomFile>, String> {
+    let mut archive = open_zip(path)?;
+    let mut roms = Vec::new();
+    for name in zip_names(&archive) {
+        match read_entry(&mut archive, path, &name) {
+            Ok(rom) => roms.push(rom),
+            Err(error) => eprintln!("warning: {}", error),
+        }
+    }
+    Ok(roms)
+}
+
+fn open_zip(path: &str) -> Result<ZipArchive<File>, String> {
+    let file = File::open(path).map_err(|error| format!("failed to read {}: {}", path, error))?;
+    ZipArchive::new(file).map_err(|error| format!("failed to read {}: {}", path, error))
+}
+
+/// The entries in an archive with ROM extensions, in the order they're stored
+fn zip_names(archive: &ZipArchive<File>) -> Vec<String> {
+    archive
+        .file_names()
+        .filter(|name| is_rom(name))
+        .map(String::from)
+        .collect()
+}
+
+fn read_entry(archive: &mut ZipArchive<File>, path: &str, name: &str) -> Result<RomFile, String> {
+    let mut contents = Vec::new();
+    archive
+        .by_name(name)
+        .map_err(|error| format!("failed to read {}: {}", path, error))?
+        .read_to_end(&mut contents)
+        .map_err(|error| format!("failed to read {} in {}: {}", name, path, error))?;
+
+    rom_file(
+        Path::new(path).join(name).to_string_lossy().into_owned(),
+        contents,
+    )
+}
+
+/// Picks one of `names` in `container`: the one `choice` names, the only one there is, or
+/// one the user types in. A choice matches the whole name, the name without its
+/// extension or directory, or the start of a name.
+fn pick<'a>(container: &str, names: &'a [String], choice: Option<&str>) -> Result<&'a str, String> {
+    if names.is_empty() {
+        return Err(format!("there are no ROMs in {}", container));
+    }
+
+    if let Some(choice) = choice {
+        return find(names, choice).ok_or_else(|| {
+            format!(
+                "no single ROM in {} matches '{}', it has: {}",
+                container,
+                choice,
+                names.join(", ")
+            )
+        });
+    }
+    if let [name] = names {
+        return Ok(name);
+    }
+
+    if !io::stdin().is_terminal() {
+        return Err(format!(
+            "{} has {} ROMs, choose one with --rom <name>: {}",
+            container,
+            names.len(),
+            names.join(", ")
+        ));
+    }
+
+    for (index, name) in names.iter().enumerate() {
+        println!("{:4}  {}", index + 1, name);
+    }
+    let stdin = io::stdin();
+    loop {
+        print!("open which ROM? ");
+        io::stdout().flush().map_err(|error| error.to_string())?;
+
+        let mut line = String::new();
+        if stdin
+            .lock()
+            .read_line(&mut line)
+            .map_err(|error| error.to_string())?
+            == 0
+        {
+            return Err(String::from("no ROM chosen"));
+        }
+        let line = line.trim();
+        let found = match line.parse::<usize>() {
+            Ok(number) => names.get(number.wrapping_sub(1)).map(String::as_str),
+            Err(_) => find(names, line),
+        };
+        match found {
+            Some(name) => return Ok(name),
+            None => println!("enter a number from 1 to {} or a name", names.len()),
+        }
+    }
+}
+
+fn find<'a>(names: &'a [String], choice: &str) -> Option<&'a str> {
+    let choice = choice.to_lowercase();
+    let stem = |name: &str| {
+        Path::new(name)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_lowercase())
+    };
+    let file_name = |name: &str| {
+        Path::new(name)
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+    };
+
+    let exact: Vec<&String> = names
+        .iter()
+        .filter(|name| {
+            name.to_lowercase() == choice
+                || file_name(name).as_deref() == Some(&choice)
+                || stem(name).as_deref() == Some(&choice)
+        })
+        .collect();
+    let matches = match exact.is_empty() {
+        true => names
+            .iter()
+            .filter(|name| file_name(name).is_some_and(|name| name.starts_with(&choice)))
+            .collect(),
+        false => exact,
+    };
+
+    match matches[..] {
+        [name] => Some(name),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let names = [
+            String::from("games/Pong.ch8"),
+            String::from("games/Pong2.ch8"),
+            String::from("demos/Maze.sc8"),
+        ];
+
+        assert_eq!(find(&names, "pong"), Some("games/Pong.ch8"));
+        assert_eq!(find(&names, "games/pong2.ch8"), Some("games/Pong2.ch8"));
+        assert_eq!(find(&names, "ma"), Some("demos/Maze.sc8"));
+        // Both Pongs start with "po"
+        assert_eq!(find(&names, "po"), None);
+
+        assert_eq!(extension_preset("Maze.SC8").unwrap().id, "superchip");
+        assert!(extension_preset("pong.ch8").is_none());
+        assert!(!is_rom("notes.txt"));
+    }
+
+    #[test]
+    fn test_list_cartridges() {
+        let directory = std::env::temp_dir().join(format!("chip8-list-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("pong.ch8"), [0x00, 0xE0]).unwrap();
+        fs::write(
+            directory.join("Game.GIF"),
+            cartridge::encode(r#"{"program": ": main clear"}"#),
+        )
+        .unwrap();
+        fs::write(directory.join("notes.txt"), "not a ROM").unwrap();
+
+        let roms = list(&directory.to_string_lossy());
+        fs::remove_dir_all(&directory).unwrap();
+        let roms = roms.unwrap();
+
+        assert_eq!(roms.len(), 2);
+        assert!(roms[0].path.ends_with("Game.GIF"));
+        // Compiled, with the jump to main in front of it
+        assert_eq!(roms[0].contents, [0x12, 0x02, 0x00, 0xE0]);
+        assert!(roms[0].profile.is_some());
+        assert!(roms[1].path.ends_with("pong.ch8"));
+    }
+}
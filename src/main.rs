@@ -1,159 +1,1927 @@
-use instructions::Instruction;
+use audio::Beeper;
+use browser::Browser;
+use cfg::Cfg;
+use config::Config;
+use config::Settings;
+use coverage::Coverage;
+use database::Database;
+use database::Profile;
+use debugger::Debugger;
+use gdb::GdbStub;
+use interpreter::ExecError;
+use keymap::Keymap;
+use loader::RomFile;
 use memory::Memory;
+use movie::Movie;
+use palette::Palette;
+use phosphor::Phosphor;
+use phosphor::PhosphorMode;
+use profiler::Profiler;
+use quirks::Platform;
+use quirks::Preset;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use recompiler::Recompiler;
+use recording::GifRecorder;
+use renderer::Renderer;
 use sdl2::event::Event;
+use sdl2::event::WindowEvent;
 use sdl2::keyboard::Keycode;
+use sdl2::keyboard::Mod;
 use sdl2::keyboard::Scancode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
+use sdl2::pixels::PixelFormatEnum;
 use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
 use std::time::Duration;
+use symbols::Symbols;
+use trace::Tracer;
+use watch::Watcher;
 
+mod assembler;
+mod audio;
+mod browser;
+mod cartridge;
+mod cfg;
+mod config;
+mod coverage;
+mod database;
+mod debugger;
+mod detect;
 mod display_constants;
+mod font;
+mod gdb;
 mod instructions;
 mod interpreter;
+mod keymap;
+mod lint;
+mod loader;
 mod memory;
+mod movie;
+mod octo;
+mod palette;
+mod phosphor;
+mod profiler;
+mod quirks;
+mod recompiler;
+mod recording;
+mod renderer;
+mod screenshot;
+mod symbols;
+mod trace;
+mod tui;
+mod watch;
+
+/// An option as `--help` lists it: name, how its value is written (empty for switches) and
+/// what it does. Values written after a space can be given as `--name value` or
+/// `--name=value`, optional ones in brackets only as `--name=value`.
+type OptionHelp = (&'static str, &'static str, &'static str);
+
+/// Options that set up the machine a ROM runs on, taken by every command that runs one
+const MACHINE_OPTIONS: [OptionHelp; 8] = [
+    (
+        "--quirks",
+        " <preset>",
+        "behave like one of the presets below",
+    ),
+    ("--speed", " <n>", "instructions per frame"),
+    (
+        "--start",
+        " <address>",
+        "load and start the ROM at this hex address instead of 200",
+    ),
+    (
+        "--seed",
+        " <n>",
+        "seed the random number generator so runs can be repeated",
+    ),
+    (
+        "--database",
+        " <dir>",
+        "set up ROMs listed in this copy of the CHIP-8 database; none is built in",
+    ),
+    (
+        "--theme",
+        " <name>",
+        "colour theme, one of the themes below",
+    ),
+    (
+        "--palette",
+        " <bg,fg[,plane,overlap]>",
+        "custom hex colours, overriding the theme",
+    ),
+    (
+        "--config",
+        " <path>",
+        "read settings from this file, run reads the user's config by default",
+    ),
+];
+
+/// Options that watch the ROM run and report on it afterwards
+const INSTRUMENT_OPTIONS: [OptionHelp; 7] = [
+    ("--trace", " <log>", "log every instruction executed"),
+    (
+        "--trace-range",
+        " <start-end>",
+        "only log these addresses, can be repeated",
+    ),
+    (
+        "--trace-ring",
+        " <n>",
+        "only keep the last n lines, written out on an error",
+    ),
+    (
+        "--profile",
+        "[=<json>]",
+        "print hot spots and subroutine times, or save them as JSON",
+    ),
+    (
+        "--coverage",
+        " <listing>",
+        "save a listing annotated with execution counts",
+    ),
+    ("--lcov", " <path>", "save coverage as an lcov tracefile"),
+    (
+        "--symbols",
+        " <path>",
+        "labels and source lines, <file>.sym when it exists",
+    ),
+];
+
+const RUN_OPTIONS: [OptionHelp; 14] = [
+    (
+        "--frontend",
+        " <sdl|tui>",
+        "draw in a window or in the terminal",
+    ),
+    ("--scale", " <n>", "window scale"),
+    ("--volume", " <0-1>", "beep volume, 0 for silence"),
+    (
+        "--keymap",
+        " <action:key,...>",
+        "bind up, down, left, right (the arrow keys), a (space) and b (return) to keypad keys",
+    ),
+    (
+        "--recompile",
+        "",
+        "run cached blocks of compiled instructions",
+    ),
+    (
+        "--cross-check",
+        "",
+        "recompile and check every block against the interpreter",
+    ),
+    (
+        "--phosphor",
+        " <hold|decay[:rate]>",
+        "reduce flicker by keeping erased pixels lit for a frame or fading them out",
+    ),
+    (
+        "--record-movie",
+        " <path>",
+        "save the keys pressed each frame, for replaying with gif",
+    ),
+    (
+        "--watch",
+        "[=<source>]",
+        "reload the ROM when it changes, or reassemble it when its source does",
+    ),
+    (
+        "--keep-state",
+        "",
+        "carry on where a reloaded ROM left off if none of its labels moved",
+    ),
+    ("--debug", "", "start in the debugger"),
+    (
+        "--gdb-port",
+        " <port>",
+        "serve the GDB remote protocol instead of running",
+    ),
+    (
+        "--headless",
+        "",
+        "run without a display or keys for --frames frames",
+    ),
+    (
+        "--frames",
+        " <n>",
+        "how long to run headless, 300 frames by default",
+    ),
+];
+
+const SCREENSHOT_OPTIONS: [OptionHelp; 3] = [
+    ("--frames", " <n>", "frames to run first, 300 by default"),
+    (
+        "--out",
+        " <png>",
+        "where to save it, screenshot.png by default",
+    ),
+    ("--scale", " <n>", "pixels per CHIP-8 pixel, 1 by default"),
+];
+
+const GIF_OPTIONS: [OptionHelp; 5] = [
+    (
+        "--movie",
+        " <path>",
+        "replay the keys from a movie saved with --record-movie",
+    ),
+    (
+        "--frames",
+        " <n>",
+        "frames to record, as long as the movie or 300 by default",
+    ),
+    (
+        "--skip",
+        " <n>",
+        "frames to skip between each frame saved, 1 by default",
+    ),
+    (
+        "--out",
+        " <gif>",
+        "where to save it, recording.gif by default",
+    ),
+    ("--scale", " <n>", "pixels per CHIP-8 pixel, 1 by default"),
+];
+
+const TEST_OPTIONS: [OptionHelp; 2] = [
+    ("--frames", " <n>", "frames to run, 300 by default"),
+    (
+        "--expect",
+        " <txt>",
+        "the expected display, <file>.expected.txt by default",
+    ),
+];
+
+/// Options for opening a ROM in a zip archive or a directory of them
+const ARCHIVE_OPTIONS: [OptionHelp; 1] = [(
+    "--rom",
+    " <name>",
+    "which ROM in a zip archive or directory to open, instead of choosing from a list",
+)];
+
+const LISTING_OPTIONS: [OptionHelp; 2] = [
+    (
+        "--start",
+        " <address>",
+        "hex address the ROM is loaded at, 200 by default",
+    ),
+    (
+        "--symbols",
+        " <path>",
+        "labels and source lines, <file>.sym when it exists",
+    ),
+];
+
+const CFG_OPTIONS: [OptionHelp; 1] = [("--dot", " <path>", "save the graph as Graphviz DOT")];
+
+const ASM_OPTIONS: [OptionHelp; 2] = [
+    (
+        "--out",
+        " <rom>",
+        "where to save the ROM, <source>.ch8 by default",
+    ),
+    (
+        "--start",
+        " <address>",
+        "hex address the ROM is loaded at, 200 by default",
+    ),
+];
+
+const INFO_OPTIONS: [OptionHelp; 2] = [
+    (
+        "--start",
+        " <address>",
+        "hex address the ROM is loaded at, 200 by default",
+    ),
+    (
+        "--database",
+        " <dir>",
+        "set up ROMs listed in this copy of the CHIP-8 database; none is built in",
+    ),
+];
+
+/// A subcommand: its name, arguments, what it does and the options it takes
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    description: &'static str,
+    options: &'static [&'static [OptionHelp]],
+}
+
+const COMMANDS: [Command; 9] = [
+    Command {
+        name: "run",
+        usage: "<file>",
+        description: "run a ROM in a window, the terminal or headless",
+        options: &[
+            &RUN_OPTIONS,
+            &MACHINE_OPTIONS,
+            &INSTRUMENT_OPTIONS,
+            &ARCHIVE_OPTIONS,
+        ],
+    },
+    Command {
+        name: "screenshot",
+        usage: "<file>",
+        description: "run a ROM headless and save the display as a PNG",
+        options: &[
+            &SCREENSHOT_OPTIONS,
+            &MACHINE_OPTIONS,
+            &INSTRUMENT_OPTIONS,
+            &ARCHIVE_OPTIONS,
+        ],
+    },
+    Command {
+        name: "gif",
+        usage: "<file>",
+        description: "run a ROM headless, optionally replaying a movie, and record it as a GIF",
+        options: &[
+            &GIF_OPTIONS,
+            &MACHINE_OPTIONS,
+            &INSTRUMENT_OPTIONS,
+            &ARCHIVE_OPTIONS,
+        ],
+    },
+    Command {
+        name: "test",
+        usage: "<file>",
+        description:
+            "run a ROM headless and check the display against the one saved by the first run",
+        options: &[
+            &TEST_OPTIONS,
+            &MACHINE_OPTIONS,
+            &INSTRUMENT_OPTIONS,
+            &ARCHIVE_OPTIONS,
+        ],
+    },
+    Command {
+        name: "disasm",
+        usage: "<file>",
+        description: "print a disassembly listing",
+        options: &[&LISTING_OPTIONS, &ARCHIVE_OPTIONS],
+    },
+    Command {
+        name: "asm",
+        usage: "<source>",
+        description:
+            "assemble the mnemonics disasm prints, or Octo .8o source, into a ROM and symbols",
+        options: &[&ASM_OPTIONS],
+    },
+    Command {
+        name: "cfg",
+        usage: "<file>",
+        description: "summarise the ROM's control-flow graph",
+        options: &[&CFG_OPTIONS, &LISTING_OPTIONS, &ARCHIVE_OPTIONS],
+    },
+    Command {
+        name: "lint",
+        usage: "<file>",
+        description: "print likely problems found by static analysis",
+        options: &[&LISTING_OPTIONS, &ARCHIVE_OPTIONS],
+    },
+    Command {
+        name: "info",
+        usage: "<file>",
+        description: "show the ROM's hash, title, platform and a summary of its code",
+        options: &[&INFO_OPTIONS, &ARCHIVE_OPTIONS],
+    },
+];
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() == 1 {
-        println!("usage: chip8 <file>");
-        return;
-    }
-
-    let filename = &args[1];
-
-    let contents = fs::read(filename).expect("Error reading the given filename");
-
-    let mut memory = Memory {
-        delay: 0,
-        sound: 0,
-        stack_pointer: 0,
-        program_counter: memory::INTERPRETER_SIZE as u16,
-        i: 0,
-        registers: [0; 16],
-        stack: [0; 16],
-        display: [[0; 64]; 32],
-        ram: [0; 0xFFF],
-    };
-
-    memory.ram[0..5].clone_from_slice(&display_constants::ZERO);
-    memory.ram[5..10].clone_from_slice(&display_constants::ONE);
-    memory.ram[10..15].clone_from_slice(&display_constants::TWO);
-    memory.ram[15..20].clone_from_slice(&display_constants::THREE);
-    memory.ram[20..25].clone_from_slice(&display_constants::FOUR);
-    memory.ram[25..30].clone_from_slice(&display_constants::FIVE);
-    memory.ram[30..35].clone_from_slice(&display_constants::SIX);
-    memory.ram[35..40].clone_from_slice(&display_constants::SEVEN);
-    memory.ram[40..45].clone_from_slice(&display_constants::EIGHT);
-    memory.ram[45..50].clone_from_slice(&display_constants::NINE);
-    memory.ram[50..55].clone_from_slice(&display_constants::A);
-    memory.ram[55..60].clone_from_slice(&display_constants::B);
-    memory.ram[60..65].clone_from_slice(&display_constants::C);
-    memory.ram[65..70].clone_from_slice(&display_constants::D);
-    memory.ram[70..75].clone_from_slice(&display_constants::E);
-    memory.ram[75..80].clone_from_slice(&display_constants::F);
-
-    memory.ram[memory::INTERPRETER_SIZE..(contents.len() + memory::INTERPRETER_SIZE)]
-        .clone_from_slice(&contents[..]);
-
-    let sdl_context = sdl2::init().unwrap();
-    let video_subsystem = sdl_context.video().unwrap();
+    let command = args.get(1).map(String::as_str);
+    let help = args[1..].iter().any(|arg| arg == "--help" || arg == "-h");
+    let result = match command {
+        None | Some("help" | "--help" | "-h") => {
+            print_help(args.get(2).and_then(|name| find_command(name)));
+            Ok(())
+        }
+        Some(name) if help => {
+            print_help(find_command(name).or(find_command("run")));
+            Ok(())
+        }
+        Some("run") => run_command(&args[2..]),
+        Some("screenshot") => screenshot_command(&args[2..]),
+        Some("gif") => gif_command(&args[2..]),
+        Some("test") => test_command(&args[2..]),
+        Some("disasm") => disasm_command(&args[2..]),
+        Some("asm") => asm_command(&args[2..]),
+        Some("cfg") => cfg_command(&args[2..]),
+        Some("lint") => lint_command(&args[2..]),
+        Some("info") => info_command(&args[2..]),
+        // `chip8 <file>` is short for `chip8 run <file>`
+        Some(_) => run_command(&args[1..]),
+    };
+
+    if let Err(error) = result {
+        eprintln!("error: {}", error);
+        std::process::exit(1);
+    }
+}
+
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+/// Prints the usage of one command, or a list of them all
+fn print_help(command: Option<&Command>) {
+    let command = match command {
+        Some(command) => command,
+        None => {
+            println!("usage: chip8 <command> [options]\n\ncommands:");
+            for command in COMMANDS.iter() {
+                println!("  {:<12}{}", command.name, command.description);
+            }
+            println!("\n`chip8 <file>` is short for `chip8 run <file>`");
+            println!("<file> can also be Octo .8o source or a cartridge .gif, a zip archive or");
+            println!("a directory of ROMs");
+            println!("`chip8 <command> --help` lists the options a command takes");
+            return;
+        }
+    };
+
+    println!(
+        "usage: chip8 {} {} [options]\n",
+        command.name, command.usage
+    );
+    println!("{}\n\noptions:", command.description);
+    let mut listed = HashSet::new();
+    for &(name, value, description) in command.options.iter().copied().flatten() {
+        if listed.insert(name) {
+            println!("  {:<32}{}", format!("{}{}", name, value), description);
+        }
+    }
+
+    if command.options.contains(&&MACHINE_OPTIONS[..]) {
+        let presets: Vec<&str> = quirks::PRESETS.iter().map(|preset| preset.id).collect();
+        let themes: Vec<&str> = palette::THEMES.iter().map(|(name, _)| *name).collect();
+        println!("\npresets: {}", presets.join(", "));
+        println!("themes: {}", themes.join(", "));
+    }
+}
+
+/// Fails on any option `command` doesn't take, so typos don't go unnoticed
+fn check_options(command: &str, args: &[String]) -> Result<(), String> {
+    let known = find_command(command).map_or(&[][..], |command| command.options);
+    for arg in args.iter().filter(|arg| arg.starts_with('-')) {
+        let name = arg.split('=').next().unwrap_or(arg);
+        if !known
+            .iter()
+            .copied()
+            .flatten()
+            .any(|option| option.0 == name)
+        {
+            return Err(format!(
+                "unknown option {}, see `chip8 {} --help`",
+                name, command
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether an option takes its value from the following argument, e.g. `--frontend tui`
+fn takes_value(arg: &str) -> bool {
+    COMMANDS
+        .iter()
+        .flat_map(|command| command.options.iter().copied().flatten())
+        .any(|&(name, value, _)| name == arg && value.starts_with(' '))
+}
+
+/// The file a command works on: the first argument that isn't an option or an option's value
+fn filename<'a>(command: &str, args: &'a [String]) -> Result<&'a str, String> {
+    args.iter()
+        .enumerate()
+        .find(|&(index, arg)| {
+            !arg.starts_with('-') && (index == 0 || !takes_value(&args[index - 1]))
+        })
+        .map(|(_, arg)| arg.as_str())
+        .ok_or_else(|| {
+            let usage = find_command(command).map_or("<file>", |command| command.usage);
+            format!(
+                "missing {}, usage: chip8 {} {} [options]",
+                usage, command, usage
+            )
+        })
+}
+
+/// Parses the value of `--name` as a number, or `default` when it isn't given
+fn number_option<T: FromStr>(args: &[String], name: &str, default: T) -> Result<T, String> {
+    match option_value(args, name) {
+        Some(value) => value
+            .parse()
+            .map_err(|_| format!("{} must be a number, got '{}'", name, value)),
+        None => Ok(default),
+    }
+}
+
+fn volume_option(args: &[String], default: f32) -> Result<f32, String> {
+    match number_option(args, "--volume", default)? {
+        volume if (0.0..=1.0).contains(&volume) => Ok(volume),
+        _ => Err(String::from("--volume must be between 0 and 1")),
+    }
+}
+
+fn scale_option(args: &[String], default: u32) -> Result<u32, String> {
+    match number_option(args, "--scale", default)? {
+        0 => Err(String::from("--scale must be a positive number")),
+        scale => Ok(scale),
+    }
+}
+
+/// The `--start` address, written in hex like the addresses in listings
+fn start_address(args: &[String]) -> Result<Option<u16>, String> {
+    let value = match option_value(args, "--start") {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    match u16::from_str_radix(digits, 16) {
+        Ok(address) if address < 0x1000 => Ok(Some(address)),
+        _ => Err(format!(
+            "--start must be a hex address below 1000, got '{}'",
+            value
+        )),
+    }
+}
+
+/// Reads the ROM named on the command line, choosing one from an archive or directory by `--rom`
+fn read_rom(args: &[String], filename: &str) -> Result<RomFile, String> {
+    loader::load(filename, option_value(args, "--rom"))
+}
+
+/// The config from `--config=`, otherwise the user's own when `user_config` is set, with
+/// the path to save it back to
+fn load_config(args: &[String], user_config: bool) -> Result<(Config, Option<PathBuf>), String> {
+    let path = match option_value(args, "--config") {
+        Some(path) => Some(PathBuf::from(path)),
+        None if user_config => config::default_path(),
+        None => None,
+    };
+    match path {
+        Some(path) => Ok((Config::load(&path)?, Some(path))),
+        None => Ok((Config::default(), None)),
+    }
+}
+
+/// A ROM loaded into a machine set up by the database, the config and the machine options
+struct Machine {
+    /// Where the ROM came from, for naming files after it
+    path: String,
+    contents: Vec<u8>,
+    memory: Memory,
+    profile: Option<Profile>,
+    symbols: Option<Symbols>,
+    /// The command line followed by the options saved for this ROM, which it overrides
+    args: Vec<String>,
+    /// The options from the config's defaults, for when nothing else gives a value
+    defaults: Vec<String>,
+}
+
+fn load_machine(args: &[String], rom: RomFile, config: &Config) -> Result<Machine, String> {
+    let RomFile {
+        path,
+        contents,
+        symbols,
+        profile,
+    } = rom;
+    let mut memory = Memory::new();
+
+    let mut args = args.to_vec();
+    if let Some(settings) = config.roms.get(&database::sha1(&contents)) {
+        args.extend(settings.to_args());
+    }
+    let defaults = config.defaults.to_args();
+
+    let start = start_address(&args)?;
+    if let Some(start) = start {
+        memory.program_counter = start;
+    }
+    if let Some(reason) = detect::foreign_format(&contents) {
+        println!(
+            "warning: {} {}, so it may not be a CHIP-8 program",
+            path, reason
+        );
+    }
+
+    // The database and the ROM's own code know better than the defaults
+    let default_preset = apply_machine_options(&defaults, &mut memory)?;
+    let (profile, identified) = identify_rom(&args, &path, &contents, profile, &mut memory)?;
+    let chosen = apply_machine_options(&args, &mut memory)?;
+
+    let platform = chosen
+        .or(identified)
+        .or(default_preset)
+        .map(|preset| preset.platform);
+    if platform == Some(Platform::XoChip) {
+        memory.ram.resize(memory::XO_CHIP_RAM_SIZE, 0);
+    }
+    match start {
+        Some(start) => memory.load_rom_at(start as usize, &contents),
+        None => memory.load_rom(&contents),
+    }
+    .map_err(|error| format!("can't load {}: {}", path, error))?;
+
+    let symbols = load_symbols(&args, &path, symbols)?;
+
+    Ok(Machine {
+        path,
+        contents,
+        memory,
+        profile,
+        symbols,
+        args,
+        defaults,
+    })
+}
+
+/// Applies `--quirks`, `--speed` and `--seed`, returning the quirks preset if one was chosen
+fn apply_machine_options(
+    args: &[String],
+    memory: &mut Memory,
+) -> Result<Option<&'static Preset>, String> {
+    let mut chosen = None;
+    if let Some(id) = option_value(args, "--quirks") {
+        let preset = quirks::find_preset(id).ok_or_else(|| {
+            let ids: Vec<&str> = quirks::PRESETS.iter().map(|preset| preset.id).collect();
+            format!(
+                "unknown quirks preset '{}', expected one of: {}",
+                id,
+                ids.join(", ")
+            )
+        })?;
+        memory.quirks = preset.quirks;
+        chosen = Some(preset);
+    }
+    memory.speed = match number_option(args, "--speed", memory.speed)? {
+        0 => return Err(String::from("--speed must be a positive number")),
+        speed => speed,
+    };
+    if let Some(seed) = option_value(args, "--seed") {
+        let seed = seed
+            .parse()
+            .map_err(|_| format!("--seed must be a number, got '{}'", seed))?;
+        memory.rng = StdRng::seed_from_u64(seed);
+    }
+    Ok(chosen)
+}
+
+/// Runs for a number of frames with no keys pressed
+fn run_headless(
+    memory: &mut Memory,
+    mut recompiler: Option<&mut Recompiler>,
+    instruments: &mut Instruments,
+    frames: usize,
+) -> Result<(), ExecError> {
+    let no_keys = HashSet::new();
+    for _ in 0..frames {
+        run_frame(
+            memory,
+            recompiler.as_deref_mut(),
+            instruments,
+            &no_keys,
+            &no_keys,
+        )?;
+    }
+    Ok(())
+}
+
+/// Refuses to let `--watch=<source>` assemble over anything but a plain ROM file, since
+/// a directory, an archive, a cartridge or the source itself would be lost
+fn check_watch_output(source: &str, filename: &str) -> Result<(), String> {
+    let path = Path::new(filename);
+    let octo = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("8o"));
+    let kind = if path.is_dir() {
+        "a directory"
+    } else if loader::is_zip(filename) {
+        "a zip archive"
+    } else if octo || path == Path::new(source) {
+        "source code"
+    } else if fs::read(path).is_ok_and(|contents| cartridge::is_gif(&contents)) {
+        "a cartridge"
+    } else {
+        return Ok(());
+    };
+    Err(format!(
+        "--watch={} would assemble over {}, which is {}; run the ROM file it builds instead",
+        source, filename, kind
+    ))
+}
+
+/// `chip8 run <file>`: runs a ROM in a window, the terminal or headless
+fn run_command(args: &[String]) -> Result<(), String> {
+    check_options("run", args)?;
+    let filename = filename("run", args)?;
+    let (config, config_path) = load_config(args, true)?;
+    if let Some(source) = flag_value(args, "--watch") {
+        check_watch_output(source, filename)?;
+        assemble_file(source, args, Path::new(filename))?;
+    }
+    let machine = load_machine(args, read_rom(args, filename)?, &config)?;
+    let mut session = Session::new(machine)?;
+
+    let Session {
+        machine,
+        recompiler,
+        palette,
+        instruments,
+        keymap,
+        ..
+    } = &mut session;
+    let rom_args = &machine.args[..];
+    let memory = &mut machine.memory;
+    let symbols = machine.symbols.as_ref();
+
+    if rom_args.iter().any(|arg| arg == "--headless") {
+        let frames = number_option(rom_args, "--frames", 300)?;
+        let result = run_headless(memory, recompiler.as_mut(), instruments, frames);
+        finish_instruments(rom_args, instruments, memory, symbols);
+        return result.map_err(|error| describe_error(&error, symbols));
+    }
+
+    if let Some(port) = option_value(rom_args, "--gdb-port") {
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| String::from("--gdb-port must be a port number"))?;
+        return GdbStub::new()
+            .serve(port, memory)
+            .map_err(|error| error.to_string());
+    }
+
+    if rom_args.iter().any(|arg| arg == "--debug") {
+        let mut debugger = Debugger::new(memory.program_counter);
+        return debugger
+            .run(memory, palette)
+            .map_err(|error| error.to_string());
+    }
+
+    match option_value(rom_args, "--frontend") {
+        None | Some("sdl") => run_window(args, session, config, config_path),
+        Some("tui") => {
+            let result = tui::run(memory, recompiler.as_mut(), instruments, palette, keymap);
+            finish_instruments(rom_args, instruments, memory, symbols);
+            // Execution errors come back wrapped so they can share the terminal's io::Result
+            result.map_err(|error| {
+                match error
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<ExecError>())
+                {
+                    Some(error) => describe_error(error, symbols),
+                    None => error.to_string(),
+                }
+            })
+        }
+        Some(frontend) => Err(format!(
+            "unknown frontend '{}', expected sdl or tui",
+            frontend
+        )),
+    }
+}
+
+/// A ROM being run along with everything set up for it, which is replaced when another ROM is
+/// dropped on the window or picked from the browser
+struct Session {
+    machine: Machine,
+    recompiler: Option<Recompiler>,
+    keymap: Keymap,
+    theme: usize,
+    palette: Palette,
+    instruments: Instruments,
+    /// Settings changed with the function keys, saved for this ROM when it's closed
+    changes: Settings,
+}
+
+impl Session {
+    fn new(machine: Machine) -> Result<Session, String> {
+        let args = &machine.args[..];
+        let profile = machine.profile.as_ref();
+
+        // --cross-check implies --recompile
+        let cross_check = args.iter().any(|arg| arg == "--cross-check");
+        let recompiler = if cross_check || args.iter().any(|arg| arg == "--recompile") {
+            Some(Recompiler::new(cross_check))
+        } else {
+            None
+        };
+
+        let keymap = select_keymap(args, &machine.defaults, profile)?;
+        let (theme, palette) = select_palette(args, &machine.defaults, profile)?;
+        let instruments = create_instruments(
+            args,
+            &machine.memory,
+            machine.contents.len(),
+            machine.symbols.as_ref(),
+        )?;
+
+        Ok(Session {
+            machine,
+            recompiler,
+            keymap,
+            theme,
+            palette,
+            instruments,
+            changes: Settings::default(),
+        })
+    }
+
+    fn title(&self) -> String {
+        self.machine
+            .profile
+            .as_ref()
+            .map(Profile::describe)
+            .unwrap_or_else(|| String::from("CHIP8 Emulator"))
+    }
+
+    /// Writes out the instruments' reports and saves any settings changed for this ROM
+    fn close(&self, config: &mut Config, config_path: Option<&Path>) {
+        let machine = &self.machine;
+        if let Some(path) = config_path.filter(|_| self.changes != Settings::default()) {
+            let hash = database::sha1(&machine.contents);
+            config.remember(&hash, &self.changes);
+            match config.save(path, &hash) {
+                Ok(()) => println!("saved settings for this ROM to {}", path.display()),
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+
+        finish_instruments(
+            &machine.args,
+            &self.instruments,
+            &machine.memory,
+            machine.symbols.as_ref(),
+        );
+    }
+}
+
+/// Runs `session` in a window until it's closed. ROMs dropped on the window or picked from the
+/// browser with F1 take over, set up by the same command line `args`, and with `--watch` the
+/// ROM is reloaded whenever it's rebuilt.
+fn run_window(
+    args: &[String],
+    mut session: Session,
+    mut config: Config,
+    config_path: Option<PathBuf>,
+) -> Result<(), String> {
+    let rom_args = &session.machine.args[..];
+    let defaults = &session.machine.defaults[..];
+    let scale = scale_option(rom_args, scale_option(defaults, display_constants::SCALE)?)?;
+    let volume = volume_option(rom_args, volume_option(defaults, audio::DEFAULT_VOLUME)?)?;
+
+    let phosphor = match option_value(args, "--phosphor") {
+        Some(value) => Some(Phosphor::new(
+            PhosphorMode::parse(value)
+                .map_err(|error| format!("invalid phosphor mode: {}", error))?,
+        )),
+        None => None,
+    };
+
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
 
     let window = video_subsystem
         .window(
-            "CHIP8 Emulator",
-            display_constants::WIDTH * display_constants::SCALE,
-            display_constants::HEIGHT * display_constants::SCALE,
+            &session.title(),
+            display_constants::WIDTH * scale,
+            display_constants::HEIGHT * scale,
         )
         .position_centered()
+        .resizable()
+        .build()
+        .map_err(|error| error.to_string())?;
+
+    let mut canvas = window
+        .into_canvas()
         .build()
-        .unwrap();
+        .map_err(|error| error.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            display_constants::WIDTH,
+            display_constants::HEIGHT,
+        )
+        .map_err(|error| error.to_string())?;
+    let mut menu_texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::RGB24,
+            browser::WIDTH as u32,
+            browser::HEIGHT as u32,
+        )
+        .map_err(|error| error.to_string())?;
+    let mut renderer = Renderer::new(session.palette, phosphor);
 
-    let mut canvas = window.into_canvas().build().unwrap();
+    let movie_path = option_value(args, "--record-movie");
+    let mut movie = Movie::default();
+    let mut gif_recorder: Option<GifRecorder> = None;
 
-    canvas.set_draw_color(Color::RGB(0, 255, 255));
-    canvas.clear();
-    canvas.present();
-    let mut event_pump = sdl_context.event_pump().unwrap();
+    // Carry on silently rather than refuse to run without a sound device
+    let mut beeper = match volume > 0.0 {
+        true => sdl_context
+            .audio()
+            .and_then(|audio| Beeper::new(&audio, volume))
+            .map_err(|error| eprintln!("no sound: {}", error))
+            .ok(),
+        false => None,
+    };
+    let mut current_volume = volume;
+
+    // --watch=<source> watches the source, rewriting the ROM itself
+    let filename = filename("run", args)?;
+    let watched_path = session.machine.path.clone();
+    let mut watcher = args
+        .iter()
+        .any(|arg| arg == "--watch" || arg.starts_with("--watch="))
+        .then(|| Watcher::new(flag_value(args, "--watch").unwrap_or(filename)));
+    let keep_state = args.iter().any(|arg| arg == "--keep-state");
+
+    // The machine is paused while the browser is open
+    let mut browser: Option<Browser> = None;
+    // The next ROM to run, and whether it's a new build of the current one
+    let mut opening: Option<(RomFile, bool)> = None;
+
+    let mut event_pump = sdl_context.event_pump()?;
     let mut old_scancodes: HashSet<Scancode> = HashSet::new();
+    let mut result = Ok(());
 
     'running: loop {
         for event in event_pump.poll_iter() {
+            // The browser takes every key while it's open
+            if let (
+                Some(open),
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    ..
+                },
+            ) = (browser.as_mut(), &event)
+            {
+                match open.key(*keycode) {
+                    Some(browser::Action::Open(rom)) => {
+                        opening = Some((*rom, false));
+                        browser = None;
+                    }
+                    Some(browser::Action::Close) => browser = None,
+                    None => {}
+                }
+                continue;
+            }
+
+            let memory = &mut session.machine.memory;
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::DropFile { filename, .. } => {
+                    // Directories and archives open in the browser to pick a ROM from
+                    if Path::new(&filename).is_dir() || loader::is_zip(&filename) {
+                        browser = open_browser(args, &filename);
+                    } else {
+                        match loader::load(&filename, None) {
+                            Ok(rom) => opening = Some((rom, false)),
+                            Err(error) => eprintln!("{}", error),
+                        }
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    ..
+                } => browser = open_browser(args, &browser_location(&session.machine.path)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F2),
+                    ..
+                } => {
+                    session.theme = (session.theme + 1) % palette::THEMES.len();
+                    renderer.set_palette(palette::THEMES[session.theme].1);
+                    session.changes.theme = Some(palette::THEMES[session.theme].0.to_string());
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F3),
+                    ..
+                } => {
+                    current_volume = match current_volume > 0.0 {
+                        true => 0.0,
+                        false if volume > 0.0 => volume,
+                        false => audio::DEFAULT_VOLUME,
+                    };
+                    if beeper.is_none() && current_volume > 0.0 {
+                        beeper = sdl_context
+                            .audio()
+                            .and_then(|audio| Beeper::new(&audio, current_volume))
+                            .map_err(|error| eprintln!("no sound: {}", error))
+                            .ok();
+                    }
+                    if let Some(beeper) = beeper.as_mut() {
+                        beeper.set_volume(current_volume);
+                    }
+                    println!("volume {}", current_volume);
+                    session.changes.volume = Some(current_volume);
+                }
+                Event::KeyDown {
+                    keycode: Some(keycode @ (Keycode::F5 | Keycode::F6)),
+                    ..
+                } => {
+                    let step = (memory.speed / 5).max(1);
+                    memory.speed = match keycode {
+                        Keycode::F5 => memory.speed.saturating_sub(step).max(1),
+                        _ => memory.speed + step,
+                    };
+                    println!("{} instructions per frame", memory.speed);
+                    session.changes.speed = Some(memory.speed);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    keymod,
+                    ..
+                } => {
+                    // Shift saves at the native 64x32 resolution
+                    let scale = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        1
+                    } else {
+                        scale
+                    };
+                    let path = next_output_path(&session.machine.path, "png");
+
+                    match screenshot::save_png(&path, &memory.display, &renderer.palette, scale) {
+                        Ok(()) => println!("saved screenshot to {}", path.display()),
+                        Err(error) => eprintln!("failed to save screenshot: {}", error),
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => match gif_recorder.take() {
+                    Some(recorder) => match recorder.finish() {
+                        Ok(()) => println!("stopped recording"),
+                        Err(error) => eprintln!("failed to save recording: {}", error),
+                    },
+                    None => {
+                        let path = next_output_path(&session.machine.path, "gif");
+                        // Record every other frame, GIF delays can't represent 60fps
+                        match GifRecorder::new(&path, &renderer.palette, scale, 1) {
+                            Ok(recorder) => {
+                                println!("recording to {}", path.display());
+                                gif_recorder = Some(recorder);
+                            }
+                            Err(error) => eprintln!("failed to start recording: {}", error),
+                        }
+                    }
+                },
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                    ..
+                } => renderer.invalidate(),
                 _ => {}
             }
         }
 
-        let pressed_keys = pressed_keycode_set(&event_pump);
-        let new_keys: HashSet<Keycode> =
-            newly_pressed(&old_scancodes, &pressed_scancode_set(&event_pump))
+        // Only reload while the ROM that's being watched is the one running
+        if watcher.as_mut().is_some_and(Watcher::poll) && session.machine.path == watched_path {
+            let rebuilt = match flag_value(args, "--watch") {
+                Some(source) => assemble_file(source, args, Path::new(filename)),
+                None => Ok(()),
+            };
+            match rebuilt.and_then(|()| read_rom(args, filename)) {
+                Ok(rom) => opening = Some((rom, true)),
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+
+        if let Some((rom, reload)) = opening.take() {
+            match load_machine(args, rom, &config).and_then(Session::new) {
+                Ok(mut next) => {
+                    let old = &session.machine;
+                    if reload
+                        && keep_state
+                        && watch::compatible(
+                            &old.contents,
+                            old.symbols.as_ref(),
+                            &next.machine.contents,
+                            next.machine.symbols.as_ref(),
+                        )
+                    {
+                        let start = start_address(&next.machine.args)?
+                            .map_or(memory::INTERPRETER_SIZE, usize::from);
+                        let mut memory = old.memory.clone();
+                        memory
+                            .load_rom_at(start, &next.machine.contents)
+                            .map_err(|error| error.to_string())?;
+                        next.machine.memory = memory;
+                        println!("reloaded {}, carrying on where it was", old.path);
+                    } else if reload {
+                        println!("reloaded {}", old.path);
+                    }
+
+                    session.close(&mut config, config_path.as_deref());
+                    session = next;
+                    canvas
+                        .window_mut()
+                        .set_title(&session.title())
+                        .map_err(|error| error.to_string())?;
+                    renderer.set_palette(session.palette);
+                    // A movie only replays on the ROM it was recorded with
+                    movie = Movie::default();
+                }
+                Err(error) => eprintln!("{}", error),
+            }
+        }
+
+        if let Some(open) = browser.as_ref() {
+            if let Some(beeper) = beeper.as_mut() {
+                beeper.update(0);
+            }
+            old_scancodes = pressed_scancode_set(&event_pump);
+            renderer.render_menu(&mut canvas, &mut menu_texture, &open.render());
+            std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+            continue;
+        }
+
+        let keymap = &session.keymap;
+        let pressed_keys = keymap.apply(&pressed_keycode_set(&event_pump));
+        let new_keys = keymap.apply(
+            &newly_pressed(&old_scancodes, &pressed_scancode_set(&event_pump))
                 .iter()
                 .filter_map(|&s| Keycode::from_scancode(s))
-                .collect();
+                .collect(),
+        );
 
-        for _ in 0..20 {
-            let instruction = interpreter::parse(
-                memory.ram[memory.program_counter as usize],
-                memory.ram[memory.program_counter as usize + 1],
-            );
+        let memory = &mut session.machine.memory;
+        if let Err(error) = run_frame(
+            memory,
+            session.recompiler.as_mut(),
+            &mut session.instruments,
+            &pressed_keys,
+            &new_keys,
+        ) {
+            result = Err(describe_error(&error, session.machine.symbols.as_ref()));
+            break 'running;
+        }
 
-            if instruction == Instruction::Invalid {
-                continue;
+        if let Some(beeper) = beeper.as_mut() {
+            beeper.update(memory.sound);
+        }
+
+        old_scancodes = pressed_scancode_set(&event_pump);
+
+        if movie_path.is_some() {
+            movie.record(&pressed_keys);
+        }
+
+        if let Some(recorder) = gif_recorder.as_mut() {
+            if let Err(error) = recorder.capture(&memory.display) {
+                eprintln!("failed to record frame: {}", error);
+                gif_recorder = None;
             }
+        }
+
+        renderer.render(&mut canvas, &mut texture, &memory.display);
+
+        std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+    }
 
-            interpreter::execute(&mut memory, instruction, &pressed_keys, &new_keys);
+    if let Some(recorder) = gif_recorder {
+        if let Err(error) = recorder.finish() {
+            eprintln!("failed to save recording: {}", error);
         }
+    }
 
-        old_scancodes = pressed_scancode_set(&event_pump);
+    if let Some(path) = movie_path {
+        match fs::write(path, movie.to_string()) {
+            Ok(()) => println!("saved input movie to {}", path),
+            Err(error) => eprintln!("failed to save input movie: {}", error),
+        }
+    }
 
-        let mut filled_rects = Vec::new();
-        let mut blank_rects = Vec::new();
-
-        for row in 0..(display_constants::HEIGHT as usize) {
-            for col in 0..(display_constants::WIDTH as usize) {
-                if memory.display[row][col] == 1 {
-                    filled_rects.push(Rect::new(
-                        col as i32 * display_constants::SCALE as i32,
-                        row as i32 * display_constants::SCALE as i32,
-                        display_constants::SCALE,
-                        display_constants::SCALE,
-                    ));
-                } else {
-                    blank_rects.push(Rect::new(
-                        col as i32 * display_constants::SCALE as i32,
-                        row as i32 * display_constants::SCALE as i32,
-                        display_constants::SCALE,
-                        display_constants::SCALE,
-                    ));
-                }
+    // Resizing the window picks a new scale
+    let window_scale = canvas.window().size().0 / display_constants::WIDTH;
+    if window_scale > 0 && window_scale != scale {
+        session.changes.scale = Some(window_scale);
+    }
+    session.close(&mut config, config_path.as_deref());
+    result
+}
+
+/// Lists the ROMs in a directory or archive, with titles from the database `args` choose
+fn open_browser(args: &[String], path: &str) -> Option<Browser> {
+    open_database(args)
+        .and_then(|database| Browser::open(path, &database))
+        .map_err(|error| eprintln!("{}", error))
+        .ok()
+}
+
+/// The directory or archive a ROM came from, for browsing the ROMs next to it
+fn browser_location(path: &str) -> String {
+    Path::new(path)
+        .ancestors()
+        .skip(1)
+        .map(|directory| match directory.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => directory,
+        })
+        .find(|directory| directory.is_dir() || loader::is_zip(&directory.to_string_lossy()))
+        .map(|directory| directory.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from("."))
+}
+
+/// Tools that need to see every instruction, so they run on the interpreter instead of the recompiler
+#[derive(Default)]
+pub struct Instruments {
+    pub tracer: Option<Tracer>,
+    pub profiler: Option<Profiler>,
+    pub coverage: Option<Coverage>,
+}
+
+impl Instruments {
+    fn is_empty(&self) -> bool {
+        self.tracer.is_none() && self.profiler.is_none() && self.coverage.is_none()
+    }
+
+    fn step(
+        &mut self,
+        memory: &mut Memory,
+        pressed_keys: &HashSet<Keycode>,
+        new_keys: &HashSet<Keycode>,
+    ) -> Result<(), ExecError> {
+        let address = memory.program_counter;
+
+        match self.tracer.as_mut() {
+            Some(tracer) => tracer.step(memory, pressed_keys, new_keys)?,
+            None => interpreter::step(memory, pressed_keys, new_keys)?,
+        }
+
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.record(address, memory);
+        }
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.record(address, memory);
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs one 60Hz frame: `memory.speed` instructions, or up to the first draw with the
+/// vblank quirk, followed by a timer tick. Any instruments take priority over the recompiler.
+fn run_frame(
+    memory: &mut Memory,
+    recompiler: Option<&mut Recompiler>,
+    instruments: &mut Instruments,
+    pressed_keys: &HashSet<Keycode>,
+    new_keys: &HashSet<Keycode>,
+) -> Result<(), ExecError> {
+    if !instruments.is_empty() {
+        for _ in 0..memory.speed {
+            let vblank = interpreter::waits_for_vblank(memory);
+            instruments.step(memory, pressed_keys, new_keys)?;
+            if vblank {
+                break;
+            }
+        }
+        if let Some(profiler) = instruments.profiler.as_mut() {
+            profiler.end_frame();
+        }
+    } else if let Some(recompiler) = recompiler {
+        recompiler.run(memory, memory.speed, pressed_keys, new_keys)?;
+    } else {
+        for _ in 0..memory.speed {
+            let vblank = interpreter::waits_for_vblank(memory);
+            interpreter::step(memory, pressed_keys, new_keys)?;
+            if vblank {
+                break;
             }
         }
+    }
+
+    memory.tick_timers();
 
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
-        canvas.fill_rects(&filled_rects).unwrap();
+    Ok(())
+}
 
-        canvas.set_draw_color(Color::RGB(0, 255, 255));
-        canvas.fill_rects(&blank_rects).unwrap();
+/// `chip8 screenshot <file>`: runs a ROM headless for a number of frames and saves the display as a PNG
+fn screenshot_command(args: &[String]) -> Result<(), String> {
+    check_options("screenshot", args)?;
+    let filename = filename("screenshot", args)?;
+    let frames = number_option(args, "--frames", 300)?;
+    let scale = scale_option(args, 1)?;
+    let out = option_value(args, "--out").unwrap_or("screenshot.png");
 
-        if memory.delay > 0 {
-            memory.delay -= 1;
+    let (config, _) = load_config(args, false)?;
+    let Machine {
+        contents,
+        mut memory,
+        profile,
+        symbols,
+        args: rom_args,
+        defaults,
+        ..
+    } = load_machine(args, read_rom(args, filename)?, &config)?;
+    let (_, palette) = select_palette(&rom_args, &defaults, profile.as_ref())?;
+    let mut instruments = create_instruments(args, &memory, contents.len(), symbols.as_ref())?;
+
+    // Save whatever was on screen when it stopped, which helps to see why
+    if let Err(error) = run_headless(&mut memory, None, &mut instruments, frames) {
+        eprintln!("{}", describe_error(&error, symbols.as_ref()));
+    }
+    finish_instruments(args, &instruments, &memory, symbols.as_ref());
+
+    screenshot::save_png(Path::new(out), &memory.display, &palette, scale)
+        .map_err(|error| format!("failed to save screenshot: {}", error))?;
+    println!("saved screenshot to {}", out);
+    Ok(())
+}
+
+/// `chip8 gif <file>`: runs a ROM headless, optionally replaying an input movie, and records it as a GIF
+fn gif_command(args: &[String]) -> Result<(), String> {
+    check_options("gif", args)?;
+    let filename = filename("gif", args)?;
+
+    let movie = match option_value(args, "--movie") {
+        Some(path) => {
+            let text = fs::read_to_string(path)
+                .map_err(|error| format!("failed to read {}: {}", path, error))?;
+            Movie::parse(&text).map_err(|error| format!("invalid movie: {}", error))?
         }
+        None => Movie::default(),
+    };
+
+    // Without an explicit frame count, run for as long as the movie lasts
+    let default_frames = match movie.frames.len() {
+        0 => 300,
+        length => length,
+    };
+    let frames = number_option(args, "--frames", default_frames)?;
+    let skip = number_option(args, "--skip", 1)?;
+    let scale = scale_option(args, 1)?;
+    let out = option_value(args, "--out").unwrap_or("recording.gif");
+
+    let (config, _) = load_config(args, false)?;
+    let Machine {
+        contents,
+        mut memory,
+        profile,
+        symbols,
+        args: rom_args,
+        defaults,
+        ..
+    } = load_machine(args, read_rom(args, filename)?, &config)?;
+    let (_, palette) = select_palette(&rom_args, &defaults, profile.as_ref())?;
+    let mut instruments = create_instruments(args, &memory, contents.len(), symbols.as_ref())?;
+
+    let result =
+        GifRecorder::new(Path::new(out), &palette, scale, skip).and_then(|mut recorder| {
+            for frame in 0..frames {
+                let (pressed_keys, new_keys) = movie.keys(frame);
+                run_frame(
+                    &mut memory,
+                    None,
+                    &mut instruments,
+                    &pressed_keys,
+                    &new_keys,
+                )
+                .map_err(io::Error::other)?;
+                recorder.capture(&memory.display)?;
+            }
+            recorder.finish()
+        });
+    finish_instruments(args, &instruments, &memory, symbols.as_ref());
 
-        if memory.sound > 0 {
-            memory.sound -= 1;
+    result.map_err(|error| format!("failed to save recording: {}", error))?;
+    println!("saved recording to {}", out);
+    Ok(())
+}
+
+/// `chip8 test <file>`: runs a ROM headless and compares the display with the one saved
+/// next to it, saving it instead when there isn't one yet
+fn test_command(args: &[String]) -> Result<(), String> {
+    check_options("test", args)?;
+    let filename = filename("test", args)?;
+    let frames = number_option(args, "--frames", 300)?;
+
+    // Only reads a config when asked, so the results don't depend on whose machine runs it
+    let (config, _) = load_config(args, false)?;
+    let Machine {
+        path: rom_path,
+        contents,
+        mut memory,
+        symbols,
+        ..
+    } = load_machine(args, read_rom(args, filename)?, &config)?;
+    let mut instruments = create_instruments(args, &memory, contents.len(), symbols.as_ref())?;
+
+    let result = run_headless(&mut memory, None, &mut instruments, frames);
+    finish_instruments(args, &instruments, &memory, symbols.as_ref());
+    result.map_err(|error| describe_error(&error, symbols.as_ref()))?;
+
+    let display = screenshot::to_text(&memory.display);
+    let path = match option_value(args, "--expect") {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(&rom_path).with_extension("expected.txt"),
+    };
+
+    let expected = match fs::read_to_string(&path) {
+        Ok(expected) => expected,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            fs::write(&path, display)
+                .map_err(|error| format!("failed to save {}: {}", path.display(), error))?;
+            println!(
+                "saved the display to {}, later runs will be checked against it",
+                path.display()
+            );
+            return Ok(());
         }
+        Err(error) => return Err(format!("failed to read {}: {}", path.display(), error)),
+    };
 
-        canvas.present();
-        std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 60));
+    if expected == display {
+        println!("passed after {} frames", frames);
+        return Ok(());
+    }
+
+    for (row, (expected, actual)) in expected.lines().zip(display.lines()).enumerate() {
+        if expected != actual {
+            println!("row {:2} expected {}", row, expected);
+            println!("          got {}", actual);
+        }
+    }
+    Err(format!(
+        "the display after {} frames doesn't match {}",
+        frames,
+        path.display()
+    ))
+}
+
+/// `chip8 disasm <file>`: prints a disassembly listing, labelled when symbols are available
+fn disasm_command(args: &[String]) -> Result<(), String> {
+    check_options("disasm", args)?;
+    let filename = filename("disasm", args)?;
+    let origin = start_address(args)?.unwrap_or(memory::INTERPRETER_SIZE as u16);
+
+    let RomFile {
+        path,
+        contents,
+        symbols,
+        ..
+    } = read_rom(args, filename)?;
+    let symbols = load_symbols(args, &path, symbols)?.unwrap_or_default();
+    print!("{}", symbols::listing(&contents, origin, &symbols));
+    Ok(())
+}
+
+/// `chip8 asm <source>`: assembles a ROM, with a symbol file next to it so errors and
+/// traces point back at the source
+fn asm_command(args: &[String]) -> Result<(), String> {
+    check_options("asm", args)?;
+    let source_path = filename("asm", args)?;
+    let out = match option_value(args, "--out") {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(source_path).with_extension("ch8"),
+    };
+    assemble_file(source_path, args, &out)
+}
+
+/// Assembles the source at `source_path` for the address `--start` gives, or compiles it if
+/// it's Octo, saving the ROM to `out` and its symbols next to it
+fn assemble_file(source_path: &str, args: &[String], out: &Path) -> Result<(), String> {
+    let origin = start_address(args)?.unwrap_or(memory::INTERPRETER_SIZE as u16);
+
+    let source = fs::read_to_string(source_path)
+        .map_err(|error| format!("failed to read {}: {}", source_path, error))?;
+    let program = match Path::new(source_path).extension() {
+        Some(extension) if extension == "8o" => octo::compile(&source, source_path)?,
+        _ => assembler::assemble(&source, source_path, origin)?,
+    };
+
+    fs::write(out, &program.rom)
+        .map_err(|error| format!("failed to save {}: {}", out.display(), error))?;
+    println!("assembled {} bytes to {}", program.rom.len(), out.display());
+
+    let symbols_path = out.with_extension("sym");
+    fs::write(&symbols_path, program.symbols.to_string())
+        .map_err(|error| format!("failed to save {}: {}", symbols_path.display(), error))?;
+    println!("saved symbols to {}", symbols_path.display());
+    Ok(())
+}
+
+/// `chip8 cfg <file>`: summarises the ROM's control-flow graph, optionally writing it out as Graphviz DOT
+fn cfg_command(args: &[String]) -> Result<(), String> {
+    check_options("cfg", args)?;
+    let filename = filename("cfg", args)?;
+    let origin = start_address(args)?.unwrap_or(memory::INTERPRETER_SIZE as u16);
+
+    let RomFile {
+        path,
+        contents,
+        symbols,
+        ..
+    } = read_rom(args, filename)?;
+    let symbols = load_symbols(args, &path, symbols)?.unwrap_or_default();
+
+    let cfg = Cfg::build(&contents, origin);
+    print!("{}", cfg.summary(&symbols));
+
+    if let Some(path) = option_value(args, "--dot") {
+        save_report(path, "control-flow graph", cfg.to_dot(&contents, &symbols));
+    }
+    Ok(())
+}
+
+/// `chip8 lint <file>`: prints likely problems found by static analysis, failing if there are any
+fn lint_command(args: &[String]) -> Result<(), String> {
+    check_options("lint", args)?;
+    let filename = filename("lint", args)?;
+    let origin = start_address(args)?.unwrap_or(memory::INTERPRETER_SIZE as u16);
+
+    let RomFile {
+        path,
+        contents,
+        symbols,
+        ..
+    } = read_rom(args, filename)?;
+    let symbols = load_symbols(args, &path, symbols)?.unwrap_or_default();
+
+    let warnings = lint::lint(&contents, origin);
+    for warning in warnings.iter() {
+        println!("{}: {}", symbols.locate(warning.address), warning.message);
+    }
+
+    match warnings.len() {
+        0 => Ok(()),
+        count => Err(format!("{} warnings", count)),
+    }
+}
+
+/// `chip8 info <file>`: describes a ROM without running it
+fn info_command(args: &[String]) -> Result<(), String> {
+    check_options("info", args)?;
+    let filename = filename("info", args)?;
+    let origin = start_address(args)?.unwrap_or(memory::INTERPRETER_SIZE as u16);
+
+    let RomFile {
+        path,
+        contents,
+        profile,
+        ..
+    } = read_rom(args, filename)?;
+    println!(
+        "{}: {} bytes, sha1 {}",
+        path,
+        contents.len(),
+        database::sha1(&contents)
+    );
+    if let Some(reason) = detect::foreign_format(&contents) {
+        println!("warning: {}, so it may not be a CHIP-8 program", reason);
     }
+
+    // Reports the database entry, or the guessed platform and why
+    let mut memory = Memory::new();
+    memory.program_counter = origin;
+    identify_rom(args, &path, &contents, profile, &mut memory)?;
+
+    let cfg = Cfg::build(&contents, origin);
+    let summary = cfg.summary(&Symbols::default());
+    if let Some(line) = summary.lines().next() {
+        println!("{}", line);
+    }
+
+    match lint::lint(&contents, origin).len() {
+        0 => println!("no likely problems found"),
+        count => println!("{} likely problems, see `chip8 lint`", count),
+    }
+    Ok(())
+}
+
+/// Picks the theme and palette from `--theme=` and `--palette=`, a custom palette taking priority.
+/// Without either the ROM's own colours from the database are used, then the configured defaults.
+fn select_palette(
+    args: &[String],
+    defaults: &[String],
+    profile: Option<&Profile>,
+) -> Result<(usize, Palette), String> {
+    let chosen = |args: &[String]| {
+        option_value(args, "--theme").is_some() || option_value(args, "--palette").is_some()
+    };
+    if !chosen(args) {
+        if let Some(palette) = profile.and_then(|profile| profile.palette) {
+            return Ok((0, palette));
+        }
+        if chosen(defaults) {
+            return colours(defaults);
+        }
+    }
+    colours(args)
+}
+
+/// The theme from `--theme=`, and the palette from `--palette=` or that theme
+fn colours(args: &[String]) -> Result<(usize, Palette), String> {
+    let mut theme = 0;
+    if let Some(name) = option_value(args, "--theme") {
+        theme = palette::find_theme(name).ok_or_else(|| {
+            let names: Vec<&str> = palette::THEMES.iter().map(|(name, _)| *name).collect();
+            format!(
+                "unknown theme '{}', expected one of: {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+    }
+
+    match option_value(args, "--palette") {
+        Some(value) => Palette::parse(value)
+            .map(|palette| (theme, palette))
+            .map_err(|error| format!("invalid palette: {}", error)),
+        None => Ok((theme, palette::THEMES[theme].1)),
+    }
+}
+
+/// Picks the keys from `--keymap`, the database, then the configured defaults
+fn select_keymap(
+    args: &[String],
+    defaults: &[String],
+    profile: Option<&Profile>,
+) -> Result<Keymap, String> {
+    let parse =
+        |value: &str| Keymap::parse(value).map_err(|error| format!("invalid --keymap: {}", error));
+
+    if let Some(value) = option_value(args, "--keymap") {
+        return parse(value);
+    }
+    match (
+        profile.filter(|profile| !profile.keys.is_empty()),
+        option_value(defaults, "--keymap"),
+    ) {
+        (Some(profile), _) => Ok(Keymap::from_actions(&profile.keys)),
+        (None, Some(value)) => parse(value),
+        (None, None) => Ok(Keymap::default()),
+    }
+}
+
+/// The database from `--database=<dir>`; without one no ROM is recognised
+fn open_database(args: &[String]) -> Result<Database, String> {
+    match option_value(args, "--database") {
+        Some(directory) => Database::load(Path::new(directory)),
+        None => Ok(Database::default()),
+    }
+}
+
+/// Takes the settings that came with the ROM, as in a cartridge, or else looks the ROM up in
+/// the database from `--database=<dir>` if there is one, falling back on scanning its code,
+/// and sets up the machine with the quirks and speed it expects.
+/// Returns the profile it's running with, if any, and the preset it's running as.
+fn identify_rom(
+    args: &[String],
+    path: &str,
+    contents: &[u8],
+    embedded: Option<Profile>,
+    memory: &mut Memory,
+) -> Result<(Option<Profile>, Option<&'static Preset>), String> {
+    if let Some(profile) = embedded {
+        println!(
+            "running as {} at {} instructions per frame, as the cartridge says",
+            profile.preset.name, profile.speed
+        );
+        memory.quirks = profile.quirks;
+        memory.speed = profile.speed;
+        let preset = profile.preset;
+        return Ok((Some(profile), Some(preset)));
+    }
+
+    let database = open_database(args)?;
+    let profile = database.lookup(contents);
+    let preset = match profile.as_ref() {
+        Some(profile) => {
+            println!(
+                "recognised {}, running as {} at {} instructions per frame",
+                profile.describe(),
+                profile.preset.name,
+                profile.speed
+            );
+            memory.quirks = profile.quirks;
+            memory.speed = profile.speed;
+            Some(profile.preset)
+        }
+        None => match loader::extension_preset(path) {
+            // Not in the database, so go by the file name
+            Some(preset) => {
+                let name = Path::new(path).file_name().unwrap_or_default();
+                println!(
+                    "running as {}, going by the file name {}",
+                    preset.name,
+                    name.to_string_lossy()
+                );
+                memory.quirks = preset.quirks;
+                memory.speed = preset.speed;
+                Some(preset)
+            }
+            // or failing that, guess from the code itself
+            None => {
+                let detection = detect::detect(contents, memory.program_counter);
+                match detection.preset {
+                    Some(preset) => {
+                        println!("running as {}, guessed because:", preset.name);
+                        for reason in detection.reasons.iter() {
+                            println!("  {}", reason);
+                        }
+                        memory.quirks = preset.quirks;
+                        memory.speed = preset.speed;
+                    }
+                    None => println!("no platform-specific code found, running as CHIP-8"),
+                }
+                detection.preset
+            }
+        },
+    };
+
+    if let Some(preset) = preset.filter(|preset| preset.platform != Platform::Chip8) {
+        println!(
+            "{} instructions aren't supported yet, so it may not run correctly",
+            preset.name
+        );
+    }
+
+    Ok((profile, preset))
+}
+
+/// Sets up `--trace`, `--profile` and `--coverage` for a ROM of `rom_length` bytes
+fn create_instruments(
+    args: &[String],
+    memory: &Memory,
+    rom_length: usize,
+    symbols: Option<&Symbols>,
+) -> Result<Instruments, String> {
+    let mut instruments = Instruments::default();
+
+    if let Some(path) = option_value(args, "--trace") {
+        instruments.tracer = Some(create_tracer(args, path, symbols.cloned())?);
+    }
+
+    if args
+        .iter()
+        .any(|arg| arg == "--profile" || arg.starts_with("--profile="))
+    {
+        instruments.profiler = Some(Profiler::new(memory.ram.len()));
+    }
+
+    let lcov = option_value(args, "--lcov").is_some();
+    if lcov && symbols.is_none() && option_value(args, "--coverage").is_none() {
+        return Err(String::from(
+            "without symbols --lcov refers to lines of the listing, so it needs --coverage=<listing> too",
+        ));
+    }
+    if lcov || option_value(args, "--coverage").is_some() {
+        instruments.coverage = Some(Coverage::new(
+            memory.ram.len(),
+            memory.program_counter as usize,
+            rom_length,
+        ));
+    }
+
+    Ok(instruments)
+}
+
+/// Writes out the profile and coverage reports once the ROM has stopped running
+fn finish_instruments(
+    args: &[String],
+    instruments: &Instruments,
+    memory: &Memory,
+    symbols: Option<&Symbols>,
+) {
+    if let Some(profiler) = instruments.profiler.as_ref() {
+        // --profile prints a summary, --profile=<path> saves it as JSON
+        match flag_value(args, "--profile") {
+            Some(path) => {
+                let json = serde_json::to_string_pretty(&profiler.to_json(memory)).unwrap();
+                save_report(path, "profile", json);
+            }
+            None => print!("{}", profiler.report(memory)),
+        }
+    }
+
+    if let Some(coverage) = instruments.coverage.as_ref() {
+        let listing = option_value(args, "--coverage");
+        if let Some(path) = listing {
+            save_report(path, "coverage listing", coverage.listing(memory));
+        }
+
+        // lcov is keyed to source lines when there are symbols, otherwise to the listing
+        if let Some(lcov_path) = option_value(args, "--lcov") {
+            let lcov = match (symbols, listing) {
+                (Some(symbols), _) => coverage.lcov_source(memory, symbols),
+                (None, Some(path)) => coverage.lcov(memory, path),
+                (None, None) => return,
+            };
+            save_report(lcov_path, "lcov report", lcov);
+        }
+    }
+}
+
+fn save_report(path: &str, description: &str, contents: String) {
+    match fs::write(path, contents) {
+        Ok(()) => println!("saved {} to {}", description, path),
+        Err(error) => eprintln!("failed to save {}: {}", description, error),
+    }
+}
+
+/// Loads `--symbols=<path>`, otherwise takes the symbols from compiling the ROM if it was
+/// source, or loads `<rom>.sym` when it exists next to the ROM
+fn load_symbols(
+    args: &[String],
+    filename: &str,
+    compiled: Option<Symbols>,
+) -> Result<Option<Symbols>, String> {
+    let path = match option_value(args, "--symbols") {
+        Some(path) => PathBuf::from(path),
+        None if compiled.is_some() => return Ok(compiled),
+        None => {
+            let path = Path::new(filename).with_extension("sym");
+            if !path.exists() {
+                return Ok(None);
+            }
+            path
+        }
+    };
+
+    let text = fs::read_to_string(&path)
+        .map_err(|error| format!("failed to read {}: {}", path.display(), error))?;
+    Symbols::parse(&text)
+        .map(Some)
+        .map_err(|error| format!("invalid symbols in {}: {}", path.display(), error))
+}
+
+/// An execution error, with the label and source line it happened at when symbols are loaded
+fn describe_error(error: &ExecError, symbols: Option<&Symbols>) -> String {
+    match symbols {
+        Some(symbols) => format!("{} in {}", error, symbols.locate(error.address())),
+        None => error.to_string(),
+    }
+}
+
+/// Opens the `--trace` log, applying any `--trace-range=` filters and `--trace-ring=` size
+fn create_tracer(args: &[String], path: &str, symbols: Option<Symbols>) -> Result<Tracer, String> {
+    let ranges = option_values(args, "--trace-range")
+        .into_iter()
+        .map(trace::parse_range)
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(|error| format!("invalid --trace-range: {}", error))?;
+
+    let ring_size = match option_value(args, "--trace-ring").map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(size)) if size > 0 => Some(size),
+        Some(_) => return Err(String::from("--trace-ring must be a positive number")),
+    };
+
+    Tracer::create(Path::new(path), ranges, ring_size, symbols)
+        .map_err(|error| format!("failed to open trace log: {}", error))
+}
+
+/// Finds an unused `<rom name>-<n>.<extension>` in the working directory
+fn next_output_path(filename: &str, extension: &str) -> PathBuf {
+    let stem = Path::new(filename)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("chip8");
+
+    (1..)
+        .map(|n| PathBuf::from(format!("{}-{}.{}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Returns the value of a `--name value` or `--name=value` style option
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|index| args.get(index + 1))
+        .map(String::as_str)
+        .or_else(|| flag_value(args, name))
+}
+
+/// Returns the value of a `--name=value` style flag
+fn flag_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .filter_map(|arg| arg.strip_prefix(name))
+        .find_map(|rest| rest.strip_prefix('='))
+}
+
+/// Returns every value of an option that can be given more than once, in either style
+fn option_values<'a>(args: &'a [String], name: &str) -> Vec<&'a str> {
+    args.iter()
+        .enumerate()
+        .filter_map(|(index, arg)| match arg.strip_prefix(name) {
+            Some("") => args.get(index + 1).map(String::as_str),
+            Some(rest) => rest.strip_prefix('='),
+            None => None,
+        })
+        .collect()
 }
 
 fn pressed_scancode_set(event_pump: &sdl2::EventPump) -> HashSet<Scancode> {
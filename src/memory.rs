@@ -1,6 +1,18 @@
+use crate::display_constants;
+use crate::quirks::Quirks;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::fmt;
+
 pub const INTERPRETER_SIZE: usize = 0x200;
 
-#[derive(Debug)]
+/// 4 KiB, as on the COSMAC VIP
+pub const RAM_SIZE: usize = 0x1000;
+
+/// XO-CHIP programs get the whole 16-bit address space
+pub const XO_CHIP_RAM_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Memory {
     pub delay: u8,
     pub sound: u8,
@@ -10,5 +22,147 @@ pub struct Memory {
     pub registers: [u8; 16],
     pub stack: [u16; 16],
     pub display: [[u8; 64]; 32],
-    pub ram: [u8; 0xFFF],
+    pub ram: Vec<u8>,
+    pub quirks: Quirks,
+    /// Instructions executed per 60Hz frame
+    pub speed: usize,
+    /// Source for `RND`, seeded so runs can be reproduced
+    pub rng: StdRng,
+}
+
+impl Memory {
+    /// Creates a blank machine with the font loaded and the program counter at the start of the program area
+    pub fn new() -> Memory {
+        let mut memory = Memory {
+            delay: 0,
+            sound: 0,
+            stack_pointer: 0,
+            program_counter: INTERPRETER_SIZE as u16,
+            i: 0,
+            registers: [0; 16],
+            stack: [0; 16],
+            display: [[0; 64]; 32],
+            ram: vec![0; RAM_SIZE],
+            quirks: Quirks::default(),
+            speed: 20,
+            rng: StdRng::from_entropy(),
+        };
+
+        memory.ram[0..5].clone_from_slice(&display_constants::ZERO);
+        memory.ram[5..10].clone_from_slice(&display_constants::ONE);
+        memory.ram[10..15].clone_from_slice(&display_constants::TWO);
+        memory.ram[15..20].clone_from_slice(&display_constants::THREE);
+        memory.ram[20..25].clone_from_slice(&display_constants::FOUR);
+        memory.ram[25..30].clone_from_slice(&display_constants::FIVE);
+        memory.ram[30..35].clone_from_slice(&display_constants::SIX);
+        memory.ram[35..40].clone_from_slice(&display_constants::SEVEN);
+        memory.ram[40..45].clone_from_slice(&display_constants::EIGHT);
+        memory.ram[45..50].clone_from_slice(&display_constants::NINE);
+        memory.ram[50..55].clone_from_slice(&display_constants::A);
+        memory.ram[55..60].clone_from_slice(&display_constants::B);
+        memory.ram[60..65].clone_from_slice(&display_constants::C);
+        memory.ram[65..70].clone_from_slice(&display_constants::D);
+        memory.ram[70..75].clone_from_slice(&display_constants::E);
+        memory.ram[75..80].clone_from_slice(&display_constants::F);
+
+        memory
+    }
+
+    /// Copies a program into ram after the interpreter area
+    pub fn load_rom(&mut self, contents: &[u8]) -> Result<(), LoadError> {
+        self.load_rom_at(INTERPRETER_SIZE, contents)
+    }
+
+    /// Copies a program into ram at `start`, for interpreters that load programs elsewhere
+    pub fn load_rom_at(&mut self, start: usize, contents: &[u8]) -> Result<(), LoadError> {
+        if contents.is_empty() {
+            return Err(LoadError::Empty);
+        }
+        let end = start + contents.len();
+        if end > self.ram.len() {
+            return Err(LoadError::TooLarge {
+                size: contents.len(),
+                start,
+                ram_size: self.ram.len(),
+            });
+        }
+
+        self.ram[start..end].clone_from_slice(contents);
+        Ok(())
+    }
+
+    /// Counts both timers down by one, called once per 60Hz frame
+    pub fn tick_timers(&mut self) {
+        if self.delay > 0 {
+            self.delay -= 1;
+        }
+
+        if self.sound > 0 {
+            self.sound -= 1;
+        }
+    }
+}
+
+/// Why a ROM couldn't be loaded
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    Empty,
+    /// `size` bytes don't fit between `start` and the end of `ram_size` bytes of ram
+    TooLarge {
+        size: usize,
+        start: usize,
+        ram_size: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Empty => write!(f, "the file is empty"),
+            LoadError::TooLarge {
+                size,
+                start,
+                ram_size,
+            } => {
+                write!(
+                    f,
+                    "{} bytes is too big, only {} fit at 0x{:03X} in {} KiB of memory",
+                    size,
+                    ram_size - start,
+                    start,
+                    ram_size / 1024
+                )?;
+                if *ram_size < XO_CHIP_RAM_SIZE && start + size <= XO_CHIP_RAM_SIZE {
+                    write!(f, ", XO-CHIP programs get 64 KiB with --quirks xochip")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_rom() {
+        let mut memory = Memory::new();
+        assert_eq!(memory.load_rom(&[]), Err(LoadError::Empty));
+
+        // Everything up to the last byte of 4 KiB
+        let rom = vec![0xAA; RAM_SIZE - INTERPRETER_SIZE];
+        memory.load_rom(&rom).unwrap();
+        assert_eq!(memory.ram[0xFFF], 0xAA);
+
+        let error = memory.load_rom(&[0; 3585]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "3585 bytes is too big, only 3584 fit at 0x200 in 4 KiB of memory, \
+             XO-CHIP programs get 64 KiB with --quirks xochip"
+        );
+
+        memory.ram.resize(XO_CHIP_RAM_SIZE, 0);
+        memory.load_rom(&[0; 3585]).unwrap();
+    }
 }
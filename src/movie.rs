@@ -0,0 +1,115 @@
+use crate::interpreter::KEY_MAP;
+use sdl2::keyboard::Keycode;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Keypad input recorded frame by frame, so a run can be replayed headless.
+///
+/// Stored as text with one line per frame listing the pressed CHIP-8 keys as hex digits,
+/// or `.` when nothing is pressed. Lines starting with `#` are ignored.
+#[derive(Debug, Default, PartialEq)]
+pub struct Movie {
+    /// Bitmask of pressed keys for each frame, bit n set when key n is held
+    pub frames: Vec<u16>,
+}
+
+impl Movie {
+    pub fn parse(text: &str) -> Result<Movie, String> {
+        let mut frames = Vec::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut keys = 0u16;
+            if line != "." {
+                for digit in line.chars() {
+                    let key = digit
+                        .to_digit(16)
+                        .ok_or_else(|| format!("line {}: '{}' is not a key", number + 1, digit))?;
+                    keys |= 1 << key;
+                }
+            }
+            frames.push(keys);
+        }
+
+        Ok(Movie { frames })
+    }
+
+    /// Records which keypad keys are held this frame
+    pub fn record(&mut self, pressed_keys: &HashSet<Keycode>) {
+        let keys = KEY_MAP
+            .iter()
+            .enumerate()
+            .filter(|(_, keycode)| pressed_keys.contains(keycode))
+            .fold(0u16, |keys, (key, _)| keys | 1 << key);
+
+        self.frames.push(keys);
+    }
+
+    /// The held and newly pressed keys for `frame`, in the form the interpreter expects
+    pub fn keys(&self, frame: usize) -> (HashSet<Keycode>, HashSet<Keycode>) {
+        let current = self.frames.get(frame).copied().unwrap_or(0);
+        let previous = match frame {
+            0 => 0,
+            _ => self.frames.get(frame - 1).copied().unwrap_or(0),
+        };
+
+        (to_keycodes(current), to_keycodes(current & !previous))
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &keys in self.frames.iter() {
+            if keys == 0 {
+                writeln!(f, ".")?;
+                continue;
+            }
+
+            for key in 0..16 {
+                if keys & (1 << key) != 0 {
+                    write!(f, "{:X}", key)?;
+                }
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn to_keycodes(keys: u16) -> HashSet<Keycode> {
+    (0..16)
+        .filter(|key| keys & (1 << key) != 0)
+        .map(|key| KEY_MAP[key])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let movie = Movie::parse("# test\n.\n5\n4A\n").unwrap();
+        assert_eq!(movie.frames, vec![0, 0b10_0000, 0b100_0001_0000]);
+        assert_eq!(movie.to_string(), ".\n5\n4A\n");
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        assert!(Movie::parse("5\nG\n").is_err());
+    }
+
+    #[test]
+    fn test_new_keys() {
+        let movie = Movie::parse("5\n56\n").unwrap();
+        let (pressed, new) = movie.keys(1);
+
+        assert_eq!(pressed.len(), 2);
+        assert_eq!(new, HashSet::from([KEY_MAP[6]]));
+    }
+}
@@ -0,0 +1,1090 @@
+use crate::assembler::Program;
+use crate::symbols::SourceLine;
+use crate::symbols::Symbols;
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+/// Where programs are loaded, starting with a jump to `main`
+const START: usize = 0x200;
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: u32,
+}
+
+struct Macro {
+    parameters: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Somewhere a label is used before it's defined, filled in once every label is known
+struct Fixup {
+    address: usize,
+    name: String,
+    line: u32,
+    kind: Reference,
+}
+
+/// How an address is written into the instructions that refer to it
+#[derive(Clone, Copy)]
+enum Reference {
+    /// The low 12 bits of an instruction, as in `jump`
+    Address,
+    /// The 16-bit word after `i := long`
+    Long,
+    /// The bytes of the two `:=` that `:unpack` emits, with the nibble for the top of v0
+    /// unless it's `:unpack long`
+    Unpack(Option<u8>),
+}
+
+/// An address operand, which can be a label that's defined further down
+enum Target {
+    Known(usize),
+    Later(String),
+}
+
+/// Structures waiting for the word that closes them, with the address of the jump to fill in
+enum Block {
+    Begin(usize),
+    Else(usize),
+    Loop { start: usize, breaks: Vec<usize> },
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+    Key,
+    NotKey,
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Byte(u8),
+}
+
+#[derive(Clone, Copy)]
+struct Condition {
+    register: usize,
+    comparison: Comparison,
+    /// What the register is compared with, nothing for `key` and `-key`
+    operand: Option<Operand>,
+}
+
+impl Condition {
+    fn negate(self) -> Condition {
+        use Comparison::*;
+        let comparison = match self.comparison {
+            Equal => NotEqual,
+            NotEqual => Equal,
+            Less => GreaterOrEqual,
+            GreaterOrEqual => Less,
+            Greater => LessOrEqual,
+            LessOrEqual => Greater,
+            Key => NotKey,
+            NotKey => Key,
+        };
+        Condition { comparison, ..self }
+    }
+}
+
+/// Compiles Octo, the assembly language most CHIP-8 programs are written in nowadays, into a
+/// ROM with symbols for every label and the source line of every instruction:
+///
+/// ```text
+/// : main
+///     i := ball
+///     loop
+///         sprite v0 v1 3
+///         v0 += 1
+///         if v0 == 60 then v0 := 0
+///     again
+/// : ball
+///     0x40 0xE0 0x40
+/// ```
+///
+/// Supports the instructions of CHIP-8, SUPER-CHIP and XO-CHIP, `if`, `loop` and `while`,
+/// and the `:alias`, `:const`, `:calc`, `:macro`, `:byte`, `:org`, `:unpack` and `:next`
+/// directives. `:calc` evaluates right to left with no precedence, as Octo does.
+pub fn compile(source: &str, file: &str) -> Result<Program, String> {
+    let mut compiler = Compiler {
+        file,
+        tokens: tokenize(source),
+        line: 0,
+        // Filled in with the jump to main at the end
+        rom: vec![0x10, 0x00],
+        here: START + 2,
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        blocks: Vec::new(),
+        symbols: Symbols::default(),
+    };
+
+    while let Some(token) = compiler.tokens.pop_front() {
+        compiler.line = token.line;
+        compiler
+            .statement(token)
+            .map_err(|message| format!("{}:{}: {}", file, compiler.line, message))?;
+    }
+
+    if let Some((block, line)) = compiler.blocks.last() {
+        let message = match block {
+            Block::Begin(_) | Block::Else(_) => "begin is never closed with end",
+            Block::Loop { .. } => "loop is never closed with again",
+        };
+        return Err(format!("{}:{}: {}", file, line, message));
+    }
+
+    if !compiler.labels.contains_key("main") {
+        return Err(format!(
+            "{}: there's no main label to start the program at",
+            file
+        ));
+    }
+    compiler.fixups.push(Fixup {
+        address: START,
+        name: String::from("main"),
+        line: 0,
+        kind: Reference::Address,
+    });
+    for fixup in &compiler.fixups {
+        let address = *compiler
+            .labels
+            .get(&fixup.name)
+            .ok_or_else(|| format!("{}:{}: undefined label {}", file, fixup.line, fixup.name))?;
+        fill(&mut compiler.rom, fixup.kind, fixup.address, address)
+            .map_err(|message| format!("{}:{}: {}", file, fixup.line, message))?;
+    }
+
+    Ok(Program {
+        rom: compiler.rom,
+        symbols: compiler.symbols,
+    })
+}
+
+/// Splits the source into words, dropping `#` comments
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (number, text) in source.lines().enumerate() {
+        let text = text.split('#').next().unwrap_or("");
+        tokens.extend(text.split_whitespace().map(|word| Token {
+            text: word.to_string(),
+            line: number as u32 + 1,
+        }));
+    }
+    tokens
+}
+
+struct Compiler<'a> {
+    file: &'a str,
+    /// What's left to compile, with macros expanded in place at the front
+    tokens: VecDeque<Token>,
+    /// The line of the last token taken, for errors and symbols
+    line: u32,
+    /// Everything from `START` up to the highest address written
+    rom: Vec<u8>,
+    here: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, usize>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    /// Open structures, innermost last, with the line each was opened on
+    blocks: Vec<(Block, u32)>,
+    symbols: Symbols,
+}
+
+impl Compiler<'_> {
+    fn statement(&mut self, token: Token) -> Result<(), String> {
+        if let Some(register) = self.register(&token.text) {
+            return self.assignment(register);
+        }
+        if self.macros.contains_key(&token.text) {
+            return self.expand(&token.text);
+        }
+
+        match token.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                // Labels the second byte of the next instruction, for code that rewrites it
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let token = self.next()?;
+                let register = match token.text.as_str() {
+                    "{" => {
+                        let value = self.calc()?;
+                        Some(value as usize).filter(|_| (0.0..16.0).contains(&value))
+                    }
+                    text => self.register(text),
+                }
+                .ok_or_else(|| format!("{} isn't a register", token.text))?;
+                self.aliases.insert(name, register);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let token = self.next()?;
+                let value = self
+                    .value(&token.text)
+                    .ok_or_else(|| format!("expected a number, got '{}'", token.text))?;
+                self.constants.insert(name, value);
+            }
+            ":calc" => {
+                let name = self.name()?;
+                self.expect("{")?;
+                let value = self.calc()?;
+                self.constants.insert(name, value);
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit(&[byte])?;
+            }
+            ":org" => {
+                let token = self.next()?;
+                let address = self.number_of(&token)?;
+                if !(START as f64..=0xFFFF as f64).contains(&address) {
+                    return Err(format!(
+                        ":org 0x{:X} is outside of 200-FFFF",
+                        address as i64
+                    ));
+                }
+                self.here = address as usize;
+            }
+            ":unpack" => {
+                let token = self.next()?;
+                let nibble = match token.text.as_str() {
+                    "long" => None,
+                    _ => Some(self.nibble_of(&token)?),
+                };
+                let target = self.target()?;
+                let at = self.here;
+                self.instruction(0x6000)?;
+                self.instruction(0x6100)?;
+                self.refer(target, Reference::Unpack(nibble), at)?;
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut parameters = Vec::new();
+                loop {
+                    let token = self.next()?;
+                    if token.text == "{" {
+                        break;
+                    }
+                    parameters.push(token.text);
+                }
+                let body = self.braced()?;
+                self.macros.insert(name, Macro { parameters, body });
+            }
+            // Hints for Octo's debugger, which don't change the program
+            ":breakpoint" | ":proto" => {
+                self.next()?;
+            }
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            }
+            "return" | ";" => self.instruction(0x00EE)?,
+            "clear" => self.instruction(0x00E0)?,
+            "scroll-right" => self.instruction(0x00FB)?,
+            "scroll-left" => self.instruction(0x00FC)?,
+            "exit" => self.instruction(0x00FD)?,
+            "lores" => self.instruction(0x00FE)?,
+            "hires" => self.instruction(0x00FF)?,
+            "audio" => self.instruction(0xF002)?,
+            "scroll-down" => {
+                let nibble = self.nibble()?;
+                self.instruction(0x00C0 | nibble as u16)?;
+            }
+            "scroll-up" => {
+                let nibble = self.nibble()?;
+                self.instruction(0x00D0 | nibble as u16)?;
+            }
+            "plane" => {
+                let planes = self.nibble()?;
+                if planes > 3 {
+                    return Err(format!("there are only planes 0 to 3, not {}", planes));
+                }
+                self.instruction(0xF001 | (planes as u16) << 8)?;
+            }
+            "bcd" => self.register_instruction(0xF033)?,
+            "saveflags" => self.register_instruction(0xF075)?,
+            "loadflags" => self.register_instruction(0xF085)?,
+            "save" | "load" => {
+                let x = self.register_operand()?;
+                let save = token.text == "save";
+                match self.tokens.front() {
+                    Some(next) if next.text == "-" => {
+                        self.next()?;
+                        let y = self.register_operand()?;
+                        let low = if save { 0x2 } else { 0x3 };
+                        self.instruction(0x5000 | (x as u16) << 8 | (y as u16) << 4 | low)?;
+                    }
+                    _ => {
+                        let low = if save { 0x55 } else { 0x65 };
+                        self.instruction(0xF000 | (x as u16) << 8 | low)?;
+                    }
+                }
+            }
+            "sprite" => {
+                let x = self.register_operand()?;
+                let y = self.register_operand()?;
+                let height = self.nibble()?;
+                self.instruction(0xD000 | (x as u16) << 8 | (y as u16) << 4 | height as u16)?;
+            }
+            "jump" => self.address_instruction(0x1000)?,
+            "jump0" => self.address_instruction(0xB000)?,
+            "native" => self.address_instruction(0x0000)?,
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let low = match token.text.as_str() {
+                    "delay" => 0x15,
+                    "buzzer" => 0x18,
+                    _ => 0x3A,
+                };
+                self.register_instruction(0xF000 | low)?;
+            }
+            "i" => {
+                let operator = self.next()?;
+                match operator.text.as_str() {
+                    "+=" => self.register_instruction(0xF01E)?,
+                    ":=" => match self.tokens.front().map(|token| token.text.as_str()) {
+                        Some("hex") => {
+                            self.next()?;
+                            self.register_instruction(0xF029)?;
+                        }
+                        Some("bighex") => {
+                            self.next()?;
+                            self.register_instruction(0xF030)?;
+                        }
+                        Some("long") => {
+                            self.next()?;
+                            let target = self.target()?;
+                            self.instruction(0xF000)?;
+                            let at = self.here;
+                            self.emit(&[0, 0])?;
+                            self.refer(target, Reference::Long, at)?;
+                        }
+                        _ => self.address_instruction(0xA000)?,
+                    },
+                    text => return Err(format!("expected := or += after i, got '{}'", text)),
+                }
+            }
+            "if" => {
+                let condition = self.condition()?;
+                let token = self.next()?;
+                match token.text.as_str() {
+                    "then" => self.skip_unless(condition)?,
+                    "begin" => {
+                        // Jump past the body unless the condition holds
+                        self.skip_unless(condition.negate())?;
+                        self.blocks.push((Block::Begin(self.here), self.line));
+                        self.instruction(0x1000)?;
+                    }
+                    text => return Err(format!("expected then or begin, got '{}'", text)),
+                }
+            }
+            "else" => match self.blocks.pop() {
+                Some((Block::Begin(jump), line)) => {
+                    self.blocks.push((Block::Else(self.here), line));
+                    self.instruction(0x1000)?;
+                    fill(&mut self.rom, Reference::Address, jump, self.here)?;
+                }
+                _ => return Err(String::from("else without if ... begin")),
+            },
+            "end" => match self.blocks.pop() {
+                Some((Block::Begin(jump) | Block::Else(jump), _)) => {
+                    fill(&mut self.rom, Reference::Address, jump, self.here)?;
+                }
+                _ => return Err(String::from("end without if ... begin")),
+            },
+            "loop" => {
+                let start = self.here;
+                self.blocks.push((
+                    Block::Loop {
+                        start,
+                        breaks: Vec::new(),
+                    },
+                    self.line,
+                ));
+            }
+            "while" => {
+                if !self
+                    .blocks
+                    .iter()
+                    .any(|(block, _)| matches!(block, Block::Loop { .. }))
+                {
+                    return Err(String::from("while outside of a loop"));
+                }
+                let condition = self.condition()?;
+                self.skip_unless(condition.negate())?;
+                let jump = self.here;
+                self.instruction(0x1000)?;
+                if let Some((Block::Loop { breaks, .. }, _)) = self
+                    .blocks
+                    .iter_mut()
+                    .rev()
+                    .find(|(block, _)| matches!(block, Block::Loop { .. }))
+                {
+                    breaks.push(jump);
+                }
+            }
+            "again" => match self.blocks.pop() {
+                Some((Block::Loop { start, breaks }, _)) => {
+                    let at = self.here;
+                    self.instruction(0x1000)?;
+                    fill(&mut self.rom, Reference::Address, at, start)?;
+                    for jump in breaks {
+                        fill(&mut self.rom, Reference::Address, jump, self.here)?;
+                    }
+                }
+                _ => return Err(String::from("again without loop")),
+            },
+            text if text.starts_with(':') => return Err(format!("unknown directive {}", text)),
+            text => {
+                // A label on its own calls it, a number on its own is a byte of data
+                if !self.labels.contains_key(text) {
+                    if let Some(value) = self.value(text) {
+                        let byte = to_byte(value)?;
+                        return self.emit(&[byte]);
+                    }
+                }
+                if !is_name(text) {
+                    return Err(format!("unexpected '{}'", text));
+                }
+                let target = self.target_of(text);
+                let at = self.here;
+                self.instruction(0x2000)?;
+                self.refer(target, Reference::Address, at)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `vx := ...`, `vx += ...` and the other operators on a register
+    fn assignment(&mut self, x: usize) -> Result<(), String> {
+        let operator = self.next()?;
+        let token = self.next()?;
+        let x = (x as u16) << 8;
+
+        let opcode = match (operator.text.as_str(), self.register(&token.text)) {
+            (":=", Some(y)) => 0x8000 | x | (y as u16) << 4,
+            (":=", None) => match token.text.as_str() {
+                "random" => 0xC000 | x | self.byte()? as u16,
+                "key" => 0xF00A | x,
+                "delay" => 0xF007 | x,
+                _ => 0x6000 | x | self.byte_of(&token)? as u16,
+            },
+            ("+=", Some(y)) => 0x8004 | x | (y as u16) << 4,
+            ("+=", None) => 0x7000 | x | self.byte_of(&token)? as u16,
+            ("-=", Some(y)) => 0x8005 | x | (y as u16) << 4,
+            ("-=", None) => 0x7000 | x | self.byte_of(&token)?.wrapping_neg() as u16,
+            ("|=", Some(y)) => 0x8001 | x | (y as u16) << 4,
+            ("&=", Some(y)) => 0x8002 | x | (y as u16) << 4,
+            ("^=", Some(y)) => 0x8003 | x | (y as u16) << 4,
+            (">>=", Some(y)) => 0x8006 | x | (y as u16) << 4,
+            ("=-", Some(y)) => 0x8007 | x | (y as u16) << 4,
+            ("<<=", Some(y)) => 0x800E | x | (y as u16) << 4,
+            (operator, _) => {
+                return Err(format!(
+                    "can't use {} with '{}' on a register",
+                    operator, token.text
+                ))
+            }
+        };
+        self.instruction(opcode)
+    }
+
+    /// Reads `register comparison operand`, as `if` and `while` take them
+    fn condition(&mut self) -> Result<Condition, String> {
+        use Comparison::*;
+        let register = self.register_operand()?;
+        let token = self.next()?;
+        let comparison = match token.text.as_str() {
+            "==" => Equal,
+            "!=" => NotEqual,
+            "<" => Less,
+            ">" => Greater,
+            "<=" => LessOrEqual,
+            ">=" => GreaterOrEqual,
+            "key" | "-key" => {
+                return Ok(Condition {
+                    register,
+                    comparison: if token.text == "key" { Key } else { NotKey },
+                    operand: None,
+                })
+            }
+            text => return Err(format!("expected a comparison, got '{}'", text)),
+        };
+
+        let token = self.next()?;
+        let operand = match self.register(&token.text) {
+            Some(register) => Operand::Register(register),
+            None => Operand::Byte(self.byte_of(&token)?),
+        };
+        Ok(Condition {
+            register,
+            comparison,
+            operand: Some(operand),
+        })
+    }
+
+    /// Emits a skip so that the next instruction only runs when `condition` holds. The
+    /// orderings go through vf, which `vf -= vy` and `vf =- vx` leave at 1 when there's no borrow.
+    fn skip_unless(&mut self, condition: Condition) -> Result<(), String> {
+        use Comparison::*;
+        let x = (condition.register as u16) << 8;
+        let opcode = match (condition.comparison, condition.operand) {
+            (Key, _) => 0xE0A1 | x,
+            (NotKey, _) => 0xE09E | x,
+            (Equal, Some(Operand::Byte(byte))) => 0x4000 | x | byte as u16,
+            (NotEqual, Some(Operand::Byte(byte))) => 0x3000 | x | byte as u16,
+            (Equal, Some(Operand::Register(y))) => 0x9000 | x | (y as u16) << 4,
+            (NotEqual, Some(Operand::Register(y))) => 0x5000 | x | (y as u16) << 4,
+            (comparison, Some(operand)) => {
+                // vf ends up as whether the left side is at least the right
+                let swapped = matches!(comparison, Greater | LessOrEqual);
+                let vx = condition.register as u16;
+                let setup = match (swapped, operand) {
+                    (false, Operand::Register(y)) => [0x8F00 | vx << 4, 0x8F05 | (y as u16) << 4],
+                    (false, Operand::Byte(byte)) => [0x6F00 | byte as u16, 0x8F07 | vx << 4],
+                    (true, Operand::Register(y)) => [0x8F00 | (y as u16) << 4, 0x8F05 | vx << 4],
+                    (true, Operand::Byte(byte)) => [0x6F00 | byte as u16, 0x8F05 | vx << 4],
+                };
+                for opcode in setup {
+                    self.instruction(opcode)?;
+                }
+                match comparison {
+                    Less | Greater => 0x4F00,
+                    _ => 0x4F01,
+                }
+            }
+            (_, None) => unreachable!("only key conditions have no operand"),
+        };
+        self.instruction(opcode)
+    }
+
+    /// Replaces a macro's name with its body, its parameters replaced by the words after it
+    fn expand(&mut self, name: &str) -> Result<(), String> {
+        let count = self.macros[name].parameters.len();
+        let mut arguments = Vec::new();
+        for _ in 0..count {
+            arguments.push(self.next()?.text);
+        }
+
+        let definition = &self.macros[name];
+        for token in definition.body.iter().rev() {
+            let text = match definition
+                .parameters
+                .iter()
+                .position(|parameter| *parameter == token.text)
+            {
+                Some(index) => arguments[index].clone(),
+                None => token.text.clone(),
+            };
+            self.tokens.push_front(Token {
+                text,
+                line: token.line,
+            });
+        }
+        Ok(())
+    }
+
+    /// Takes the words up to the `}` matching a `{` that was just taken
+    fn braced(&mut self) -> Result<Vec<Token>, String> {
+        let mut tokens = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = self.next()?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(tokens),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    /// Evaluates a `:calc` style expression after its `{`
+    fn calc(&mut self) -> Result<f64, String> {
+        let tokens: Vec<String> = self.braced()?.into_iter().map(|token| token.text).collect();
+        let mut position = 0;
+        let value = self.expression(&tokens, &mut position)?;
+        match tokens.get(position) {
+            Some(token) => Err(format!("unexpected '{}' in expression", token)),
+            None => Ok(value),
+        }
+    }
+
+    fn expression(&self, tokens: &[String], position: &mut usize) -> Result<f64, String> {
+        let left = self.term(tokens, position)?;
+        let operator = match tokens.get(*position) {
+            None => return Ok(left),
+            Some(token) if token == ")" => return Ok(left),
+            Some(token) => token,
+        };
+        *position += 1;
+        // Everything to the right first, as Octo does
+        let right = self.expression(tokens, position)?;
+
+        let (a, b) = (left as i64, right as i64);
+        let shift = |shift: fn(i64, u32) -> Option<i64>| {
+            u32::try_from(b)
+                .ok()
+                .and_then(|b| shift(a, b))
+                .map(|value| value as f64)
+                .ok_or_else(|| format!("shift by {} out of range", b))
+        };
+        let value = match operator.as_str() {
+            "+" => left + right,
+            "-" => left - right,
+            "*" => left * right,
+            "/" => left / right,
+            "%" => left % right,
+            "pow" => left.powf(right),
+            "min" => left.min(right),
+            "max" => left.max(right),
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => shift(i64::checked_shl)?,
+            ">>" => shift(i64::checked_shr)?,
+            "<" => (left < right) as u8 as f64,
+            ">" => (left > right) as u8 as f64,
+            "<=" => (left <= right) as u8 as f64,
+            ">=" => (left >= right) as u8 as f64,
+            "==" => (left == right) as u8 as f64,
+            "!=" => (left != right) as u8 as f64,
+            operator => return Err(format!("unknown operator '{}'", operator)),
+        };
+        Ok(value)
+    }
+
+    fn term(&self, tokens: &[String], position: &mut usize) -> Result<f64, String> {
+        let token = tokens
+            .get(*position)
+            .ok_or_else(|| String::from("expression ends early"))?;
+        *position += 1;
+
+        let unary = |function: fn(f64) -> f64, position: &mut usize| {
+            self.term(tokens, position).map(function)
+        };
+        match token.as_str() {
+            "(" => {
+                let value = self.expression(tokens, position)?;
+                match tokens.get(*position) {
+                    Some(token) if token == ")" => {
+                        *position += 1;
+                        Ok(value)
+                    }
+                    _ => Err(String::from("missing )")),
+                }
+            }
+            "-" => unary(|value| -value, position),
+            "~" => unary(|value| !(value as i64) as f64, position),
+            "!" => unary(|value| (value == 0.0) as u8 as f64, position),
+            "abs" => unary(f64::abs, position),
+            "sqrt" => unary(f64::sqrt, position),
+            "floor" => unary(f64::floor, position),
+            "ceil" => unary(f64::ceil, position),
+            "sin" => unary(f64::sin, position),
+            "cos" => unary(f64::cos, position),
+            "tan" => unary(f64::tan, position),
+            "exp" => unary(f64::exp, position),
+            "log" => unary(f64::ln, position),
+            "sign" => unary(f64::signum, position),
+            "@" => {
+                // A byte already compiled into the ROM
+                let address = self.term(tokens, position)? as usize;
+                let byte = address
+                    .checked_sub(START)
+                    .and_then(|index| self.rom.get(index));
+                Ok(byte.copied().unwrap_or(0) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            text => self
+                .value(text)
+                .ok_or_else(|| format!("unknown name '{}' in expression", text)),
+        }
+    }
+
+    /// A number, constant or label defined so far
+    fn value(&self, text: &str) -> Option<f64> {
+        number(text)
+            .or_else(|| self.constants.get(text).copied())
+            .or_else(|| self.labels.get(text).map(|&address| address as f64))
+    }
+
+    /// A value, or an expression in braces
+    fn number_of(&mut self, token: &Token) -> Result<f64, String> {
+        match token.text.as_str() {
+            "{" => self.calc(),
+            text => self
+                .value(text)
+                .ok_or_else(|| format!("expected a number, got '{}'", text)),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.byte_of(&token)
+    }
+
+    fn byte_of(&mut self, token: &Token) -> Result<u8, String> {
+        let value = self.number_of(token)?;
+        to_byte(value)
+    }
+
+    fn nibble(&mut self) -> Result<u8, String> {
+        let token = self.next()?;
+        self.nibble_of(&token)
+    }
+
+    fn nibble_of(&mut self, token: &Token) -> Result<u8, String> {
+        let value = self.number_of(token)? as i64;
+        match value {
+            0..=15 => Ok(value as u8),
+            _ => Err(format!("{} doesn't fit in a nibble", value)),
+        }
+    }
+
+    /// v0 to vf, or a name given to one with `:alias`
+    fn register(&self, text: &str) -> Option<usize> {
+        if let Some(&register) = self.aliases.get(text) {
+            return Some(register);
+        }
+        let digit = text.strip_prefix(['v', 'V'])?;
+        match digit.len() {
+            1 => usize::from_str_radix(digit, 16).ok(),
+            _ => None,
+        }
+    }
+
+    fn register_operand(&mut self) -> Result<usize, String> {
+        let token = self.next()?;
+        self.register(&token.text)
+            .ok_or_else(|| format!("expected a register, got '{}'", token.text))
+    }
+
+    /// An instruction with a register in its second nibble, such as `bcd vx`
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), String> {
+        let x = self.register_operand()?;
+        self.instruction(opcode | (x as u16) << 8)
+    }
+
+    /// An instruction with an address in its low 12 bits, such as `jump`
+    fn address_instruction(&mut self, opcode: u16) -> Result<(), String> {
+        let target = self.target()?;
+        let at = self.here;
+        self.instruction(opcode)?;
+        self.refer(target, Reference::Address, at)
+    }
+
+    fn target(&mut self) -> Result<Target, String> {
+        let token = self.next()?;
+        match token.text.as_str() {
+            "{" => Ok(Target::Known(self.calc()? as usize)),
+            text if self.value(text).is_some() || is_name(text) => Ok(self.target_of(text)),
+            text => Err(format!("expected an address, got '{}'", text)),
+        }
+    }
+
+    fn target_of(&self, text: &str) -> Target {
+        match self.value(text) {
+            Some(value) => Target::Known(value as usize),
+            None => Target::Later(text.to_string()),
+        }
+    }
+
+    /// Writes `target` into the instructions at `at` now, or once it's defined
+    fn refer(&mut self, target: Target, kind: Reference, at: usize) -> Result<(), String> {
+        match target {
+            Target::Known(address) => fill(&mut self.rom, kind, at, address),
+            Target::Later(name) => {
+                self.fixups.push(Fixup {
+                    address: at,
+                    name,
+                    line: self.line,
+                    kind,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn define_label(&mut self, name: String, address: usize) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return Err(format!("label {} is defined twice", name));
+        }
+        self.symbols.insert_label(address as u16, &name);
+        self.labels.insert(name, address);
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let token = self.next()?;
+        match is_name(&token.text) && self.register(&token.text).is_none() {
+            true => Ok(token.text),
+            false => Err(format!("'{}' can't be used as a name", token.text)),
+        }
+    }
+
+    fn expect(&mut self, text: &str) -> Result<(), String> {
+        let token = self.next()?;
+        match token.text == text {
+            true => Ok(()),
+            false => Err(format!("expected {}, got '{}'", text, token.text)),
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, String> {
+        let token = self
+            .tokens
+            .pop_front()
+            .ok_or_else(|| String::from("unexpected end of file"))?;
+        self.line = token.line;
+        Ok(token)
+    }
+
+    fn instruction(&mut self, opcode: u16) -> Result<(), String> {
+        self.emit(&opcode.to_be_bytes())
+    }
+
+    /// Writes bytes at the current address, noting the line they came from
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        if self.here + bytes.len() > 0x10000 {
+            return Err(String::from("program runs past 0xFFFF"));
+        }
+        self.symbols.insert_line(
+            self.here as u16,
+            SourceLine {
+                file: self.file.to_string(),
+                line: self.line,
+            },
+        );
+
+        let end = self.here + bytes.len() - START;
+        if self.rom.len() < end {
+            self.rom.resize(end, 0);
+        }
+        self.rom[self.here - START..end].copy_from_slice(bytes);
+        self.here += bytes.len();
+        Ok(())
+    }
+}
+
+/// Writes `address` into the instruction at `at` as `kind` says
+fn fill(rom: &mut [u8], kind: Reference, at: usize, address: usize) -> Result<(), String> {
+    let index = at - START;
+    match kind {
+        Reference::Address | Reference::Unpack(Some(_)) if address > 0xFFF => {
+            return Err(format!("address 0x{:X} is past 0xFFF", address))
+        }
+        Reference::Long if address > 0xFFFF => {
+            return Err(format!("address 0x{:X} is past 0xFFFF", address))
+        }
+        Reference::Address => {
+            rom[index] = (rom[index] & 0xF0) | (address >> 8) as u8;
+            rom[index + 1] = address as u8;
+        }
+        Reference::Long => {
+            rom[index] = (address >> 8) as u8;
+            rom[index + 1] = address as u8;
+        }
+        Reference::Unpack(nibble) => {
+            rom[index + 1] = match nibble {
+                Some(nibble) => nibble << 4 | (address >> 8) as u8,
+                None => (address >> 8) as u8,
+            };
+            rom[index + 3] = address as u8;
+        }
+    }
+    Ok(())
+}
+
+fn to_byte(value: f64) -> Result<u8, String> {
+    let value = value as i64;
+    match value {
+        -128..=255 => Ok(value as u8),
+        _ => Err(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+/// Parses decimal, `0x` hex and `0b` binary numbers, which may be negative
+fn number(text: &str) -> Option<f64> {
+    let (sign, digits) = match text.strip_prefix('-') {
+        Some(digits) => (-1.0, digits),
+        None => (1.0, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()? as f64
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()? as f64
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(sign * value)
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && text
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        compile(source, "game.8o").unwrap().rom
+    }
+
+    #[test]
+    fn test_compile() {
+        let program = compile(
+            ": main\n  i := sprite\n  v0 := 5\n  sprite v0 v1 3 # draw\n  jump main\n: sprite 0x40 0xE0 0x40",
+            "game.8o",
+        )
+        .unwrap();
+
+        assert_eq!(
+            program.rom,
+            [0x12, 0x02, 0xA2, 0x0A, 0x60, 0x05, 0xD0, 0x13, 0x12, 0x02, 0x40, 0xE0, 0x40]
+        );
+        assert_eq!(program.symbols.label(0x20A), Some("sprite"));
+        assert_eq!(
+            program.symbols.source_line(0x206).unwrap().to_string(),
+            "game.8o:4"
+        );
+
+        assert_eq!(
+            rom(": main v1 -= 1 v2 =- v3 i := long main save v1 - v2 draw : draw ;"),
+            [
+                0x12, 0x02, 0x71, 0xFF, 0x82, 0x37, 0xF0, 0x00, 0x02, 0x02, 0x51, 0x22, 0x22, 0x0E,
+                0x00, 0xEE
+            ]
+        );
+    }
+
+    #[test]
+    fn test_control_flow() {
+        assert_eq!(
+            rom(": main if v0 == 3 then v1 := 1 if v2 key then clear"),
+            [0x12, 0x02, 0x40, 0x03, 0x61, 0x01, 0xE2, 0xA1, 0x00, 0xE0]
+        );
+        // vf := 9, vf =- v0 leaves vf at 1 when v0 >= 9
+        assert_eq!(
+            rom(": main if v0 < 9 then clear"),
+            [0x12, 0x02, 0x6F, 0x09, 0x8F, 0x07, 0x4F, 0x00, 0x00, 0xE0]
+        );
+        assert_eq!(
+            rom(": main if v0 != v1 begin clear else return end"),
+            [0x12, 0x02, 0x90, 0x10, 0x12, 0x0A, 0x00, 0xE0, 0x12, 0x0C, 0x00, 0xEE]
+        );
+        assert_eq!(
+            rom(": main loop v0 += 1 while v0 != 10 again"),
+            [0x12, 0x02, 0x70, 0x01, 0x40, 0x0A, 0x12, 0x0A, 0x12, 0x02]
+        );
+    }
+
+    #[test]
+    fn test_comparisons_run() {
+        use crate::interpreter;
+        use crate::memory::Memory;
+        use std::collections::HashSet;
+
+        // Each condition against v0 = 9 and v2 = 9, with whether v1 := 1 should run
+        let cases = [
+            ("v0 < 9", false),
+            ("v0 < 10", true),
+            ("v0 > 8", true),
+            ("v0 > 9", false),
+            ("v0 <= 9", true),
+            ("v0 >= 10", false),
+            ("v0 >= v2", true),
+            ("v0 < v2", false),
+        ];
+        for (condition, expected) in cases {
+            let source = format!(": main v0 := 9 v2 := 9 if {} then v1 := 1", condition);
+            let program = rom(&source);
+            let mut memory = Memory::new();
+            memory.load_rom(&program).unwrap();
+
+            let no_keys = HashSet::new();
+            while (memory.program_counter as usize) < 0x200 + program.len() {
+                interpreter::step(&mut memory, &no_keys, &no_keys).unwrap();
+            }
+            assert_eq!(memory.registers[1], expected as u8, "if {}", condition);
+        }
+    }
+
+    #[test]
+    fn test_directives() {
+        let source = ":alias x v3\n\
+                      :const speed 2\n\
+                      :calc double { speed * 2 + 1 }\n\
+                      :macro step register amount { register += amount }\n\
+                      : main step x double\n\
+                      :unpack 0xA data\n\
+                      :org 0x300 : data :byte { data >> 8 }";
+        let program = rom(source);
+        // double is speed * (2 + 1), evaluating right to left
+        assert_eq!(
+            &program[..8],
+            [0x12, 0x02, 0x73, 0x06, 0x60, 0xA3, 0x61, 0x00]
+        );
+        assert_eq!(program.len(), 0x101);
+        assert_eq!(program[0x100], 0x03);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| compile(source, "game.8o").err().unwrap();
+
+        assert_eq!(
+            error(": main\n  jump nowhere"),
+            "game.8o:2: undefined label nowhere"
+        );
+        assert_eq!(
+            error(": main\nv0 := 300"),
+            "game.8o:2: 300 doesn't fit in a byte"
+        );
+        assert_eq!(
+            error(": start clear"),
+            "game.8o: there's no main label to start the program at"
+        );
+        assert_eq!(
+            error(": main\nloop\nclear"),
+            "game.8o:2: loop is never closed with again"
+        );
+        assert_eq!(error(": main :frob"), "game.8o:1: unknown directive :frob");
+        assert_eq!(
+            error(":calc x { 1 << 64 }"),
+            "game.8o:1: shift by 64 out of range"
+        );
+        assert_eq!(
+            error(":calc x { 1 >> -1 }"),
+            "game.8o:1: shift by -1 out of range"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use sdl2::pixels::Color;
+
+/// Colours used to draw the display, indexed by pixel value.
+///
+/// Index 0 is the background and 1 the foreground. XO-CHIP's second bitplane
+/// uses 2, and 3 is drawn where both planes overlap.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    pub colors: [Color; 4],
+}
+
+impl Palette {
+    const fn new(background: Color, foreground: Color, plane: Color, overlap: Color) -> Palette {
+        Palette {
+            colors: [background, foreground, plane, overlap],
+        }
+    }
+
+    /// Parses a comma separated list of 2 or 4 hex colours, e.g. `000000,FFFFFF`.
+    /// With only 2 colours the XO-CHIP plane colours fall back to the foreground.
+    pub fn parse(value: &str) -> Result<Palette, String> {
+        let colors = value
+            .split(',')
+            .map(parse_color)
+            .collect::<Result<Vec<Color>, String>>()?;
+
+        match colors[..] {
+            [background, foreground] => {
+                Ok(Palette::new(background, foreground, foreground, foreground))
+            }
+            [background, foreground, plane, overlap] => {
+                Ok(Palette::new(background, foreground, plane, overlap))
+            }
+            _ => Err(format!("expected 2 or 4 colours, got {}", colors.len())),
+        }
+    }
+}
+
+/// Built-in themes, cycled through at runtime
+pub const THEMES: [(&str, Palette); 5] = [
+    (
+        "classic",
+        Palette::new(
+            Color::RGB(0, 255, 255),
+            Color::RGB(0, 0, 0),
+            Color::RGB(0, 0, 255),
+            Color::RGB(255, 255, 255),
+        ),
+    ),
+    (
+        "green",
+        Palette::new(
+            Color::RGB(0x0C, 0x1A, 0x0C),
+            Color::RGB(0x33, 0xFF, 0x66),
+            Color::RGB(0x1A, 0x99, 0x3D),
+            Color::RGB(0xB3, 0xFF, 0xC6),
+        ),
+    ),
+    (
+        "amber",
+        Palette::new(
+            Color::RGB(0x1A, 0x0F, 0x00),
+            Color::RGB(0xFF, 0xB0, 0x00),
+            Color::RGB(0x99, 0x66, 0x00),
+            Color::RGB(0xFF, 0xDD, 0x88),
+        ),
+    ),
+    (
+        "lcd",
+        Palette::new(
+            Color::RGB(0x9B, 0xBC, 0x0F),
+            Color::RGB(0x0F, 0x38, 0x0F),
+            Color::RGB(0x30, 0x62, 0x30),
+            Color::RGB(0x8B, 0xAC, 0x0F),
+        ),
+    ),
+    (
+        "high-contrast",
+        Palette::new(
+            Color::RGB(0, 0, 0),
+            Color::RGB(255, 255, 255),
+            Color::RGB(255, 255, 0),
+            Color::RGB(255, 0, 255),
+        ),
+    ),
+];
+
+/// Looks up a built-in theme by name, returning its index into `THEMES`
+pub fn find_theme(name: &str) -> Option<usize> {
+    THEMES.iter().position(|(theme, _)| *theme == name)
+}
+
+fn parse_color(value: &str) -> Result<Color, String> {
+    let hex = value.trim().trim_start_matches('#');
+
+    if hex.len() != 6 {
+        return Err(format!("'{}' is not a 6 digit hex colour", value));
+    }
+
+    let rgb = u32::from_str_radix(hex, 16)
+        .map_err(|_| format!("'{}' is not a 6 digit hex colour", value))?;
+
+    Ok(Color::RGB((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_two_colours() {
+        let palette = Palette::parse("000000,#FFB000").unwrap();
+        assert_eq!(palette.colors[0], Color::RGB(0, 0, 0));
+        assert_eq!(palette.colors[1], Color::RGB(0xFF, 0xB0, 0x00));
+        assert_eq!(palette.colors[3], Color::RGB(0xFF, 0xB0, 0x00));
+    }
+
+    #[test]
+    fn test_parse_four_colours() {
+        let palette = Palette::parse("000000,FFFFFF,FF0000,00FF00").unwrap();
+        assert_eq!(palette.colors[2], Color::RGB(255, 0, 0));
+        assert_eq!(palette.colors[3], Color::RGB(0, 255, 0));
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(Palette::parse("000000").is_err());
+        assert!(Palette::parse("000000,GGGGGG").is_err());
+    }
+
+    #[test]
+    fn test_find_theme() {
+        assert_eq!(find_theme("amber"), Some(2));
+        assert_eq!(find_theme("purple"), None);
+    }
+}
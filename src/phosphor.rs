@@ -0,0 +1,131 @@
+use crate::renderer::Display;
+
+/// Brightness below which a decaying pixel is treated as fully off
+const CUTOFF: u8 = 8;
+/// How fast pixels fade with plain `decay`, gone after ten frames
+const DEFAULT_RATE: f32 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PhosphorMode {
+    /// Each frame an unlit pixel loses this fraction of its brightness
+    Decay(f32),
+    /// Erased pixels stay lit for one extra frame
+    Hold,
+}
+
+impl PhosphorMode {
+    /// Parses `hold`, `decay`, or `decay:<rate>` with a rate between 0 and 1, which can
+    /// also be given on its own
+    pub fn parse(value: &str) -> Result<PhosphorMode, String> {
+        let rate = match value {
+            "hold" => return Ok(PhosphorMode::Hold),
+            "decay" => return Ok(PhosphorMode::Decay(DEFAULT_RATE)),
+            _ => value.strip_prefix("decay:").unwrap_or(value),
+        };
+
+        match rate.parse::<f32>() {
+            Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(PhosphorMode::Decay(rate)),
+            _ => Err(format!(
+                "'{}' is not 'hold', 'decay' or 'decay:<rate>' with a rate between 0 and 1",
+                value
+            )),
+        }
+    }
+}
+
+/// Smooths out XOR flicker by remembering how recently each pixel was lit.
+pub struct Phosphor {
+    mode: PhosphorMode,
+    /// Brightness of each pixel from 0 (off) to 255 (fully lit)
+    levels: Display,
+    previous: Display,
+}
+
+impl Phosphor {
+    pub fn new(mode: PhosphorMode) -> Phosphor {
+        Phosphor {
+            mode,
+            levels: [[0; 64]; 32],
+            previous: [[0; 64]; 32],
+        }
+    }
+
+    /// Advances one frame and returns the brightness of every pixel
+    pub fn filter(&mut self, display: &Display) -> Display {
+        for (row, pixels) in display.iter().enumerate() {
+            for (col, &pixel) in pixels.iter().enumerate() {
+                let lit = pixel != 0;
+                let level = &mut self.levels[row][col];
+
+                *level = match self.mode {
+                    _ if lit => 255,
+                    PhosphorMode::Hold if self.previous[row][col] != 0 => 255,
+                    PhosphorMode::Hold => 0,
+                    PhosphorMode::Decay(rate) => {
+                        let decayed = (*level as f32 * (1.0 - rate)) as u8;
+                        if decayed < CUTOFF {
+                            0
+                        } else {
+                            decayed
+                        }
+                    }
+                };
+            }
+        }
+
+        self.previous = *display;
+        self.levels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hold_keeps_pixel_for_one_frame() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Hold);
+        let mut display = [[0; 64]; 32];
+
+        display[0][0] = 1;
+        assert_eq!(phosphor.filter(&display)[0][0], 255);
+
+        display[0][0] = 0;
+        assert_eq!(phosphor.filter(&display)[0][0], 255);
+        assert_eq!(phosphor.filter(&display)[0][0], 0);
+    }
+
+    #[test]
+    fn test_decay_fades_out() {
+        let mut phosphor = Phosphor::new(PhosphorMode::Decay(0.5));
+        let mut display = [[0; 64]; 32];
+
+        display[5][5] = 1;
+        phosphor.filter(&display);
+
+        display[5][5] = 0;
+        assert_eq!(phosphor.filter(&display)[5][5], 127);
+        assert_eq!(phosphor.filter(&display)[5][5], 63);
+
+        for _ in 0..4 {
+            phosphor.filter(&display);
+        }
+        assert_eq!(phosphor.filter(&display)[5][5], 0);
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(PhosphorMode::parse("hold"), Ok(PhosphorMode::Hold));
+        assert_eq!(PhosphorMode::parse("0.25"), Ok(PhosphorMode::Decay(0.25)));
+        assert_eq!(
+            PhosphorMode::parse("decay"),
+            Ok(PhosphorMode::Decay(DEFAULT_RATE))
+        );
+        assert_eq!(
+            PhosphorMode::parse("decay:0.5"),
+            Ok(PhosphorMode::Decay(0.5))
+        );
+        assert!(PhosphorMode::parse("decay:").is_err());
+        assert!(PhosphorMode::parse("2").is_err());
+    }
+}
@@ -0,0 +1,261 @@
+use crate::instructions::Instruction;
+use crate::interpreter;
+use crate::memory::Memory;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// How many addresses the hot-spot report lists
+const HOTTEST: usize = 20;
+
+/// Counts executions per address and time spent in subroutines.
+///
+/// Time is measured in instructions, so it is independent of how fast the host runs.
+#[derive(Debug)]
+pub struct Profiler {
+    counts: Vec<u64>,
+    instructions: u64,
+    subroutines: HashMap<u16, Subroutine>,
+    /// Subroutines currently being executed, innermost last
+    calls: Vec<Call>,
+    frames: u64,
+    /// Instructions this frame that moved the program counter, so busy waits on `Fx0A` or
+    /// a jump to self don't count towards the work done in a frame
+    busy_this_frame: u64,
+    busy: u64,
+    max_busy: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Subroutine {
+    calls: u64,
+    /// Instructions executed between the call and its return, including nested calls
+    inclusive: u64,
+    /// Instructions executed in the subroutine itself
+    exclusive: u64,
+}
+
+#[derive(Debug)]
+struct Call {
+    address: u16,
+    /// Value of `instructions` when the call was made
+    start: u64,
+    /// Instructions spent in nested calls
+    nested: u64,
+}
+
+impl Profiler {
+    pub fn new(ram_size: usize) -> Profiler {
+        Profiler {
+            counts: vec![0; ram_size],
+            instructions: 0,
+            subroutines: HashMap::new(),
+            calls: Vec::new(),
+            frames: 0,
+            busy_this_frame: 0,
+            busy: 0,
+            max_busy: 0,
+        }
+    }
+
+    /// Records the instruction that was just executed from `address`
+    pub fn record(&mut self, address: u16, memory: &Memory) {
+        self.counts[address as usize] += 1;
+        self.instructions += 1;
+        if memory.program_counter != address {
+            self.busy_this_frame += 1;
+        }
+
+        let opcode = interpreter::opcode_at(memory, address);
+        match interpreter::parse((opcode >> 8) as u8, opcode as u8) {
+            Instruction::Call(_) => self.calls.push(Call {
+                address: memory.program_counter,
+                start: self.instructions,
+                nested: 0,
+            }),
+            // A return without a matching call is left to the interpreter to complain about
+            Instruction::Return => {
+                if let Some(call) = self.calls.pop() {
+                    let inclusive = self.instructions - call.start;
+
+                    let subroutine = self.subroutines.entry(call.address).or_default();
+                    subroutine.calls += 1;
+                    subroutine.inclusive += inclusive;
+                    subroutine.exclusive += inclusive - call.nested;
+
+                    if let Some(caller) = self.calls.last_mut() {
+                        caller.nested += inclusive;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frames += 1;
+        self.busy += self.busy_this_frame;
+        self.max_busy = self.max_busy.max(self.busy_this_frame);
+        self.busy_this_frame = 0;
+    }
+
+    /// Addresses by execution count, most executed first
+    fn hottest(&self) -> Vec<(u16, u64)> {
+        let mut hottest: Vec<(u16, u64)> = self
+            .counts
+            .iter()
+            .enumerate()
+            .filter(|(_, &count)| count > 0)
+            .map(|(address, &count)| (address as u16, count))
+            .collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hottest.truncate(HOTTEST);
+        hottest
+    }
+
+    /// Subroutines by inclusive time, most expensive first
+    fn sorted_subroutines(&self) -> Vec<(u16, Subroutine)> {
+        let mut subroutines: Vec<(u16, Subroutine)> = self
+            .subroutines
+            .iter()
+            .map(|(&address, &subroutine)| (address, subroutine))
+            .collect();
+        subroutines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        subroutines
+    }
+
+    fn busy_per_frame(&self) -> f64 {
+        self.busy as f64 / self.frames.max(1) as f64
+    }
+
+    fn percent(&self, count: u64) -> f64 {
+        count as f64 * 100.0 / self.instructions.max(1) as f64
+    }
+
+    /// A plain text summary for printing on exit
+    pub fn report(&self, memory: &Memory) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "{} instructions over {} frames, {:.1} busy per frame on average and {} at most",
+            self.instructions,
+            self.frames,
+            self.busy_per_frame(),
+            self.max_busy
+        );
+
+        let _ = writeln!(out, "\nhottest addresses:");
+        for (address, count) in self.hottest() {
+            let _ = writeln!(
+                out,
+                "  {:03X}  {:>10}  {:>5.1}%  {}",
+                address,
+                count,
+                self.percent(count),
+                disassemble(memory, address)
+            );
+        }
+
+        let _ = writeln!(out, "\nsubroutines:");
+        let _ = writeln!(
+            out,
+            "  addr       calls   inclusive        self  % inclusive"
+        );
+        for (address, subroutine) in self.sorted_subroutines() {
+            let _ = writeln!(
+                out,
+                "  {:03X}  {:>10}  {:>10}  {:>10}  {:>10.1}%",
+                address,
+                subroutine.calls,
+                subroutine.inclusive,
+                subroutine.exclusive,
+                self.percent(subroutine.inclusive)
+            );
+        }
+
+        out
+    }
+
+    pub fn to_json(&self, memory: &Memory) -> Value {
+        let hottest: Vec<Value> = self
+            .hottest()
+            .into_iter()
+            .map(|(address, count)| {
+                json!({
+                    "address": address,
+                    "count": count,
+                    "instruction": disassemble(memory, address),
+                })
+            })
+            .collect();
+
+        let subroutines: Vec<Value> = self
+            .sorted_subroutines()
+            .into_iter()
+            .map(|(address, subroutine)| {
+                json!({
+                    "address": address,
+                    "calls": subroutine.calls,
+                    "inclusive": subroutine.inclusive,
+                    "self": subroutine.exclusive,
+                })
+            })
+            .collect();
+
+        json!({
+            "instructions": self.instructions,
+            "frames": self.frames,
+            "busy_per_frame": self.busy_per_frame(),
+            "max_busy_per_frame": self.max_busy,
+            "hottest": hottest,
+            "subroutines": subroutines,
+        })
+    }
+}
+
+fn disassemble(memory: &Memory, address: u16) -> String {
+    let opcode = interpreter::opcode_at(memory, address);
+    interpreter::parse((opcode >> 8) as u8, opcode as u8).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_subroutine_times() {
+        // 200: CALL 206, 202: CALL 20A, 204: JP 204
+        // 206: CALL 20A, 208: RET
+        // 20A: LD V0, 1, 20C: RET
+        let mut memory = Memory::new();
+        memory
+            .load_rom(&[
+                0x22, 0x06, 0x22, 0x0A, 0x12, 0x04, 0x22, 0x0A, 0x00, 0xEE, 0x60, 0x01, 0x00, 0xEE,
+            ])
+            .unwrap();
+
+        let mut profiler = Profiler::new(memory.ram.len());
+        let no_keys = HashSet::new();
+        for _ in 0..10 {
+            let address = memory.program_counter;
+            interpreter::step(&mut memory, &no_keys, &no_keys).unwrap();
+            profiler.record(address, &memory);
+        }
+        profiler.end_frame();
+
+        let outer = profiler.subroutines[&0x206];
+        assert_eq!(outer.calls, 1);
+        assert_eq!(outer.inclusive, 4);
+        assert_eq!(outer.exclusive, 2);
+
+        let inner = profiler.subroutines[&0x20A];
+        assert_eq!(inner.calls, 2);
+        assert_eq!(inner.inclusive, 4);
+
+        // The jump to self at 204 is idle time
+        assert_eq!(profiler.max_busy, 8);
+        assert_eq!(profiler.hottest()[0], (0x204, 2));
+    }
+}
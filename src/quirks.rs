@@ -0,0 +1,151 @@
+/// Behaviours that differ between CHIP-8 interpreters, named as in the CHIP-8 database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// `8xy6`/`8xyE` shift Vx in place instead of shifting Vy into Vx
+    pub shift: bool,
+    /// `Fx55`/`Fx65` add x to I rather than x + 1
+    pub memory_increment_by_x: bool,
+    /// `Fx55`/`Fx65` leave I alone
+    pub memory_leave_i_unchanged: bool,
+    /// Sprites wrap around the edges of the screen instead of being clipped
+    pub wrap: bool,
+    /// `Bnnn` jumps to nnn + Vx, with x the top nibble of nnn, instead of nnn + V0
+    pub jump: bool,
+    /// Drawing waits for the next frame, so at most one sprite is drawn per frame
+    pub vblank: bool,
+    /// `8xy1`, `8xy2` and `8xy3` reset VF
+    pub logic: bool,
+}
+
+impl Default for Quirks {
+    /// How this emulator has always behaved
+    fn default() -> Quirks {
+        Quirks {
+            shift: true,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: true,
+            wrap: true,
+            jump: false,
+            vblank: false,
+            logic: false,
+        }
+    }
+}
+
+/// The instruction set a ROM was written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip,
+}
+
+/// A known interpreter, with the quirks and speed ROMs written for it expect
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preset {
+    /// Platform id used by the CHIP-8 database
+    pub id: &'static str,
+    pub name: &'static str,
+    pub platform: Platform,
+    pub quirks: Quirks,
+    /// Instructions per frame
+    pub speed: usize,
+}
+
+const COSMAC_VIP: Quirks = Quirks {
+    shift: false,
+    memory_increment_by_x: false,
+    memory_leave_i_unchanged: false,
+    wrap: false,
+    jump: false,
+    vblank: true,
+    logic: true,
+};
+
+const SUPERCHIP: Quirks = Quirks {
+    shift: true,
+    memory_increment_by_x: false,
+    memory_leave_i_unchanged: true,
+    wrap: false,
+    jump: true,
+    vblank: false,
+    logic: false,
+};
+
+pub const PRESETS: [Preset; 7] = [
+    Preset {
+        id: "originalChip8",
+        name: "CHIP-8 on the COSMAC VIP",
+        platform: Platform::Chip8,
+        quirks: COSMAC_VIP,
+        speed: 15,
+    },
+    Preset {
+        id: "hybridVIP",
+        name: "CHIP-8 with machine code routines on the COSMAC VIP",
+        platform: Platform::Chip8,
+        quirks: COSMAC_VIP,
+        speed: 15,
+    },
+    Preset {
+        id: "modernChip8",
+        name: "modern CHIP-8",
+        platform: Platform::Chip8,
+        quirks: Quirks {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: false,
+            wrap: false,
+            jump: false,
+            vblank: false,
+            logic: false,
+        },
+        speed: 12,
+    },
+    Preset {
+        id: "chip48",
+        name: "CHIP-48 on the HP-48",
+        platform: Platform::Chip8,
+        quirks: Quirks {
+            memory_increment_by_x: true,
+            memory_leave_i_unchanged: false,
+            ..SUPERCHIP
+        },
+        speed: 30,
+    },
+    Preset {
+        id: "superchip1",
+        name: "SUPER-CHIP 1.1",
+        platform: Platform::SuperChip,
+        quirks: SUPERCHIP,
+        speed: 30,
+    },
+    Preset {
+        id: "superchip",
+        name: "modern SUPER-CHIP",
+        platform: Platform::SuperChip,
+        quirks: SUPERCHIP,
+        speed: 30,
+    },
+    Preset {
+        id: "xochip",
+        name: "XO-CHIP",
+        platform: Platform::XoChip,
+        quirks: Quirks {
+            shift: false,
+            memory_increment_by_x: false,
+            memory_leave_i_unchanged: false,
+            wrap: true,
+            jump: false,
+            vblank: false,
+            logic: false,
+        },
+        speed: 100,
+    },
+];
+
+pub fn find_preset(id: &str) -> Option<&'static Preset> {
+    PRESETS
+        .iter()
+        .find(|preset| preset.id.eq_ignore_ascii_case(id))
+}
@@ -0,0 +1,415 @@
+use crate::instructions::Instruction;
+use crate::interpreter;
+use crate::interpreter::ExecError;
+use crate::memory::Memory;
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt;
+
+/// Upper bound on the number of instructions translated into a single block.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+type Op = Box<dyn Fn(&mut Memory)>;
+
+/// A straight-line run of instructions translated into closures.
+///
+/// The block always ends just before a "terminator" (a jump, skip, draw, memory write etc.)
+/// which is left to the interpreter.
+struct Block {
+    start: u16,
+    /// Address of the terminating instruction, directly after the last compiled op
+    end: u16,
+    ops: Vec<Op>,
+}
+
+impl Block {
+    fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the block's code, including its terminator, overlaps `start..end`
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        (self.start as usize) < end && start < self.end as usize + 2
+    }
+}
+
+/// Reported by cross-check mode when a compiled block and the interpreter disagree.
+#[derive(Debug)]
+pub struct Mismatch {
+    pub block_start: u16,
+    pub expected: Box<Memory>,
+    pub actual: Box<Memory>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "compiled block at 0x{:X} diverged from the interpreter",
+            self.block_start
+        )?;
+
+        if self.expected.program_counter != self.actual.program_counter {
+            writeln!(
+                f,
+                "  pc: expected 0x{:X}, got 0x{:X}",
+                self.expected.program_counter, self.actual.program_counter
+            )?;
+        }
+        if self.expected.i != self.actual.i {
+            writeln!(
+                f,
+                "  i: expected 0x{:X}, got 0x{:X}",
+                self.expected.i, self.actual.i
+            )?;
+        }
+        for reg in 0..16 {
+            if self.expected.registers[reg] != self.actual.registers[reg] {
+                writeln!(
+                    f,
+                    "  V{:X}: expected 0x{:X}, got 0x{:X}",
+                    reg, self.expected.registers[reg], self.actual.registers[reg]
+                )?;
+            }
+        }
+        if self.expected.delay != self.actual.delay || self.expected.sound != self.actual.sound {
+            writeln!(f, "  timers differ")?;
+        }
+        if self.expected.ram != self.actual.ram || self.expected.display != self.actual.display {
+            writeln!(f, "  ram or display differ")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Execution backend that caches basic blocks as closures instead of
+/// parsing and dispatching every instruction.
+pub struct Recompiler {
+    blocks: HashMap<u16, Block>,
+    /// Run every compiled block against the interpreter and compare the results
+    pub cross_check: bool,
+}
+
+impl Recompiler {
+    pub fn new(cross_check: bool) -> Recompiler {
+        Recompiler {
+            blocks: HashMap::new(),
+            cross_check,
+        }
+    }
+
+    /// Executes `cycles` instructions, the same budget the interpreter loop would use,
+    /// stopping early after a draw when the vblank quirk is on.
+    pub fn run(
+        &mut self,
+        memory: &mut Memory,
+        cycles: usize,
+        pressed_keys: &HashSet<Keycode>,
+        new_keys: &HashSet<Keycode>,
+    ) -> Result<(), ExecError> {
+        let mut remaining = cycles;
+
+        while remaining > 0 {
+            let pc = memory.program_counter;
+
+            let block = self.blocks.entry(pc).or_insert_with(|| compile(memory, pc));
+
+            if block.len() > remaining {
+                // Not enough budget left for the whole block, so finish off one instruction at a time
+                let vblank = interpreter::waits_for_vblank(memory);
+                interpreter::step(memory, pressed_keys, new_keys)?;
+                remaining -= 1;
+                if vblank {
+                    break;
+                }
+                continue;
+            }
+
+            if block.len() > 0 {
+                let reference = if self.cross_check {
+                    Some(interpret_block(
+                        memory,
+                        block.len(),
+                        pressed_keys,
+                        new_keys,
+                    )?)
+                } else {
+                    None
+                };
+
+                for op in block.ops.iter() {
+                    op(memory);
+                }
+                memory.program_counter = block.end;
+                remaining -= block.len();
+
+                if let Some(expected) = reference {
+                    if expected != *memory {
+                        return Err(ExecError::Mismatch(Box::new(Mismatch {
+                            block_start: block.start,
+                            expected: Box::new(expected),
+                            actual: Box::new(memory.clone()),
+                        })));
+                    }
+                }
+
+                if remaining == 0 {
+                    break;
+                }
+            }
+
+            let written = written_range(memory);
+            let vblank = interpreter::waits_for_vblank(memory);
+            interpreter::step(memory, pressed_keys, new_keys)?;
+            remaining -= 1;
+
+            if let Some((start, end)) = written {
+                self.invalidate(start, end);
+            }
+            if vblank {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Drops every cached block whose code overlaps `start..end`
+    pub fn invalidate(&mut self, start: usize, end: usize) {
+        self.blocks.retain(|_, block| !block.overlaps(start, end));
+    }
+}
+
+/// Translates instructions starting at `start` until the first one that cannot be compiled.
+fn compile(memory: &Memory, start: u16) -> Block {
+    let mut ops = Vec::new();
+    let mut address = start as usize;
+
+    // The last word of a 64 KiB ram is left to the interpreter, since the block would end
+    // past the last address
+    let end = memory.ram.len().min(u16::MAX as usize);
+    while ops.len() < MAX_BLOCK_LENGTH && address + 2 <= end {
+        let instruction = interpreter::parse(memory.ram[address], memory.ram[address + 1]);
+
+        match compile_op(instruction) {
+            Some(op) => ops.push(op),
+            None => break,
+        }
+
+        address += 2;
+    }
+
+    Block {
+        start,
+        end: address as u16,
+        ops,
+    }
+}
+
+/// Returns a closure with the same effect as the interpreter for instructions that
+/// only touch registers, `i` and the timers. Anything else ends the block.
+fn compile_op(instruction: Instruction) -> Option<Op> {
+    let op: Op = match instruction {
+        Instruction::LoadByte { vx, byte } => Box::new(move |m| m.registers[vx] = byte),
+        Instruction::AddByte { vx, byte } => {
+            Box::new(move |m| m.registers[vx] = m.registers[vx].wrapping_add(byte))
+        }
+        Instruction::LoadReg { vx, vy } => Box::new(move |m| m.registers[vx] = m.registers[vy]),
+        Instruction::Or { vx, vy } => Box::new(move |m| {
+            m.registers[vx] |= m.registers[vy];
+            reset_flag(m);
+        }),
+        Instruction::And { vx, vy } => Box::new(move |m| {
+            m.registers[vx] &= m.registers[vy];
+            reset_flag(m);
+        }),
+        Instruction::Xor { vx, vy } => Box::new(move |m| {
+            m.registers[vx] ^= m.registers[vy];
+            reset_flag(m);
+        }),
+        Instruction::AddReg { vx, vy } => Box::new(move |m| {
+            let (result, carry) = m.registers[vx].overflowing_add(m.registers[vy]);
+            m.registers[vx] = result;
+            m.registers[0xF] = carry as u8;
+        }),
+        Instruction::Subtract { vx, vy } => Box::new(move |m| {
+            let no_borrow = m.registers[vx] >= m.registers[vy];
+            m.registers[vx] = m.registers[vx].wrapping_sub(m.registers[vy]);
+            m.registers[0xF] = no_borrow as u8;
+        }),
+        Instruction::ShiftRight { vx, vy } => Box::new(move |m| {
+            let value = m.registers[if m.quirks.shift { vx } else { vy }];
+            m.registers[vx] = value >> 1;
+            m.registers[0xF] = value & 0b0000_0001;
+        }),
+        Instruction::SubtractReverse { vx, vy } => Box::new(move |m| {
+            let no_borrow = m.registers[vy] >= m.registers[vx];
+            m.registers[vx] = m.registers[vy].wrapping_sub(m.registers[vx]);
+            m.registers[0xF] = no_borrow as u8;
+        }),
+        Instruction::ShiftLeft { vx, vy } => Box::new(move |m| {
+            let value = m.registers[if m.quirks.shift { vx } else { vy }];
+            m.registers[vx] = value << 1;
+            m.registers[0xF] = value >> 7;
+        }),
+        Instruction::LoadAddress(addr) => {
+            let addr = addr.to_u16();
+            Box::new(move |m| m.i = addr)
+        }
+        Instruction::LoadDelay { vx } => Box::new(move |m| m.registers[vx] = m.delay),
+        Instruction::SetDelay { vx } => Box::new(move |m| m.delay = m.registers[vx]),
+        Instruction::SetSound { vx } => Box::new(move |m| m.sound = m.registers[vx]),
+        Instruction::AddAddressOffset { vx } => {
+            Box::new(move |m| m.i = m.i.wrapping_add(m.registers[vx] as u16))
+        }
+        Instruction::LoadSprite { vx } => Box::new(move |m| {
+            let digit = m.registers[vx] as u16;
+            m.i = if digit <= 0xF { digit * 5 } else { 0 };
+        }),
+        _ => return None,
+    };
+
+    Some(op)
+}
+
+/// The logic quirk clears VF after `OR`, `AND` and `XOR`
+fn reset_flag(memory: &mut Memory) {
+    if memory.quirks.logic {
+        memory.registers[0xF] = 0;
+    }
+}
+
+/// Runs `length` instructions on a copy of `memory` with the interpreter
+fn interpret_block(
+    memory: &Memory,
+    length: usize,
+    pressed_keys: &HashSet<Keycode>,
+    new_keys: &HashSet<Keycode>,
+) -> Result<Memory, ExecError> {
+    let mut reference = memory.clone();
+    for _ in 0..length {
+        interpreter::step(&mut reference, pressed_keys, new_keys)?;
+    }
+    Ok(reference)
+}
+
+/// The range of `ram` the instruction at the program counter is about to write to, if any
+fn written_range(memory: &Memory) -> Option<(usize, usize)> {
+    let pc = memory.program_counter as usize;
+    let i = memory.i as usize;
+
+    if pc + 1 >= memory.ram.len() {
+        return None;
+    }
+
+    match interpreter::parse(memory.ram[pc], memory.ram[pc + 1]) {
+        Instruction::SetBCD { .. } => Some((i, i + 3)),
+        Instruction::LoadRegisters { vx } => Some((i, i + vx + 1)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory;
+    use crate::quirks::Quirks;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn memory_with_program(program: &[u8]) -> Memory {
+        let mut memory = Memory {
+            delay: 0,
+            sound: 0,
+            stack_pointer: 0,
+            program_counter: 0x200,
+            i: 0,
+            registers: [0; 16],
+            stack: [0; 16],
+            display: [[0; 64]; 32],
+            ram: vec![0; memory::RAM_SIZE],
+            quirks: Quirks::default(),
+            speed: 20,
+            rng: StdRng::seed_from_u64(0),
+        };
+        memory.ram[0x200..0x200 + program.len()].clone_from_slice(program);
+        memory
+    }
+
+    #[test]
+    fn test_block_matches_interpreter() {
+        // LD V0, 0xF0; LD V1, 0x20; ADD V0, V1; SHL V1; LD I, 0x300; ADD I, V1; JP 0x200
+        let program = [
+            0x60, 0xF0, 0x61, 0x20, 0x80, 0x14, 0x81, 0x0E, 0xA3, 0x00, 0xF1, 0x1E, 0x12, 0x00,
+        ];
+        let mut memory = memory_with_program(&program);
+        let mut recompiler = Recompiler::new(true);
+
+        let result = recompiler.run(&mut memory, 7, &HashSet::new(), &HashSet::new());
+
+        assert!(result.is_ok());
+        assert_eq!(memory.registers[0], 0x10);
+        assert_eq!(memory.registers[1], 0x40);
+        assert_eq!(memory.registers[0xF], 0);
+        assert_eq!(memory.i, 0x340);
+        assert_eq!(memory.program_counter, 0x200);
+    }
+
+    #[test]
+    fn test_add_byte_leaves_flag() {
+        // LD VF, 0x05; LD V0, 0xFF; ADD V0, 0x02; JP 0x200
+        let program = [0x6F, 0x05, 0x60, 0xFF, 0x70, 0x02, 0x12, 0x00];
+        let mut memory = memory_with_program(&program);
+        let mut recompiler = Recompiler::new(true);
+
+        recompiler
+            .run(&mut memory, 4, &HashSet::new(), &HashSet::new())
+            .unwrap();
+
+        assert_eq!(memory.registers[0], 0x01);
+        assert_eq!(memory.registers[0xF], 0x05);
+    }
+
+    #[test]
+    fn test_add_address_offset_wraps() {
+        // LD V0, 0x02; ADD I, V0; JP 0x200
+        let program = [0x60, 0x02, 0xF0, 0x1E, 0x12, 0x00];
+        let mut memory = memory_with_program(&program);
+        memory.ram.resize(memory::XO_CHIP_RAM_SIZE, 0);
+        memory.i = 0xFFFF;
+        let mut recompiler = Recompiler::new(true);
+
+        recompiler
+            .run(&mut memory, 3, &HashSet::new(), &HashSet::new())
+            .unwrap();
+
+        assert_eq!(memory.i, 0x0001);
+    }
+
+    #[test]
+    fn test_code_write_invalidates_block() {
+        // LD V0, 0x12; LD I, 0x206; LD [I], V0; LD V1, 0x01
+        let program = [0x60, 0x12, 0xA2, 0x06, 0xF0, 0x55, 0x61, 0x01];
+        let mut memory = memory_with_program(&program);
+        let mut recompiler = Recompiler::new(true);
+
+        // Compile the block at 0x206 before it gets overwritten
+        memory.program_counter = 0x206;
+        recompiler
+            .run(&mut memory, 1, &HashSet::new(), &HashSet::new())
+            .unwrap();
+        assert!(recompiler.blocks.contains_key(&0x206));
+
+        memory.program_counter = 0x200;
+        recompiler
+            .run(&mut memory, 3, &HashSet::new(), &HashSet::new())
+            .unwrap();
+
+        assert!(!recompiler.blocks.contains_key(&0x206));
+        assert_eq!(memory.ram[0x206], 0x12);
+    }
+}
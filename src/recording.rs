@@ -0,0 +1,156 @@
+use crate::display_constants;
+use crate::palette::Palette;
+use crate::renderer::Display;
+use gif::{Encoder, Frame, Repeat};
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Records the display to an animated GIF, one frame per call to `capture`.
+pub struct GifRecorder {
+    encoder: Encoder<BufWriter<File>>,
+    scale: u32,
+    width: u16,
+    height: u16,
+    /// Number of frames dropped between each recorded one
+    skip: u32,
+    frames_seen: u32,
+    /// Centiseconds of GIF delay written so far, used to avoid drift from rounding
+    elapsed: u32,
+}
+
+impl GifRecorder {
+    pub fn new(path: &Path, palette: &Palette, scale: u32, skip: u32) -> io::Result<GifRecorder> {
+        // GIF dimensions are 16 bits
+        let size = |length: u32| {
+            length
+                .checked_mul(scale)
+                .and_then(|size| u16::try_from(size).ok())
+        };
+        let (width, height) = match (
+            size(display_constants::WIDTH),
+            size(display_constants::HEIGHT),
+        ) {
+            (Some(width), Some(height)) => (width, height),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("a scale of {} is too large for a GIF", scale),
+                ))
+            }
+        };
+
+        let global_palette: Vec<u8> = palette
+            .colors
+            .iter()
+            .flat_map(|color| [color.r, color.g, color.b])
+            .collect();
+
+        let mut encoder = Encoder::new(
+            BufWriter::new(File::create(path)?),
+            width,
+            height,
+            &global_palette,
+        )
+        .map_err(io::Error::other)?;
+        encoder
+            .set_repeat(Repeat::Infinite)
+            .map_err(io::Error::other)?;
+
+        Ok(GifRecorder {
+            encoder,
+            scale,
+            width,
+            height,
+            skip,
+            frames_seen: 0,
+            elapsed: 0,
+        })
+    }
+
+    /// Called once per 60Hz frame, writing the display unless this frame is skipped
+    pub fn capture(&mut self, display: &Display) -> io::Result<()> {
+        let index = self.frames_seen;
+        self.frames_seen += 1;
+
+        if !index.is_multiple_of(self.skip + 1) {
+            return Ok(());
+        }
+
+        // Each recorded frame is shown until the next recorded one, 100 / 60 centiseconds per frame
+        let end = (index + self.skip + 1) * 100 / 60;
+        let delay = end - self.elapsed;
+        self.elapsed = end;
+
+        let mut frame = Frame::from_indexed_pixels(
+            self.width,
+            self.height,
+            to_indexed(display, self.scale),
+            None,
+        );
+        frame.delay = delay as u16;
+
+        self.encoder.write_frame(&frame).map_err(io::Error::other)
+    }
+
+    /// Writes the GIF trailer and closes the file
+    pub fn finish(self) -> io::Result<()> {
+        self.encoder.into_inner()?;
+        Ok(())
+    }
+}
+
+/// Expands `display` into palette indices, each pixel repeated `scale` times in both directions
+fn to_indexed(display: &Display, scale: u32) -> Vec<u8> {
+    let scale = scale as usize;
+    let mut pixels = Vec::with_capacity(display.len() * display[0].len() * scale * scale);
+
+    for row in display.iter() {
+        let scaled_row: Vec<u8> = row
+            .iter()
+            .flat_map(|&pixel| std::iter::repeat_n(pixel & 0b11, scale))
+            .collect();
+
+        for _ in 0..scale {
+            pixels.extend_from_slice(&scaled_row);
+        }
+    }
+
+    pixels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_indexed_scaled() {
+        let mut display = [[0; 64]; 32];
+        display[1][0] = 1;
+
+        let pixels = to_indexed(&display, 2);
+
+        assert_eq!(pixels.len(), 128 * 64);
+        assert_eq!(pixels[0], 0);
+        assert_eq!(pixels[2 * 128], 1);
+        assert_eq!(pixels[3 * 128 + 1], 1);
+        assert_eq!(pixels[3 * 128 + 2], 0);
+    }
+
+    #[test]
+    fn test_scale_too_large() {
+        let path = std::env::temp_dir().join(format!("chip8-scale-{}.gif", std::process::id()));
+        let palette = &crate::palette::THEMES[0].1;
+
+        let error = GifRecorder::new(&path, palette, 1024, 0).err().unwrap();
+        assert_eq!(error.to_string(), "a scale of 1024 is too large for a GIF");
+        assert!(!path.exists());
+
+        GifRecorder::new(&path, palette, 1023, 0)
+            .unwrap()
+            .finish()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,184 @@
+use crate::display_constants;
+use crate::palette::Palette;
+use crate::phosphor::Phosphor;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::{Texture, WindowCanvas};
+
+const LETTERBOX: Color = Color::RGB(0, 0, 0);
+
+pub type Display = [[u8; 64]; 32];
+
+/// Draws the display through a single streaming texture, only re-uploading when something changed.
+pub struct Renderer {
+    last_frame: Option<Display>,
+    pub palette: Palette,
+    /// Optional flicker filter applied to the display before drawing
+    pub phosphor: Option<Phosphor>,
+}
+
+impl Renderer {
+    pub fn new(palette: Palette, phosphor: Option<Phosphor>) -> Renderer {
+        Renderer {
+            last_frame: None,
+            palette,
+            phosphor,
+        }
+    }
+
+    /// Switches to `palette`, redrawing on the next frame
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.palette = palette;
+        self.invalidate();
+    }
+
+    /// Forces the next call to `render` to draw, e.g. after the window was resized or exposed
+    pub fn invalidate(&mut self) {
+        self.last_frame = None;
+    }
+
+    /// Uploads `display` into `texture` and copies it onto the canvas letterboxed.
+    /// Does nothing if the frame is unchanged since the last call.
+    pub fn render(&mut self, canvas: &mut WindowCanvas, texture: &mut Texture, display: &Display) {
+        // With the phosphor filter the frame holds brightness levels rather than palette indices
+        let (frame, blended) = match self.phosphor.as_mut() {
+            Some(phosphor) => (phosphor.filter(display), true),
+            None => (*display, false),
+        };
+
+        if self.last_frame == Some(frame) {
+            return;
+        }
+
+        let palette = &self.palette;
+        texture
+            .with_lock(None, |buffer, pitch| {
+                write_pixels(buffer, pitch, &frame, palette, blended)
+            })
+            .unwrap();
+        present(canvas, texture);
+
+        self.last_frame = Some(frame);
+    }
+
+    /// Draws a menu in place of the display, from rows of palette indices as wide as `texture`.
+    /// The display is drawn again on the next call to `render`.
+    pub fn render_menu(&mut self, canvas: &mut WindowCanvas, texture: &mut Texture, pixels: &[u8]) {
+        let width = texture.query().width as usize;
+        let palette = &self.palette;
+        texture
+            .with_lock(None, |buffer, pitch| {
+                for (index, &pixel) in pixels.iter().enumerate() {
+                    let color = palette.colors[pixel as usize & 0b11];
+                    let offset = index / width * pitch + index % width * 3;
+
+                    buffer[offset] = color.r;
+                    buffer[offset + 1] = color.g;
+                    buffer[offset + 2] = color.b;
+                }
+            })
+            .unwrap();
+        present(canvas, texture);
+
+        self.invalidate();
+    }
+}
+
+/// Copies `texture` onto the canvas letterboxed and shows it
+fn present(canvas: &mut WindowCanvas, texture: &Texture) {
+    let (width, height) = canvas.output_size().unwrap();
+
+    canvas.set_draw_color(LETTERBOX);
+    canvas.clear();
+    canvas
+        .copy(texture, None, Some(letterbox(width, height)))
+        .unwrap();
+    canvas.present();
+}
+
+/// Writes `frame` as RGB24 pixels into a locked texture buffer
+fn write_pixels(
+    buffer: &mut [u8],
+    pitch: usize,
+    frame: &Display,
+    palette: &Palette,
+    blended: bool,
+) {
+    for (row, pixels) in frame.iter().enumerate() {
+        for (col, &pixel) in pixels.iter().enumerate() {
+            let color = if blended {
+                blend(palette.colors[0], palette.colors[1], pixel)
+            } else {
+                palette.colors[pixel as usize & 0b11]
+            };
+            let offset = row * pitch + col * 3;
+
+            buffer[offset] = color.r;
+            buffer[offset + 1] = color.g;
+            buffer[offset + 2] = color.b;
+        }
+    }
+}
+
+/// Mixes from `background` at level 0 to `foreground` at level 255
+fn blend(background: Color, foreground: Color, level: u8) -> Color {
+    let mix = |from: u8, to: u8| {
+        (from as u32 * (255 - level as u32) + to as u32 * level as u32) as f32 / 255.0
+    };
+
+    Color::RGB(
+        mix(background.r, foreground.r).round() as u8,
+        mix(background.g, foreground.g).round() as u8,
+        mix(background.b, foreground.b).round() as u8,
+    )
+}
+
+/// The largest rect with the display's aspect ratio that fits in the window, centered
+fn letterbox(window_width: u32, window_height: u32) -> Rect {
+    let width = window_width
+        .min(window_height * display_constants::WIDTH / display_constants::HEIGHT)
+        .max(1);
+    let height = (width * display_constants::HEIGHT / display_constants::WIDTH).max(1);
+
+    Rect::new(
+        (window_width as i32 - width as i32) / 2,
+        (window_height as i32 - height as i32) / 2,
+        width,
+        height,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blend() {
+        let background = Color::RGB(0, 0, 0);
+        let foreground = Color::RGB(255, 100, 0);
+
+        assert_eq!(blend(background, foreground, 0), background);
+        assert_eq!(blend(background, foreground, 255), foreground);
+        assert_eq!(blend(background, foreground, 51), Color::RGB(51, 20, 0));
+    }
+
+    #[test]
+    fn test_letterbox_exact_fit() {
+        assert_eq!(letterbox(640, 320), Rect::new(0, 0, 640, 320));
+    }
+
+    #[test]
+    fn test_letterbox_tall_window() {
+        assert_eq!(letterbox(640, 600), Rect::new(0, 140, 640, 320));
+    }
+
+    #[test]
+    fn test_letterbox_wide_window() {
+        assert_eq!(letterbox(1000, 320), Rect::new(180, 0, 640, 320));
+    }
+
+    #[test]
+    fn test_letterbox_non_integer_scale() {
+        assert_eq!(letterbox(700, 400), Rect::new(0, 25, 700, 350));
+    }
+}
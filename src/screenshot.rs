@@ -0,0 +1,95 @@
+use crate::display_constants;
+use crate::palette::Palette;
+use crate::renderer::Display;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Expands `display` into RGB rows, each pixel repeated `scale` times in both directions
+pub fn to_rgb(display: &Display, palette: &Palette, scale: u32) -> Vec<u8> {
+    let scale = scale as usize;
+    let width = display_constants::WIDTH as usize * scale;
+    let mut data = Vec::with_capacity(width * display_constants::HEIGHT as usize * scale * 3);
+
+    for pixels in display.iter() {
+        let mut row = Vec::with_capacity(width * 3);
+        for &pixel in pixels.iter() {
+            let color = palette.colors[pixel as usize & 0b11];
+            for _ in 0..scale {
+                row.extend_from_slice(&[color.r, color.g, color.b]);
+            }
+        }
+
+        for _ in 0..scale {
+            data.extend_from_slice(&row);
+        }
+    }
+
+    data
+}
+
+/// The display as text, `#` for lit pixels and `.` for the rest, one line per row
+pub fn to_text(display: &Display) -> String {
+    display
+        .iter()
+        .map(|row| {
+            let mut line: String = row
+                .iter()
+                .map(|&pixel| if pixel == 0 { '.' } else { '#' })
+                .collect();
+            line.push('\n');
+            line
+        })
+        .collect()
+}
+
+/// Writes `display` to a PNG file using `palette`, scaled up by an integer factor
+pub fn save_png(path: &Path, display: &Display, palette: &Palette, scale: u32) -> io::Result<()> {
+    let file = File::create(path)?;
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        display_constants::WIDTH * scale,
+        display_constants::HEIGHT * scale,
+    );
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&to_rgb(display, palette, scale))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::THEMES;
+
+    #[test]
+    fn test_to_rgb_scaled() {
+        let palette = THEMES[0].1;
+        let mut display = [[0; 64]; 32];
+        display[0][1] = 1;
+
+        let data = to_rgb(&display, &palette, 2);
+
+        assert_eq!(data.len(), 128 * 64 * 3);
+        // Pixel (1, 0) covers columns 2 and 3 of the first two rows
+        let lit = [
+            palette.colors[1].r,
+            palette.colors[1].g,
+            palette.colors[1].b,
+        ];
+        let unlit = [
+            palette.colors[0].r,
+            palette.colors[0].g,
+            palette.colors[0].b,
+        ];
+        assert_eq!(data[0..3], unlit);
+        assert_eq!(data[6..9], lit);
+        assert_eq!(data[128 * 3 + 9..128 * 3 + 12], lit);
+        assert_eq!(data[12..15], unlit);
+    }
+}
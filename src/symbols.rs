@@ -0,0 +1,236 @@
+use crate::instructions::Instruction;
+use crate::interpreter;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Labels and source lines for the addresses of a ROM, loaded from a symbol file.
+///
+/// The file is plain text with one entry per line, `#` starting a comment:
+///
+/// ```text
+/// draw_player = 0x2A4
+/// 0x2A4 game.8o:17
+/// ```
+///
+/// The first form names an address, the second says which source line the byte at an
+/// address was assembled from.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Symbols {
+    labels: BTreeMap<u16, String>,
+    lines: BTreeMap<u16, SourceLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file, self.line)
+    }
+}
+
+impl Symbols {
+    pub fn parse(text: &str) -> Result<Symbols, String> {
+        let mut symbols = Symbols::default();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let error = || {
+                format!(
+                    "line {}: expected `label = address` or `address file:line`",
+                    number + 1
+                )
+            };
+
+            if let Some((label, address)) = line.split_once('=') {
+                let address = parse_address(address.trim()).ok_or_else(error)?;
+                symbols.insert_label(address, label.trim());
+            } else {
+                let (address, location) = line.split_once(char::is_whitespace).ok_or_else(error)?;
+                let (file, source_line) = location.trim().rsplit_once(':').ok_or_else(error)?;
+
+                symbols.insert_line(
+                    parse_address(address).ok_or_else(error)?,
+                    SourceLine {
+                        file: file.to_string(),
+                        line: source_line.parse().map_err(|_| error())?,
+                    },
+                );
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    /// Names `address`, keeping the first name when several labels share an address
+    pub fn insert_label(&mut self, address: u16, label: &str) {
+        self.labels
+            .entry(address)
+            .or_insert_with(|| label.to_string());
+    }
+
+    pub fn insert_line(&mut self, address: u16, source_line: SourceLine) {
+        self.lines.insert(address, source_line);
+    }
+
+    /// Every label, by address
+    pub fn labels(&self) -> &BTreeMap<u16, String> {
+        &self.labels
+    }
+
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    pub fn source_line(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+
+    /// Names `address` relative to the closest label at or before it, e.g. `draw_player+4`
+    pub fn name(&self, address: u16) -> String {
+        match self.labels.range(..=address).next_back() {
+            Some((&start, label)) if start == address => label.clone(),
+            Some((&start, label)) => format!("{}+{}", label, address - start),
+            None => format!("0x{:03X}", address),
+        }
+    }
+
+    /// The label and source line of `address`, for error messages
+    pub fn locate(&self, address: u16) -> String {
+        match self.source_line(address) {
+            Some(source_line) => format!("{} ({})", self.name(address), source_line),
+            None => self.name(address),
+        }
+    }
+
+    /// Disassembles `instruction`, naming its address operand when it has a label
+    pub fn format_instruction(&self, instruction: &Instruction) -> String {
+        let text = instruction.to_string();
+
+        match instruction
+            .target()
+            .and_then(|target| Some((target, self.label(target)?)))
+        {
+            Some((target, label)) => text.replace(&format!("0x{:X}", target), label),
+            None => text,
+        }
+    }
+}
+
+impl fmt::Display for Symbols {
+    /// Writes the symbol file that `parse` reads
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (address, label) in self.labels.iter() {
+            writeln!(f, "{} = 0x{:03X}", label, address)?;
+        }
+        for (address, source_line) in self.lines.iter() {
+            writeln!(f, "0x{:03X} {}", address, source_line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Disassembles `rom`, loaded at `origin`, with a line for each label and source lines as comments.
+/// Words are decoded two bytes at a time, except where a label falls on the second byte.
+pub fn listing(rom: &[u8], origin: u16, symbols: &Symbols) -> String {
+    let mut out = String::new();
+    let mut offset = 0;
+
+    while offset < rom.len() {
+        let address = origin + offset as u16;
+        if let Some(label) = symbols.label(address) {
+            out.push_str(&format!("{}:\n", label));
+        }
+
+        let single = offset + 1 >= rom.len() || symbols.label(address + 1).is_some();
+        let mut text = if single {
+            format!("  {:03X}  {:02X}", address, rom[offset])
+        } else {
+            let instruction = interpreter::parse(rom[offset], rom[offset + 1]);
+            format!(
+                "  {:03X}  {:02X}{:02X}  {}",
+                address,
+                rom[offset],
+                rom[offset + 1],
+                symbols.format_instruction(&instruction)
+            )
+        };
+
+        if let Some(source_line) = symbols.source_line(address) {
+            text = format!("{:<36}; {}", text, source_line);
+        }
+        out.push_str(&text);
+        out.push('\n');
+
+        offset += if single { 1 } else { 2 };
+    }
+
+    out
+}
+
+fn parse_address(text: &str) -> Option<u16> {
+    let digits = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix('$'))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMBOLS: &str = "# generated\ndraw_player = 0x2A4\nmain = 200\n0x2A4 game.8o:17\n";
+
+    #[test]
+    fn test_parse() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+        assert_eq!(symbols.label(0x200), Some("main"));
+        assert_eq!(
+            symbols.source_line(0x2A4).unwrap().to_string(),
+            "game.8o:17"
+        );
+
+        assert!(Symbols::parse("draw_player = zz").is_err());
+        assert!(Symbols::parse("0x2A4 game.8o").is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+        assert_eq!(Symbols::parse(&symbols.to_string()), Ok(symbols));
+    }
+
+    #[test]
+    fn test_names() {
+        let symbols = Symbols::parse(SYMBOLS).unwrap();
+        assert_eq!(symbols.name(0x2A8), "draw_player+4");
+        assert_eq!(symbols.name(0x100), "0x100");
+        assert_eq!(symbols.locate(0x2A4), "draw_player (game.8o:17)");
+
+        let call = interpreter::parse(0x22, 0xA4);
+        assert_eq!(symbols.format_instruction(&call), "CALL draw_player");
+    }
+
+    #[test]
+    fn test_listing() {
+        let symbols = Symbols::parse("main = 0x200\nsprite = 0x203\n0x200 game.8o:3\n").unwrap();
+        let rom = [0x22, 0x03, 0x00, 0xF0, 0x90];
+
+        assert_eq!(
+            listing(&rom, 0x200, &symbols),
+            "main:\n\
+             \x20 200  2203  CALL sprite            ; game.8o:3\n\
+             \x20 202  00\n\
+             sprite:\n\
+             \x20 203  F090  ???\n"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use crate::interpreter;
+use crate::interpreter::ExecError;
+use crate::memory::Memory;
+use crate::symbols::Symbols;
+use sdl2::keyboard::Keycode;
+use std::collections::{HashSet, VecDeque};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// Writes a line per executed instruction to a log file.
+///
+/// Each line holds the cycle count, PC, raw opcode, disassembly and any registers or `I`
+/// that the instruction changed, e.g. `     42  20A  7A05  ADD VA, 0x05     VA=01->06`.
+/// With symbols, the PC is also given relative to the closest label.
+pub struct Tracer {
+    out: BufWriter<File>,
+    symbols: Option<Symbols>,
+    /// Only instructions at these addresses are logged, or everything when empty
+    ranges: Vec<RangeInclusive<u16>>,
+    /// In ring mode lines are held back here, and only written out when execution fails
+    ring: Option<VecDeque<String>>,
+    ring_size: usize,
+    cycle: u64,
+}
+
+impl Tracer {
+    /// `ring_size` of Some(n) keeps only the last n lines, dumping them if an `ExecError` occurs
+    pub fn create(
+        path: &Path,
+        ranges: Vec<RangeInclusive<u16>>,
+        ring_size: Option<usize>,
+        symbols: Option<Symbols>,
+    ) -> io::Result<Tracer> {
+        Ok(Tracer {
+            out: BufWriter::new(File::create(path)?),
+            symbols,
+            ranges,
+            ring: ring_size.map(VecDeque::with_capacity),
+            ring_size: ring_size.unwrap_or(0),
+            cycle: 0,
+        })
+    }
+
+    /// Executes one instruction like `interpreter::step`, logging it on the way
+    pub fn step(
+        &mut self,
+        memory: &mut Memory,
+        pressed_keys: &HashSet<Keycode>,
+        new_keys: &HashSet<Keycode>,
+    ) -> Result<(), ExecError> {
+        let pc = memory.program_counter;
+        let registers = memory.registers;
+        let i = memory.i;
+
+        let result = interpreter::step(memory, pressed_keys, new_keys);
+        self.cycle += 1;
+
+        match &result {
+            Ok(()) if self.traces(pc) => {
+                let line =
+                    format_line(self.cycle, pc, memory, &registers, i, self.symbols.as_ref());
+                self.log(line)
+            }
+            Ok(()) => {}
+            Err(error) => self.fail(error),
+        }
+
+        result
+    }
+
+    fn traces(&self, address: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&address))
+    }
+
+    fn log(&mut self, line: String) {
+        match self.ring.as_mut() {
+            Some(ring) => {
+                if ring.len() == self.ring_size {
+                    ring.pop_front();
+                }
+                ring.push_back(line);
+            }
+            // Tracing is best effort, a full disk shouldn't stop the machine
+            None => {
+                let _ = writeln!(self.out, "{}", line);
+            }
+        }
+    }
+
+    /// Writes out any held back lines followed by the error
+    fn fail(&mut self, error: &ExecError) {
+        if let Some(ring) = self.ring.as_mut() {
+            for line in ring.drain(..) {
+                let _ = writeln!(self.out, "{}", line);
+            }
+        }
+
+        let _ = match self.symbols.as_ref() {
+            Some(symbols) => writeln!(
+                self.out,
+                "{:>7}  {:03X}  error: {} in {}",
+                self.cycle,
+                error.address(),
+                error,
+                symbols.locate(error.address())
+            ),
+            None => writeln!(
+                self.out,
+                "{:>7}  {:03X}  error: {}",
+                self.cycle,
+                error.address(),
+                error
+            ),
+        };
+        let _ = self.out.flush();
+    }
+}
+
+/// Formats a trace line for the instruction at `pc`, given the registers and `I` from before it ran
+fn format_line(
+    cycle: u64,
+    pc: u16,
+    memory: &Memory,
+    registers: &[u8; 16],
+    i: u16,
+    symbols: Option<&Symbols>,
+) -> String {
+    let opcode = interpreter::opcode_at(memory, pc);
+    let instruction = interpreter::parse((opcode >> 8) as u8, opcode as u8);
+
+    let mut line = match symbols {
+        Some(symbols) => format!(
+            "{:>7}  {:03X}  {:<20}  {:04X}  {:<20}",
+            cycle,
+            pc,
+            symbols.name(pc),
+            opcode,
+            symbols.format_instruction(&instruction)
+        ),
+        None => format!(
+            "{:>7}  {:03X}  {:04X}  {:<16}",
+            cycle,
+            pc,
+            opcode,
+            instruction.to_string()
+        ),
+    };
+
+    for (reg, (before, after)) in registers.iter().zip(memory.registers.iter()).enumerate() {
+        if before != after {
+            line.push_str(&format!(" V{:X}={:02X}->{:02X}", reg, before, after));
+        }
+    }
+    if i != memory.i {
+        line.push_str(&format!(" I={:03X}->{:03X}", i, memory.i));
+    }
+
+    line.trim_end().to_string()
+}
+
+/// Parses an inclusive hex address range such as `200-2FF`, or a single address
+pub fn parse_range(text: &str) -> Result<RangeInclusive<u16>, String> {
+    let parse = |address: &str| {
+        u16::from_str_radix(address.trim_start_matches("0x"), 16)
+            .map_err(|_| format!("'{}' is not a hex address", address))
+    };
+
+    let (start, end) = match text.split_once('-') {
+        Some((start, end)) => (parse(start)?, parse(end)?),
+        None => (parse(text)?, parse(text)?),
+    };
+
+    if start > end {
+        return Err(format!("range {} ends before it starts", text));
+    }
+
+    Ok(start..=end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("200-2FF"), Ok(0x200..=0x2FF));
+        assert_eq!(parse_range("0x3A0"), Ok(0x3A0..=0x3A0));
+        assert!(parse_range("2FF-200").is_err());
+        assert!(parse_range("zz").is_err());
+    }
+
+    #[test]
+    fn test_format_line_deltas() {
+        let mut memory = Memory::new();
+        memory.load_rom(&[0x7A, 0x05]).unwrap();
+        let registers = memory.registers;
+        memory.registers[0xA] = 5;
+
+        assert_eq!(
+            format_line(1, 0x200, &memory, &registers, 0x200, None),
+            "      1  200  7A05  ADD VA, 0x05     VA=00->05 I=200->000"
+        );
+
+        let symbols = Symbols::parse("main = 0x1FE").unwrap();
+        assert_eq!(
+            format_line(1, 0x200, &memory, &registers, 0, Some(&symbols)),
+            "      1  200  main+2                7A05  ADD VA, 0x05         VA=00->05"
+        );
+    }
+}
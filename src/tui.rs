@@ -0,0 +1,307 @@
+use crate::interpreter::KEY_MAP;
+use crate::keymap::Keymap;
+use crate::memory::Memory;
+use crate::palette::Palette;
+use crate::recompiler::Recompiler;
+use crate::renderer::Display;
+use crate::Instruments;
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+use sdl2::keyboard::Keycode;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::{Stdout, Write};
+use std::time::{Duration, Instant};
+
+/// Characters for each keypad key, in the same physical layout as the SDL frontend's `KEY_MAP`
+const KEY_CHARS: [char; 16] = [
+    'x', '1', '2', '3', 'q', 'w', 'e', 'a', 's', 'd', 'z', 'c', '4', 'r', 'f', 'v',
+];
+
+/// How long a key counts as held after a press when the terminal can't report releases.
+/// Long enough to bridge the gap before the terminal's key repeat kicks in.
+const HOLD_FRAMES: u64 = 30;
+
+pub const FRAME: Duration = Duration::from_nanos(1_000_000_000 / 60);
+
+/// Puts the terminal into raw mode on the alternate screen, restoring it when dropped
+pub struct Screen {
+    pub stdout: Stdout,
+    /// Whether the terminal reports key releases
+    pub enhanced: bool,
+}
+
+impl Screen {
+    pub fn enter() -> io::Result<Screen> {
+        let mut stdout = io::stdout();
+
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, Hide)?;
+
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+
+        Ok(Screen { stdout, enhanced })
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = execute!(self.stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(self.stdout, ResetColor, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Tracks which keypad keys are held from terminal key events
+pub struct Keypad {
+    /// Frame until which each key counts as held, by the SDL keycode it corresponds to
+    held_until: HashMap<Keycode, u64>,
+    old_keys: HashSet<Keycode>,
+    frame: u64,
+    /// Whether release events arrive, so keys can be held indefinitely
+    enhanced: bool,
+    /// Extra keys, such as the arrows, that press keypad keys as in the SDL frontend
+    keymap: Keymap,
+}
+
+impl Keypad {
+    pub fn new(enhanced: bool, keymap: Keymap) -> Keypad {
+        Keypad {
+            held_until: HashMap::new(),
+            old_keys: HashSet::new(),
+            frame: 0,
+            enhanced,
+            keymap,
+        }
+    }
+
+    /// Updates the keypad from a key event, returning false if the key isn't on the keypad
+    /// or bound by the keymap
+    pub fn handle(&mut self, key: &KeyEvent) -> bool {
+        let keycode = match key_index(key.code) {
+            Some(index) => KEY_MAP[index],
+            None => match host_keycode(key.code) {
+                Some(keycode) if self.keymap.binds(keycode) => keycode,
+                _ => return false,
+            },
+        };
+
+        let until = match key.kind {
+            KeyEventKind::Release => 0,
+            _ if self.enhanced => u64::MAX,
+            _ => self.frame + HOLD_FRAMES,
+        };
+        self.held_until.insert(keycode, until);
+
+        true
+    }
+
+    /// Advances one frame, returning the held and newly pressed keys
+    pub fn next_frame(&mut self) -> (HashSet<Keycode>, HashSet<Keycode>) {
+        let pressed_keys: HashSet<Keycode> = self
+            .held_until
+            .iter()
+            .filter(|&(_, &until)| until > self.frame)
+            .map(|(&keycode, _)| keycode)
+            .collect();
+        let new_keys = &pressed_keys - &self.old_keys;
+
+        self.old_keys = pressed_keys.clone();
+        self.frame += 1;
+
+        (
+            self.keymap.apply(&pressed_keys),
+            self.keymap.apply(&new_keys),
+        )
+    }
+}
+
+/// Whether a key event asks to leave the terminal frontends, either Escape or Ctrl+C
+pub fn is_quit(key: &KeyEvent) -> bool {
+    let quit = key.code == KeyCode::Esc
+        || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+
+    quit && key.kind != KeyEventKind::Release
+}
+
+/// Runs the machine in the terminal until Escape or Ctrl+C is pressed
+pub fn run(
+    memory: &mut Memory,
+    mut recompiler: Option<&mut Recompiler>,
+    instruments: &mut Instruments,
+    palette: &Palette,
+    keymap: &Keymap,
+) -> io::Result<()> {
+    let mut screen = Screen::enter()?;
+    let mut keypad = Keypad::new(screen.enhanced, keymap.clone());
+    let mut last_display: Option<Display> = None;
+
+    loop {
+        let deadline = Instant::now() + FRAME;
+
+        while let Some(timeout) = deadline.checked_duration_since(Instant::now()) {
+            if !event::poll(timeout)? {
+                break;
+            }
+
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                Event::Resize(..) => {
+                    last_display = None;
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if is_quit(&key) {
+                return Ok(());
+            }
+
+            keypad.handle(&key);
+        }
+
+        let (pressed_keys, new_keys) = keypad.next_frame();
+
+        crate::run_frame(
+            memory,
+            recompiler.as_deref_mut(),
+            instruments,
+            &pressed_keys,
+            &new_keys,
+        )
+        .map_err(io::Error::other)?;
+
+        if last_display.as_ref() != Some(&memory.display) {
+            draw(&mut screen.stdout, &memory.display, palette)?;
+            last_display = Some(memory.display);
+        }
+    }
+}
+
+/// Draws two display rows per terminal line using the upper half block,
+/// with the top pixel as the foreground and the bottom pixel as the background
+fn draw(out: &mut impl Write, display: &Display, palette: &Palette) -> io::Result<()> {
+    for (line, rows) in display.chunks(2).enumerate() {
+        queue!(out, MoveTo(0, line as u16))?;
+
+        for (&top, &bottom) in rows[0].iter().zip(rows[1].iter()) {
+            queue!(
+                out,
+                SetForegroundColor(to_terminal_color(palette, top)),
+                SetBackgroundColor(to_terminal_color(palette, bottom)),
+                Print('▀')
+            )?;
+        }
+    }
+
+    queue!(out, ResetColor)?;
+    out.flush()
+}
+
+pub fn to_terminal_color(palette: &Palette, pixel: u8) -> Color {
+    let color = palette.colors[pixel as usize & 0b11];
+    Color::Rgb {
+        r: color.r,
+        g: color.g,
+        b: color.b,
+    }
+}
+
+/// The keypad key bound to a terminal key, if any
+fn key_index(code: KeyCode) -> Option<usize> {
+    match code {
+        KeyCode::Char(c) => KEY_CHARS
+            .iter()
+            .position(|&key| key == c.to_ascii_lowercase()),
+        _ => None,
+    }
+}
+
+/// The SDL keycode for a terminal key a keymap can bind, such as an arrow key
+fn host_keycode(code: KeyCode) -> Option<Keycode> {
+    match code {
+        KeyCode::Up => Some(Keycode::Up),
+        KeyCode::Down => Some(Keycode::Down),
+        KeyCode::Left => Some(Keycode::Left),
+        KeyCode::Right => Some(Keycode::Right),
+        KeyCode::Char(' ') => Some(Keycode::Space),
+        KeyCode::Enter => Some(Keycode::Return),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_index() {
+        assert_eq!(key_index(KeyCode::Char('x')), Some(0x0));
+        assert_eq!(key_index(KeyCode::Char('V')), Some(0xF));
+        assert_eq!(key_index(KeyCode::Char('4')), Some(0xC));
+        assert_eq!(key_index(KeyCode::Char('p')), None);
+        assert_eq!(key_index(KeyCode::Enter), None);
+    }
+
+    #[test]
+    fn test_keypad_holds_without_release_events() {
+        let mut keypad = Keypad::new(false, Keymap::default());
+        keypad.handle(&KeyEvent::new(KeyCode::Char('w'), KeyModifiers::NONE));
+
+        let (pressed, new) = keypad.next_frame();
+        assert!(pressed.contains(&KEY_MAP[5]));
+        assert!(new.contains(&KEY_MAP[5]));
+
+        let (pressed, new) = keypad.next_frame();
+        assert!(pressed.contains(&KEY_MAP[5]));
+        assert!(new.is_empty());
+
+        for _ in 0..HOLD_FRAMES {
+            keypad.next_frame();
+        }
+        assert!(keypad.next_frame().0.is_empty());
+    }
+
+    #[test]
+    fn test_keypad_applies_keymap() {
+        let mut keypad = Keypad::new(true, Keymap::parse("up:5").unwrap());
+        assert!(keypad.handle(&KeyEvent::new(KeyCode::Up, KeyModifiers::NONE)));
+        assert!(!keypad.handle(&KeyEvent::new(KeyCode::Down, KeyModifiers::NONE)));
+
+        let (pressed, new) = keypad.next_frame();
+        assert!(pressed.contains(&KEY_MAP[5]));
+        assert!(new.contains(&KEY_MAP[5]));
+
+        let mut release = KeyEvent::new(KeyCode::Up, KeyModifiers::NONE);
+        release.kind = KeyEventKind::Release;
+        keypad.handle(&release);
+        assert!(!keypad.next_frame().0.contains(&KEY_MAP[5]));
+    }
+
+    #[test]
+    fn test_draw_half_blocks() {
+        let mut display = [[0; 64]; 32];
+        display[0][0] = 1;
+
+        let mut out = Vec::new();
+        draw(&mut out, &display, &crate::palette::THEMES[0].1).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.matches('▀').count(), 64 * 16);
+    }
+}
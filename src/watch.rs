@@ -0,0 +1,79 @@
+use crate::symbols::Symbols;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Frames between checks, about four times a second
+const INTERVAL: u32 = 15;
+
+/// Notices a file changing on disk by checking its modification time every few frames
+pub struct Watcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    frames: u32,
+}
+
+impl Watcher {
+    pub fn new(path: &str) -> Watcher {
+        let path = PathBuf::from(path);
+        Watcher {
+            modified: modified(&path),
+            path,
+            frames: 0,
+        }
+    }
+
+    /// Called once a frame, returns whether the file changed since the last time it did
+    pub fn poll(&mut self) -> bool {
+        self.frames += 1;
+        if self.frames < INTERVAL {
+            return false;
+        }
+        self.frames = 0;
+
+        let now = modified(&self.path);
+        if now == self.modified {
+            return false;
+        }
+        self.modified = now;
+        // Editors that save by replacing the file can leave it missing for a moment
+        now.is_some()
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Whether a rebuilt ROM can take over a running machine from the old build: the same size
+/// with every label where it was, so return addresses on the stack and pointers into the ROM
+/// still point at the same things
+pub fn compatible(
+    old: &[u8],
+    old_symbols: Option<&Symbols>,
+    new: &[u8],
+    new_symbols: Option<&Symbols>,
+) -> bool {
+    old.len() == new.len() && old_symbols.map(Symbols::labels) == new_symbols.map(Symbols::labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compatible() {
+        let symbols = Symbols::parse("main = 0x200\nloop = 0x204").unwrap();
+        let moved = Symbols::parse("main = 0x200\nloop = 0x206").unwrap();
+        let old = [0x60, 0x01, 0x70, 0x01, 0x12, 0x04];
+
+        // Changing an instruction in place keeps the layout
+        let new = [0x60, 0x02, 0x70, 0x01, 0x12, 0x04];
+        assert!(compatible(&old, Some(&symbols), &new, Some(&symbols)));
+        assert!(!compatible(&old, Some(&symbols), &new, Some(&moved)));
+        assert!(!compatible(&old, None, &new[..4], None));
+    }
+}